@@ -0,0 +1,182 @@
+//! Append-only audit log of sessions, one JSON object per line
+//!
+//! diode-send and diode-receive both write a record when a session ends. Records of both sides
+//! are joined with `epoch` (start time of diode-send, carried by the init message) and
+//! `session`.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// Why a session ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// last block sent or received
+    End,
+    /// aborted by diode-send or from the control socket
+    Abort,
+    /// diode-receive gave up waiting for the last block
+    Expired,
+    /// socket error
+    Error,
+    /// all blocks received, but session digest computed by diode-receive differs from the one
+    /// sent by diode-send
+    DigestMismatch,
+}
+
+/// Record of one session
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionRecord {
+    pub epoch: Option<u64>,
+    pub session: u8,
+    #[serde(serialize_with = "rfc3339")]
+    pub start: DateTime<Utc>,
+    #[serde(serialize_with = "rfc3339")]
+    pub end: DateTime<Utc>,
+    pub bytes: u64,
+    pub blocks: u64,
+    pub lost_blocks: u64,
+    pub end_reason: EndReason,
+    /// TCP client of diode-send, or TCP server of diode-receive
+    pub tcp_peer: Option<SocketAddr>,
+    /// SHA-256 digest of session data, in hexadecimal, for sessions which reached their end
+    pub sha256: Option<String>,
+}
+
+/// lowercase hexadecimal representation of a digest
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn rfc3339<S: serde::Serializer>(
+    date: &DateTime<Utc>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    side: &'a str,
+    #[serde(flatten)]
+    record: &'a SessionRecord,
+    /// address of the UDP diode link
+    udp_addr: &'a str,
+}
+
+pub struct AuditLog {
+    path: String,
+    /// "send" or "receive"
+    side: &'static str,
+    udp_addr: String,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str, side: &'static str, udp_addr: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::new(e.kind(), format!("Cannot open audit log {path}: {e}")))?;
+
+        Ok(Self {
+            path: path.to_string(),
+            side,
+            udp_addr: udp_addr.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// append a record, errors are logged
+    pub fn write(&self, record: &SessionRecord) {
+        let line = Line {
+            side: self.side,
+            record,
+            udp_addr: &self.udp_addr,
+        };
+
+        let mut line = match serde_json::to_string(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("audit: can't serialize session record: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+
+        // one write per line, so concurrent writers never interleave records
+        let result = match self.file.lock() {
+            Ok(mut file) => file.write_all(line.as_bytes()),
+            Err(_) => Err(Error::other("lock poisoned")),
+        };
+
+        if let Err(e) = result {
+            log::error!("audit: can't write session record to {}: {e}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_hex, AuditLog, EndReason, SessionRecord};
+    use crate::test::TempDir;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.jsonl");
+        let path = path.to_str().unwrap();
+
+        let audit = AuditLog::open(path, "receive", "10.0.0.2").unwrap();
+        let mut record = SessionRecord {
+            epoch: Some(1_700_000_000_000),
+            session: 3,
+            start: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap(),
+            end: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 7).unwrap(),
+            bytes: 1000,
+            blocks: 2,
+            lost_blocks: 0,
+            end_reason: EndReason::End,
+            tcp_peer: Some("127.0.0.1:5002".parse().unwrap()),
+            sha256: Some(to_hex(&[0x01, 0xab, 0xff])),
+        };
+        audit.write(&record);
+        record.session = 4;
+        record.epoch = None;
+        record.end_reason = EndReason::DigestMismatch;
+        audit.write(&record);
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "side": "receive",
+                "epoch": 1_700_000_000_000u64,
+                "session": 3,
+                "start": "2026-01-02T03:04:05.000Z",
+                "end": "2026-01-02T03:04:07.000Z",
+                "bytes": 1000,
+                "blocks": 2,
+                "lost_blocks": 0,
+                "end_reason": "end",
+                "tcp_peer": "127.0.0.1:5002",
+                "sha256": "01abff",
+                "udp_addr": "10.0.0.2",
+            })
+        );
+        assert_eq!(lines[1]["epoch"], serde_json::Value::Null);
+        assert_eq!(lines[1]["end_reason"], "digest_mismatch");
+    }
+}
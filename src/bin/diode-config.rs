@@ -0,0 +1,185 @@
+use clap::{Parser, Subcommand};
+use diode::config::{Bandwidth, DiodeConfig, MergedConfig};
+use diode::protocol::LinkParameters;
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct DiodeConfigArgs {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate configuration file and print derived link parameters
+    Check {
+        /// Path to configuration file
+        #[arg(short, long, default_value_t = String::from("/etc/lidi/config.toml"))]
+        config: String,
+    },
+    /// Print configuration merged from file, drop-in fragments and environment
+    Show {
+        /// Path to configuration file
+        #[arg(short, long, default_value_t = String::from("/etc/lidi/config.toml"))]
+        config: String,
+    },
+}
+
+// print what the rate limit means for useful data, `max` is the wire bandwidth in Mbit/s
+fn print_bandwidth(label: &str, max: f64, link: &LinkParameters, wire_block_size: u64) {
+    let blocks_per_sec = max * 1_000_000.0 / 8.0 / wire_block_size as f64;
+    let throughput = blocks_per_sec * link.block_payload_size() as f64 * 8.0 / 1_000_000.0;
+    let packets_per_sec = blocks_per_sec * link.nb_packets() as f64;
+
+    println!(
+        "  {label:<28} {max} Mbit/s on the wire, {throughput:.1} Mbit/s of data, {packets_per_sec:.0} packets/s"
+    );
+}
+
+fn check(path: &str) -> ExitCode {
+    let config = match DiodeConfig::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration file {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // already checked by load
+    let link = match config.link_parameters() {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("Invalid configuration file {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(warning) = config.ignored_env_warning() {
+        eprintln!("{warning}");
+    }
+
+    println!("Configuration file {path} is valid");
+    println!();
+    println!("Link parameters:");
+    println!("  {:<28} {} bytes", "udp_mtu", config.udp_mtu);
+    println!(
+        "  {:<28} {} bytes, {} bytes of symbol",
+        "UDP payload per packet", link.udp_payload_size, link.data_mtu
+    );
+
+    print!(
+        "  {:<28} {} bytes ({} source packets)",
+        "encoding block", link.encoding_block_size, link.nb_encoding_packets
+    );
+    if link.encoding_block_size != config.encoding_block_size {
+        print!(", rounded from {}", config.encoding_block_size);
+    }
+    println!();
+
+    print!(
+        "  {:<28} {} bytes ({} repair packets)",
+        "repair block", link.repair_block_size, link.nb_repair_packets
+    );
+    if link.repair_block_size != config.repair_block_size {
+        print!(", rounded from {}", config.repair_block_size);
+    }
+    println!();
+
+    println!(
+        "  {:<28} {} bytes",
+        "TCP data per block",
+        link.block_payload_size()
+    );
+    println!("  {:<28} {:.1} %", "FEC overhead", link.fec_overhead());
+    println!(
+        "  {:<28} {} per block (any {} of {} packets)",
+        "tolerated lost packets",
+        link.nb_repair_packets,
+        link.nb_repair_packets,
+        link.nb_packets()
+    );
+
+    if link.nb_repair_packets == 0 {
+        println!();
+        println!("Warning: no repair packet, any lost packet makes its block unrecoverable");
+    }
+
+    let Some(sender) = &config.sender else {
+        return ExitCode::SUCCESS;
+    };
+
+    let link_overhead = sender.link_overhead.unwrap_or_default();
+    let wire_packet_size = link_overhead.wire_size(link.udp_payload_size as usize) as u64;
+    let wire_block_size = wire_packet_size * link.nb_packets();
+
+    println!();
+    println!("Sender:");
+    println!(
+        "  {:<28} {} bytes per packet, {} bytes per block",
+        "size on the wire", wire_packet_size, wire_block_size
+    );
+    println!(
+        "  {:<28} {:.1} %",
+        "wire overhead",
+        (wire_block_size as f64 / link.block_payload_size() as f64 - 1.0) * 100.0
+    );
+
+    match &sender.max_bandwidth {
+        None => println!("  {:<28} unlimited", "max_bandwidth"),
+        Some(Bandwidth::Fixed(max)) => {
+            print_bandwidth("max_bandwidth", *max, &link, wire_block_size)
+        }
+        Some(Bandwidth::Scheduled(schedule)) => {
+            for period in &schedule.schedule {
+                let label = format!("max_bandwidth {}-{}", period.start, period.end);
+                print_bandwidth(&label, period.max_bandwidth, &link, wire_block_size);
+            }
+            match schedule.default {
+                None => println!("  {:<28} unlimited", "max_bandwidth (default)"),
+                Some(max) => {
+                    print_bandwidth("max_bandwidth (default)", max, &link, wire_block_size)
+                }
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn show(path: &str) -> ExitCode {
+    let merged = match MergedConfig::load(path) {
+        Ok(merged) => merged,
+        Err(e) => {
+            eprintln!("Unable to load configuration {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    for source in &merged.sources {
+        println!("# from {source}");
+    }
+    for name in &merged.ignored {
+        println!("# ignored {name}");
+    }
+    println!();
+    print!("{}", merged.table);
+
+    // merged configuration is printed even if invalid, to help fixing it
+    match DiodeConfig::from_table(merged.table) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Invalid configuration {path}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = DiodeConfigArgs::parse();
+
+    match args.command {
+        Command::Check { config } => check(&config),
+        Command::Show { config } => show(&config),
+    }
+}
@@ -1,4 +1,4 @@
-use diode::{file, init_logger};
+use diode::{file, file::protocol::HashAlgorithm, init_logger, init_metrics};
 use std::{net, path, str::FromStr};
 
 use clap::Parser;
@@ -12,9 +12,35 @@ struct SendFileConfig {
     /// Size of file buffer
     #[arg(long, default_value_t = 8196)]
     buffer_size: usize,
-    /// Verify the hash of file content (default is false)
-    #[arg(long, default_value_t = false)]
-    hash: bool,
+    /// Hash algorithm required for received files: none (accept any, hash is still verified when
+    /// present), murmur3, sha256 or blake3. `--hash` alone is sha256, use `--hash=<ALGORITHM>` otherwise
+    #[arg(long, value_name = "ALGORITHM", default_value_t = HashAlgorithm::None, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+    hash: HashAlgorithm,
+    /// Restore owner of files when running as root: none, id (same user and group ids) or name
+    /// (same user and group names)
+    #[arg(long, value_name = "MAPPING", default_value_t = file::OwnerMapping::None)]
+    owner: file::OwnerMapping,
+    /// Namespace of extended attributes to restore (ex "user"), may be repeated
+    #[arg(long, value_name = "NAMESPACE")]
+    xattr: Vec<String>,
+    /// What to do when a file already exists: reject, overwrite, rename (numeric suffix),
+    /// rename-timestamp or version (previous file moved to .lidi-versions subdirectory)
+    #[arg(long, value_name = "POLICY", default_value_t = file::ConflictPolicy::Reject)]
+    on_conflict: file::ConflictPolicy,
+    /// Directory where files failing size, hash or policy checks are moved, with a JSON file
+    /// telling why. They are deleted otherwise
+    #[arg(long, value_name = "DIR")]
+    quarantine_dir: Option<String>,
+    /// File of the key verifying manifests sent by diode-send-dir. Manifests are not verified otherwise
+    #[arg(long, value_name = "FILE")]
+    manifest_key: Option<String>,
+    /// Keep setuid and setgid bits of received files and directories. They are cleared otherwise,
+    /// as they would apply to the owner restored with --owner, root included
+    #[arg(long)]
+    keep_setid: bool,
+    /// IP address and port of Prometheus metrics endpoint (ex "127.0.0.1:9003")
+    #[arg(long)]
+    metrics: Option<String>,
     /// Path to log configuration file
     #[arg(long)]
     log_config: Option<String>,
@@ -38,6 +64,15 @@ fn main() {
         diode: from_tcp,
         buffer_size,
         hash,
+        owner: args.owner,
+        xattrs: args.xattr,
+        on_conflict: args.on_conflict,
+        quarantine_dir: args.quarantine_dir.map(path::PathBuf::from),
+        chunk_size: None,
+        chunks: None,
+        content_id: false,
+        manifest_key: args.manifest_key.map(path::PathBuf::from),
+        keep_setid: args.keep_setid,
     };
 
     if let Err(e) = init_logger(args.log_config.as_ref(), &args.log_level) {
@@ -45,6 +80,11 @@ fn main() {
         return;
     }
 
+    if let Err(e) = init_metrics(args.metrics.as_deref()) {
+        log::error!("Cannot init metrics: {e}");
+        return;
+    }
+
     loop {
         if let Err(e) = file::receive::receive_files(&config, &output_directory) {
             log::error!("{e}");
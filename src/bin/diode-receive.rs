@@ -1,4 +1,7 @@
-use diode::{config::DiodeConfig, init_logger, init_metrics, receive::ReceiverConfig};
+use diode::{
+    config::DiodeConfig, handle_shutdown_signals, init_logger, init_metrics,
+    receive::ReceiverConfig,
+};
 
 use clap::Parser;
 
@@ -30,6 +33,10 @@ fn main() {
         return;
     }
 
+    if let Some(warning) = config.ignored_env_warning() {
+        log::warn!("{warning}");
+    }
+
     if let Some(receiver) = &config.receiver {
         if let Err(e) = init_metrics(receiver.metrics.as_deref()) {
             log::error!("Cannot init metrics: {e}");
@@ -51,6 +58,12 @@ fn main() {
                 std::process::exit(1);
             }));
 
+            // stop gracefully on SIGTERM / SIGINT
+            if let Err(e) = handle_shutdown_signals(receiver.shutdown.clone()) {
+                log::error!("failed to start signal handler thread: {e}");
+                return;
+            }
+
             // now starts the threads
             if let Err(e) = receiver.start() {
                 log::error!("failed to start diode receiver: {e}");
@@ -0,0 +1,101 @@
+use diode::{file, file::protocol::HashAlgorithm, init_logger};
+use std::{
+    net,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct SendCarouselConfig {
+    /// IP address and port to connect in TCP to diode-send (ex "127.0.0.1:5001")
+    #[arg(long, default_value_t = String::from("127.0.0.1:5001"))]
+    to_tcp: String,
+    /// Size of file buffer
+    #[arg(long, default_value_t = 8196)]
+    buffer_size: usize,
+    /// Hash algorithm of file content: none, murmur3, sha256 or blake3. `--hash` alone is sha256, use `--hash=<ALGORITHM>` otherwise
+    #[arg(long, value_name = "ALGORITHM", default_value_t = HashAlgorithm::None, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+    hash: HashAlgorithm,
+    /// Send owner of files (user and group ids and names)
+    #[arg(long)]
+    owner: bool,
+    /// Namespace of extended attributes to send (ex "user"), may be repeated
+    #[arg(long, value_name = "NAMESPACE")]
+    xattr: Vec<String>,
+    /// Split files larger than this size (in bytes) in chunks, each one sent in its own session
+    #[arg(long, value_name = "BYTES")]
+    chunk_size: Option<u64>,
+    /// Number of times all files are sent in a row
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: u32,
+    /// Send files again every given number of minutes, forever. Directories are listed again each time
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    cycle: Option<u64>,
+    /// List of files or directories to send
+    #[arg(required = true)]
+    file: Vec<String>,
+    /// Path to log configuration file
+    #[arg(long)]
+    log_config: Option<String>,
+    /// Verbosity level: info, debug, warning, error ...
+    #[arg(long, default_value_t = String::from("info"))]
+    pub log_level: String,
+}
+
+fn main() {
+    let args = SendCarouselConfig::parse();
+
+    if let Err(e) = init_logger(args.log_config.as_ref(), &args.log_level) {
+        eprintln!("Unable to init log {:?}: {}", args.log_config, e);
+        return;
+    }
+
+    let to_tcp =
+        net::SocketAddr::from_str(&args.to_tcp).expect("to_tcp must be of the form ip:port");
+
+    let config = file::Config {
+        diode: to_tcp,
+        buffer_size: args.buffer_size,
+        hash: args.hash,
+        owner: if args.owner {
+            file::OwnerMapping::Id
+        } else {
+            file::OwnerMapping::None
+        },
+        xattrs: args.xattr,
+        on_conflict: file::ConflictPolicy::default(),
+        quarantine_dir: None,
+        chunk_size: args.chunk_size,
+        chunks: None,
+        // receiver skips copies it already holds
+        content_id: true,
+        manifest_key: None,
+        keep_setid: false,
+    };
+
+    let cycle = args.cycle.map(|minutes| Duration::from_secs(minutes * 60));
+
+    loop {
+        let start = Instant::now();
+
+        for round in 1..=args.repeat {
+            log::info!("sending files, round {round}/{}", args.repeat);
+            // a lost connection to diode-send only costs this round
+            if let Err(e) = file::send::send_files(&config, &args.file) {
+                log::error!("{e}");
+            }
+        }
+
+        let Some(cycle) = cycle else {
+            return;
+        };
+
+        match cycle.checked_sub(start.elapsed()) {
+            Some(wait) => std::thread::sleep(wait),
+            None => log::warn!("sending files took longer than the cycle, starting again now"),
+        }
+    }
+}
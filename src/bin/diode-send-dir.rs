@@ -1,11 +1,18 @@
 use diode::{
-    file::{self, send::send_file},
+    file::{
+        self,
+        manifest::{self, Sender as Manifest},
+        protocol::{HashAlgorithm, Sequence},
+        send::{send_file, send_manifest},
+        sequence::Counter,
+    },
     init_logger,
 };
 use inotify::{Inotify, WatchMask};
 use std::{
     collections::{BTreeMap, VecDeque},
     net::{self, TcpStream},
+    path::PathBuf,
     str::FromStr,
     sync::mpsc::Sender,
     time::{Duration, Instant, UNIX_EPOCH},
@@ -25,9 +32,15 @@ struct SendFileConfig {
     /// Size of file buffer
     #[arg(long, default_value_t = 8196)]
     buffer_size: usize,
-    /// Compute a hash of file content (default is false)
-    #[arg(long, default_value_t = false)]
-    hash: bool,
+    /// Hash algorithm of file content: none, murmur3, sha256 or blake3. `--hash` alone is sha256, use `--hash=<ALGORITHM>` otherwise
+    #[arg(long, value_name = "ALGORITHM", default_value_t = HashAlgorithm::None, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+    hash: HashAlgorithm,
+    /// Send owner of files (user and group ids and names)
+    #[arg(long)]
+    owner: bool,
+    /// Namespace of extended attributes to send (ex "user"), may be repeated
+    #[arg(long, value_name = "NAMESPACE")]
+    xattr: Vec<String>,
     /// Directory containing files to send
     #[arg()]
     dir: String,
@@ -40,6 +53,21 @@ struct SendFileConfig {
     /// maximum delay (in milliseconds) before reconnecting the current session
     #[arg(long)]
     maximum_delay: Option<usize>,
+    /// Send a manifest of recently sent files every given number of seconds, ending the current session
+    #[arg(long, value_name = "SECONDS")]
+    manifest_interval: Option<u64>,
+    /// Number of recently sent files listed in manifests
+    #[arg(long, default_value_t = 1000)]
+    manifest_files: usize,
+    /// File of the key signing manifests, shared with diode-receive-file
+    #[arg(long, value_name = "FILE")]
+    manifest_key: Option<PathBuf>,
+    /// Identifier of this sender in file headers [default: host name]
+    #[arg(long, value_name = "ID")]
+    sender_id: Option<String>,
+    /// File keeping the sequence number of the last file sent, so that numbering goes on after a restart
+    #[arg(long, value_name = "FILE")]
+    sequence_file: Option<PathBuf>,
     /// Path to log configuration file
     #[arg(long)]
     log_config: Option<String>,
@@ -166,22 +194,34 @@ fn list_dir(dir: &str, ignore_file: &str) -> VecDeque<String> {
     ret
 }
 
-// send a list of list, until limit is reached. return true if limit is reached
+// numbering of sent files, listed in manifests
+struct Sent {
+    sender_id: String,
+    sequence: Counter,
+    manifest: Manifest,
+}
+
+// send a list of list, until limit is reached. return true if limit is reached, or if a file
+// failed to be sent: the connection may be in the middle of this file
 fn send_file_list(
     config: &file::Config,
     limits: &mut Limits,
     diode: &mut TcpStream,
     files: &mut VecDeque<String>,
+    sent: &mut Sent,
 ) -> bool {
     while let Some(filename) = files.pop_front() {
         limits.add_file();
-        match send_one_file(config, diode, &filename, limits) {
+        match send_one_file(config, diode, &filename, limits, sent) {
             Ok(reconnect) => {
                 if reconnect {
                     return true;
                 }
             }
-            Err(e) => log::warn!("Can't send file {filename}: {e}"),
+            Err(e) => {
+                log::warn!("Can't send file {filename}: {e}");
+                return true;
+            }
         }
     }
 
@@ -194,6 +234,7 @@ fn send_one_file(
     diode: &mut TcpStream,
     filename: &str,
     limits: &Limits,
+    sent: &mut Sent,
 ) -> Result<bool, file::Error> {
     let mut last_file = false;
 
@@ -202,9 +243,20 @@ fn send_one_file(
         last_file = true;
     }
 
-    match send_file(config, diode, filename, last_file) {
-        Ok(total) => {
-            log::info!("{filename} sent, {total} bytes");
+    // number is kept for next file if this one fails to be sent
+    let seq = sent.sequence.next()?;
+    let sequence = Sequence {
+        sender: sent.sender_id.clone(),
+        seq,
+    };
+
+    match send_file(config, diode, filename, Some(sequence), last_file) {
+        Ok((name, total, hash)) => {
+            sent.manifest.add(seq, &name, total as u64, &hash);
+            log::info!("{filename} sent, {total} bytes, #{seq}");
+            if let Err(e) = sent.sequence.sent(seq) {
+                log::warn!("Unable to save sequence number #{seq}: {e}");
+            }
         }
         Err(e) => {
             log::warn!("Unable to send {filename}: {e}");
@@ -301,6 +353,19 @@ fn main() {
         diode: to_tcp,
         buffer_size,
         hash,
+        owner: if args.owner {
+            file::OwnerMapping::Id
+        } else {
+            file::OwnerMapping::None
+        },
+        xattrs: args.xattr.clone(),
+        on_conflict: file::ConflictPolicy::default(),
+        quarantine_dir: None,
+        chunk_size: None,
+        chunks: None,
+        content_id: false,
+        manifest_key: args.manifest_key.clone(),
+        keep_setid: false,
     };
 
     let (inotify_tx, inotify_rx) = channel();
@@ -340,6 +405,31 @@ fn main() {
         args.maximum_delay.map(|d| Duration::from_millis(d as _)),
     );
 
+    let manifest_key = config.manifest_key.as_ref().map(|path| {
+        manifest::load_key(path)
+            .unwrap_or_else(|e| panic!("can't read manifest key {}: {e}", path.display()))
+    });
+    let sender_id = args.sender_id.clone().unwrap_or_else(|| {
+        nix::unistd::gethostname()
+            .expect("can't get host name")
+            .to_string_lossy()
+            .to_string()
+    });
+    let sequence = match Counter::open(args.sequence_file.clone()) {
+        Ok(sequence) => sequence,
+        Err(e) => {
+            log::error!("Unable to read sequence file {:?}: {e}", args.sequence_file);
+            return;
+        }
+    };
+    let mut sent = Sent {
+        sender_id,
+        sequence,
+        manifest: Manifest::new(manifest_key, args.manifest_files),
+    };
+    let manifest_interval = args.manifest_interval.map(Duration::from_secs);
+    let mut last_manifest = Instant::now();
+
     let mut diode = connect(&config);
 
     // main loop to send file, works even if there is no inotify
@@ -359,7 +449,20 @@ fn main() {
         let mut files = list_dir(args.dir.as_str(), args.ignore.as_str());
 
         while !files.is_empty() {
-            if send_file_list(&config, &mut limits, &mut diode, &mut files) {
+            if send_file_list(&config, &mut limits, &mut diode, &mut files, &mut sent) {
+                diode = connect(&config);
+                limits.reset();
+            }
+        }
+
+        // manifest ends the session, so that files listed are received before it
+        if let Some(interval) = manifest_interval {
+            if last_manifest.elapsed() >= interval {
+                last_manifest = Instant::now();
+                match send_manifest(&config, &mut diode, &mut sent.manifest, true) {
+                    Ok(total) => log::info!("manifest sent, {total} bytes"),
+                    Err(e) => log::warn!("Unable to send manifest: {e}"),
+                }
                 diode = connect(&config);
                 limits.reset();
             }
@@ -1,4 +1,4 @@
-use diode::{file, init_logger};
+use diode::{file, file::protocol::HashAlgorithm, init_logger};
 use std::{net, str::FromStr};
 
 use clap::Parser;
@@ -12,9 +12,25 @@ struct SendFileConfig {
     /// Size of file buffer
     #[arg(long, default_value_t = 8196)]
     buffer_size: usize,
-    /// Compute a hash of file content (default is false)
-    #[arg(long, default_value_t = false)]
-    hash: bool,
+    /// Hash algorithm of file content: none, murmur3, sha256 or blake3. `--hash` alone is sha256, use `--hash=<ALGORITHM>` otherwise
+    #[arg(long, value_name = "ALGORITHM", default_value_t = HashAlgorithm::None, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+    hash: HashAlgorithm,
+    /// Send owner of files (user and group ids and names)
+    #[arg(long)]
+    owner: bool,
+    /// Namespace of extended attributes to send (ex "user"), may be repeated
+    #[arg(long, value_name = "NAMESPACE")]
+    xattr: Vec<String>,
+    /// Split files larger than this size (in bytes) in chunks, each one sent in its own session
+    #[arg(long, value_name = "BYTES")]
+    chunk_size: Option<u64>,
+    /// Send only these chunks of large files, to complete a previous transfer (ex "3,7-9")
+    #[arg(long, value_name = "LIST", value_parser = file::chunks::parse_ranges, requires = "chunk_size")]
+    // full path, or clap would expect one index per value
+    chunks: Option<::std::vec::Vec<u64>>,
+    /// Send content identifiers, so that diode-receive-file skips files it already holds
+    #[arg(long)]
+    content_id: bool,
     /// List of files to send
     #[arg()]
     file: Vec<String>,
@@ -44,6 +60,19 @@ fn main() {
         diode: to_tcp,
         buffer_size,
         hash,
+        owner: if args.owner {
+            file::OwnerMapping::Id
+        } else {
+            file::OwnerMapping::None
+        },
+        xattrs: args.xattr,
+        on_conflict: file::ConflictPolicy::default(),
+        quarantine_dir: None,
+        chunk_size: args.chunk_size,
+        chunks: args.chunks,
+        content_id: args.content_id,
+        manifest_key: None,
+        keep_setid: false,
     };
 
     if let Err(e) = file::send::send_files(&config, &files) {
@@ -1,5 +1,7 @@
-use diode::config::DiodeConfig;
-use diode::{init_logger, init_metrics, send};
+use diode::config::{Bandwidth, DiodeConfig};
+use diode::{handle_shutdown_signals, init_logger, init_metrics, send};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use std::sync::{Arc, Mutex};
 
 use clap::Parser;
 
@@ -14,6 +16,27 @@ struct DiodeSenderArgs {
     pub log_level: String,
 }
 
+// reload rate limit from configuration file when SIGHUP is received
+fn reload_loop(mut signals: Signals, path: String, max_bandwidth: Arc<Mutex<Option<Bandwidth>>>) {
+    for _ in signals.forever() {
+        log::info!("SIGHUP received: reloading max_bandwidth from {path}");
+
+        let config = match DiodeConfig::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                log::error!("Unable to parse configuration file {path}: {e}");
+                continue;
+            }
+        };
+
+        let bandwidth = config.sender.and_then(|sender| sender.max_bandwidth);
+        match max_bandwidth.lock() {
+            Ok(mut max_bandwidth) => *max_bandwidth = bandwidth,
+            Err(e) => log::error!("Unable to update max_bandwidth: {e}"),
+        }
+    }
+}
+
 fn main() {
     let args = DiodeSenderArgs::parse();
     let config = DiodeConfig::load(&args.config);
@@ -31,6 +54,10 @@ fn main() {
         return;
     }
 
+    if let Some(warning) = config.ignored_env_warning() {
+        log::warn!("{warning}");
+    }
+
     if let Some(sender) = &config.sender {
         if let Err(e) = init_metrics(sender.metrics.as_deref()) {
             log::error!("Cannot init metrics: {e}");
@@ -52,6 +79,25 @@ fn main() {
                 std::process::exit(1);
             }));
 
+            // rate limit can be changed at runtime
+            let max_bandwidth = sender.max_bandwidth.clone();
+            let config_path = args.config.clone();
+            let reload = Signals::new([SIGHUP]).and_then(|signals| {
+                std::thread::Builder::new()
+                    .name("lidi_tx_reload".into())
+                    .spawn(move || reload_loop(signals, config_path, max_bandwidth))
+            });
+            if let Err(e) = reload {
+                log::error!("failed to start configuration reload thread: {e}");
+                return;
+            }
+
+            // stop gracefully on SIGTERM / SIGINT
+            if let Err(e) = handle_shutdown_signals(sender.shutdown.clone()) {
+                log::error!("failed to start signal handler thread: {e}");
+                return;
+            }
+
             // now starts the threads
             if let Err(e) = sender.start() {
                 log::error!("failed to start diode sender: {e}");
@@ -0,0 +1,291 @@
+//! Configuration merged from several layers, each one overriding the previous ones:
+//! - the base configuration file,
+//! - drop-in fragments `conf.d/*.toml` located in the directory of the base file, in
+//!   alphabetical order,
+//! - environment variables `LIDI_<KEY>`, where `__` separates table names, for instance
+//!   `LIDI_SENDER__MAX_BANDWIDTH` for `max_bandwidth` in `[sender]`. Variables not naming a known
+//!   option are ignored.
+//!
+//! Tables are merged key by key, other values (including arrays) are replaced.
+
+use super::schema;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// prefix of environment variables overriding configuration
+const ENV_PREFIX: &str = "LIDI_";
+
+/// separator of table names in environment variables
+const ENV_SEPARATOR: &str = "__";
+
+/// Name of drop-in directory, next to the base file
+const DROP_IN_DIR: &str = "conf.d";
+
+/// Configuration merged from all layers, before validation
+pub struct MergedConfig {
+    /// merged options
+    pub table: Table,
+    /// files and environment variables the options come from, in merge order
+    pub sources: Vec<String>,
+    /// environment variables with the prefix ignored, with the reason (ex `LIDI_FOO (unknown option)`)
+    pub ignored: Vec<String>,
+}
+
+impl MergedConfig {
+    pub fn load(path: &str) -> Result<Self> {
+        let mut table = read(Path::new(path))?;
+        let mut sources = vec![path.to_string()];
+        let mut ignored = vec![];
+
+        let drop_in = Path::new(path)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(DROP_IN_DIR);
+
+        if drop_in.is_dir() {
+            let mut fragments = std::fs::read_dir(&drop_in)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>>>()?;
+            fragments.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+            fragments.sort();
+
+            for fragment in fragments {
+                merge(&mut table, read(&fragment)?);
+                sources.push(fragment.display().to_string());
+            }
+        }
+
+        merge_env(&mut table, std::env::vars_os(), &mut sources, &mut ignored)?;
+
+        Ok(Self {
+            table,
+            sources,
+            ignored,
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<Table> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+
+    toml::from_str(&contents)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+}
+
+/// merge `overlay` into `base`
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// set options named by environment variables `vars` with the prefix, in alphabetical order
+fn merge_env(
+    table: &mut Table,
+    vars: impl Iterator<Item = (OsString, OsString)>,
+    sources: &mut Vec<String>,
+    ignored: &mut Vec<String>,
+) -> Result<()> {
+    let mut vars: Vec<(OsString, OsString)> = vars
+        .filter(|(name, _)| name.as_bytes().starts_with(ENV_PREFIX.as_bytes()))
+        .collect();
+    vars.sort();
+
+    for (name, value) in vars {
+        let (name, value) = match (name.into_string(), value.into_string()) {
+            (Ok(name), Ok(value)) => (name, value),
+            (name, _) => {
+                let name = name.unwrap_or_else(|name| name.to_string_lossy().into_owned());
+                ignored.push(format!("{name} (not valid UTF-8)"));
+                continue;
+            }
+        };
+
+        if set_env(table, &name, &value)? {
+            sources.push(name);
+        } else {
+            ignored.push(format!("{name} (unknown option)"));
+        }
+    }
+
+    Ok(())
+}
+
+/// set the option named by environment variable `name`, false if it names no known option
+fn set_env(table: &mut Table, name: &str, value: &str) -> Result<bool> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("{name}: {reason}"));
+
+    let keys: Vec<String> = name[ENV_PREFIX.len()..]
+        .split(ENV_SEPARATOR)
+        .map(|key| key.to_lowercase())
+        .collect();
+
+    // other programs may use the same prefix
+    if !schema::known(&keys) {
+        return Ok(false);
+    }
+
+    let (last, tables) = keys.split_last().ok_or_else(|| invalid("no option name"))?;
+
+    let mut current = table;
+    for key in tables {
+        current = match current
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(table) => table,
+            _ => return Err(invalid(&format!("'{key}' is not a table"))),
+        };
+    }
+
+    current.insert(last.clone(), parse_value(value));
+
+    Ok(true)
+}
+
+/// value written as in a TOML file (number, array, inline table...), or a plain string
+fn parse_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, merge_env, set_env};
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    use toml::{Table, Value};
+
+    #[test]
+    fn test_merge() {
+        let mut base: Table = toml::from_str(
+            r#"
+            udp_port = [ 5000, 5001 ]
+            heartbeat = 1000
+            [sender]
+            bind_tcp = "127.0.0.1:5001"
+            max_bandwidth = 100
+            "#,
+        )
+        .unwrap();
+
+        let fragment: Table = toml::from_str(
+            r#"
+            udp_port = [ 6000 ]
+            [sender]
+            max_bandwidth = { default = 200, schedule = [] }
+            [receiver]
+            to_tcp = "127.0.0.1:5002"
+            "#,
+        )
+        .unwrap();
+
+        merge(&mut base, fragment);
+
+        assert_eq!(base["udp_port"], Value::Array(vec![Value::Integer(6000)]));
+        assert_eq!(base["heartbeat"], Value::Integer(1000));
+        assert_eq!(base["sender"]["bind_tcp"].as_str(), Some("127.0.0.1:5001"));
+        assert_eq!(
+            base["sender"]["max_bandwidth"]["default"],
+            Value::Integer(200)
+        );
+        assert_eq!(base["receiver"]["to_tcp"].as_str(), Some("127.0.0.1:5002"));
+    }
+
+    #[test]
+    fn test_env() {
+        let mut table: Table =
+            toml::from_str("heartbeat = 1000\n[sender]\nmetrics = \"a\"").unwrap();
+
+        assert!(set_env(&mut table, "LIDI_HEARTBEAT", "500").unwrap());
+        set_env(&mut table, "LIDI_SENDER__MAX_BANDWIDTH", "1Gbit").unwrap();
+        set_env(&mut table, "LIDI_UDP_PORT", "[5000, 5001]").unwrap();
+        set_env(&mut table, "LIDI_RECEIVER__TO_TCP", "127.0.0.1:5002").unwrap();
+
+        assert_eq!(table["heartbeat"], Value::Integer(500));
+        assert_eq!(table["sender"]["max_bandwidth"].as_str(), Some("1Gbit"));
+        assert_eq!(table["sender"]["metrics"].as_str(), Some("a"));
+        assert_eq!(
+            table["udp_port"],
+            Value::Array(vec![Value::Integer(5000), Value::Integer(5001)])
+        );
+        assert_eq!(table["receiver"]["to_tcp"].as_str(), Some("127.0.0.1:5002"));
+
+        // checked as any other float (see DiodeConfig::check_bandwidth)
+        set_env(&mut table, "LIDI_SENDER__MAX_BANDWIDTH", "inf").unwrap();
+        assert_eq!(
+            table["sender"]["max_bandwidth"].as_float(),
+            Some(f64::INFINITY)
+        );
+
+        // unknown options are ignored
+        assert!(!set_env(&mut table, "LIDI_FOO", "1").unwrap());
+        assert!(!set_env(&mut table, "LIDI_HEARTBEAT__X", "1").unwrap());
+        assert!(!set_env(&mut table, "LIDI_SENDER____X", "1").unwrap());
+        assert!(!set_env(&mut table, "LIDI_SENDER__FOO", "1").unwrap());
+        assert!(
+            !table.contains_key("foo") && !table["sender"].as_table().unwrap().contains_key("foo")
+        );
+
+        // values of tables in schedules
+        assert!(set_env(&mut table, "LIDI_SENDER__MAX_BANDWIDTH__DEFAULT", "1").is_err());
+        table["sender"]
+            .as_table_mut()
+            .unwrap()
+            .remove("max_bandwidth");
+        assert!(set_env(&mut table, "LIDI_SENDER__MAX_BANDWIDTH__DEFAULT", "1").unwrap());
+        assert_eq!(
+            table["sender"]["max_bandwidth"]["default"],
+            Value::Integer(1)
+        );
+
+        let mut table: Table = toml::from_str("sender = 1").unwrap();
+        assert!(set_env(&mut table, "LIDI_SENDER__METRICS", "a").is_err());
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let var = |name: &[u8], value: &[u8]| {
+            (
+                OsString::from_vec(name.to_vec()),
+                OsString::from_vec(value.to_vec()),
+            )
+        };
+        let vars = vec![
+            var(b"PATH", b"/bin"),
+            var(b"LIDI_UDP_MTU", b"9000"),
+            var(b"LIDI_HEARTBEAT", b"500"),
+            var(b"LIDI_FOO", b"1"),
+            var(b"LIDI_LOG_CONFIG", b"\xff.yml"),
+            var(b"LIDI_\xff", b"1"),
+            var(b"OTHER_\xff", b"1"),
+        ];
+
+        let mut table = Table::new();
+        let (mut sources, mut ignored) = (vec![], vec![]);
+        merge_env(&mut table, vars.into_iter(), &mut sources, &mut ignored).unwrap();
+
+        assert_eq!(sources, ["LIDI_HEARTBEAT", "LIDI_UDP_MTU"]);
+        assert_eq!(
+            ignored,
+            [
+                "LIDI_FOO (unknown option)",
+                "LIDI_LOG_CONFIG (not valid UTF-8)",
+                "LIDI_\u{fffd} (not valid UTF-8)"
+            ]
+        );
+        assert_eq!(table["udp_mtu"], Value::Integer(9000));
+        assert!(!table.contains_key("log_config"));
+    }
+}
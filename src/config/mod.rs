@@ -0,0 +1,732 @@
+use chrono::Timelike;
+use core_affinity::CoreId;
+use serde::de::{value::MapAccessDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::io::{Error, ErrorKind, Result};
+
+use crate::protocol::LinkParameters;
+
+mod layers;
+mod schema;
+mod units;
+
+pub use layers::MergedConfig;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiodeConfig {
+    /// Size of RaptorQ block, in bytes
+    #[serde(deserialize_with = "units::size")]
+    pub encoding_block_size: u64,
+    /// Size of repair data, in bytes
+    #[serde(deserialize_with = "units::size")]
+    pub repair_block_size: u32,
+    /// IP address on diode-receive side used to transfert UDP packets between diode-send and diode-receive
+    pub udp_addr: String,
+    /// List of ports used to transfert packets between diode-send and diode-receive. Each different port will create a new thread. Each port/thread is able to process up to 3 Gb/s.
+    pub udp_port: Vec<u16>,
+    /// MTU of the to use one the UDP link
+    pub udp_mtu: u16,
+    /// heartbeat period in ms
+    #[serde(deserialize_with = "units::millis")]
+    pub heartbeat: u32,
+    /// Time given to the current session to complete and to flush queues when stopping (in ms). Default is 10 s.
+    #[serde(default, deserialize_with = "units::opt_millis")]
+    pub shutdown_timeout: Option<u32>,
+    /// Path to log configuration file
+    pub log_config: Option<String>,
+    /// diode sender options
+    pub sender: Option<DiodeSenderConfig>,
+    /// diode receiver options
+    pub receiver: Option<DiodeReceiverConfig>,
+    /// environment variables ignored when loading (see [DiodeConfig::ignored_env_warning])
+    #[serde(skip)]
+    pub ignored_env: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiodeSenderConfig {
+    /// TCP server socket to accept data
+    pub bind_tcp: String,
+    /// UDP socket src address to send data (format A.B.C.D or A.B.C.D:P)
+    pub bind_udp: String,
+    /// ratelimit TCP session speed (in Mbit/s), constant or depending on time of day
+    pub max_bandwidth: Option<Bandwidth>,
+    /// Maximum burst size allowed by the rate limiter (in bytes). Default is the size of one block (encoding + repair).
+    #[serde(default, deserialize_with = "units::opt_size")]
+    pub max_burst: Option<u64>,
+    /// Number of threads encoding blocks. Default is one thread per UDP port.
+    pub encoding_threads: Option<usize>,
+    /// Network overhead added to each UDP packet on the link, used by rate limiter and metrics
+    pub link_overhead: Option<LinkOverhead>,
+    /// prometheus port (sender)
+    pub metrics: Option<String>,
+    /// Path of the unix control socket (sender)
+    pub control_socket: Option<String>,
+    /// Path of the session audit log (sender)
+    pub audit_log: Option<String>,
+    /// Send the digest of each session with its last block, diode-receive must support it. Default is false.
+    pub session_digest: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiodeReceiverConfig {
+    /// IP address and port of the TCP server
+    pub to_tcp: String,
+    /// Timeout before force incomplete block recovery (in ms). Default is equal to heartbeat interval.
+    #[serde(default, deserialize_with = "units::opt_millis")]
+    pub block_expiration_timeout: Option<u32>,
+    /// Session expiration delay. Time to wait before changing session (in ms). Default is equal to 5 x heartbeat interval.
+    #[serde(default, deserialize_with = "units::opt_millis")]
+    pub session_expiration_timeout: Option<u32>,
+    /// List of core affinity. One different core id per thread. Each core id must exists.
+    pub core_affinity: Option<Vec<usize>>,
+    /// prometheus port (receiver)
+    pub metrics: Option<String>,
+    /// Path of the unix control socket (receiver)
+    pub control_socket: Option<String>,
+    /// Path of the session audit log (receiver)
+    pub audit_log: Option<String>,
+    /// Size of the queue between UDP receiver and block reorder/decoder. Default is 10k packets.
+    pub udp_packets_queue_size: Option<usize>,
+    /// Size of the queue between block reorder/decoder and TCP sender. Default is 1k blocks.
+    pub tcp_blocks_queue_size: Option<usize>,
+}
+
+/// Rate limit of diode-send
+#[derive(Clone)]
+pub enum Bandwidth {
+    /// constant bandwidth (in Mbit/s)
+    Fixed(f64),
+    /// bandwidth depending on time of day
+    Scheduled(BandwidthSchedule),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthSchedule {
+    /// bandwidth (in Mbit/s) used outside of all periods. Default is unlimited.
+    #[serde(default, deserialize_with = "units::opt_bandwidth")]
+    pub default: Option<f64>,
+    /// list of periods with a specific bandwidth. First matching period is used.
+    pub schedule: Vec<BandwidthPeriod>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthPeriod {
+    /// start of the period, local time (format HH:MM or HH:MM:SS)
+    pub start: TimeOfDay,
+    /// end of the period (excluded), local time (format HH:MM or HH:MM:SS). May be before start to span midnight.
+    pub end: TimeOfDay,
+    /// bandwidth (in Mbit/s) during this period
+    #[serde(deserialize_with = "units::bandwidth")]
+    pub max_bandwidth: f64,
+}
+
+/// Number of seconds since midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    pub fn now() -> Self {
+        Self(chrono::Local::now().num_seconds_from_midnight())
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let fields = value
+            .split(':')
+            .map(|field| field.parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid time of day '{value}': {e}"))?;
+
+        let (hours, minutes, seconds) = match fields[..] {
+            [hours, minutes] => (hours, minutes, 0),
+            [hours, minutes, seconds] => (hours, minutes, seconds),
+            _ => {
+                return Err(format!(
+                    "invalid time of day '{value}': expected HH:MM or HH:MM:SS"
+                ))
+            }
+        };
+
+        if hours > 24 || minutes > 59 || seconds > 59 || (hours == 24 && minutes + seconds > 0) {
+            return Err(format!("invalid time of day '{value}': out of range"));
+        }
+
+        Ok(Self(hours * 3600 + minutes * 60 + seconds))
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (hours, minutes, seconds) = (self.0 / 3600, self.0 / 60 % 60, self.0 % 60);
+        if seconds == 0 {
+            write!(fmt, "{hours:02}:{minutes:02}")
+        } else {
+            write!(fmt, "{hours:02}:{minutes:02}:{seconds:02}")
+        }
+    }
+}
+
+impl BandwidthPeriod {
+    fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // period spans midnight
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        d.deserialize_any(BandwidthVisitor)
+    }
+}
+
+// a value with unit or a table describing a schedule
+struct BandwidthVisitor;
+
+impl<'de> Visitor<'de> for BandwidthVisitor {
+    type Value = Bandwidth;
+
+    fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "a bandwidth or a schedule")
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> std::result::Result<Bandwidth, E> {
+        Ok(Bandwidth::Fixed(v as f64))
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> std::result::Result<Bandwidth, E> {
+        Ok(Bandwidth::Fixed(v as f64))
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, v: f64) -> std::result::Result<Bandwidth, E> {
+        Ok(Bandwidth::Fixed(v))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<Bandwidth, E> {
+        units::parse_bandwidth(v)
+            .map(Bandwidth::Fixed)
+            .map_err(E::custom)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Bandwidth, A::Error> {
+        BandwidthSchedule::deserialize(MapAccessDeserializer::new(map)).map(Bandwidth::Scheduled)
+    }
+}
+
+impl Bandwidth {
+    /// bandwidth (in Mbit/s) to apply at a given time of day, None if unlimited
+    pub fn at(&self, time: TimeOfDay) -> Option<f64> {
+        match self {
+            Self::Fixed(max) => Some(*max),
+            Self::Scheduled(schedule) => schedule
+                .schedule
+                .iter()
+                .find(|period| period.contains(time))
+                .map(|period| period.max_bandwidth)
+                .or(schedule.default),
+        }
+    }
+}
+
+/// Model of the size of a UDP packet on the wire
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkOverhead {
+    /// Size of headers added to each UDP payload (in bytes). Default is 42: Ethernet (14) + IPv4 (20) + UDP (8).
+    pub header_size: Option<usize>,
+    /// Minimum frame size (in bytes, with headers), smaller frames are padded. Default is 0.
+    pub min_frame_size: Option<usize>,
+    /// Size of the framing added to each frame (in bytes), for instance Ethernet preamble (8), frame check sequence (4) and inter frame gap (12). Default is 0.
+    pub framing_size: Option<usize>,
+}
+
+/// Ethernet (14) + IPv4 (20) + UDP (8)
+const DEFAULT_LINK_HEADER_SIZE: usize = 42;
+
+impl LinkOverhead {
+    /// size on the wire of a UDP packet carrying `udp_payload_len` bytes
+    pub fn wire_size(&self, udp_payload_len: usize) -> usize {
+        let frame_size = udp_payload_len + self.header_size.unwrap_or(DEFAULT_LINK_HEADER_SIZE);
+        frame_size.max(self.min_frame_size.unwrap_or(0)) + self.framing_size.unwrap_or(0)
+    }
+}
+
+pub const MAX_MTU: usize = 9000;
+
+/// maximum value of `sender.encoding_threads`: blocks are encoded out of order, block ids on 8 bits
+/// and the reorder window of diode-receive are limited
+pub const MAX_ENCODING_THREADS: usize = 20;
+
+/// default value of `shutdown_timeout`, in ms
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u32 = 10_000;
+
+/// Lowest max bandwidth (in Mbit/s), 1 kbit/s
+pub const MIN_BANDWIDTH: f64 = 0.001;
+
+/// max bandwidth in Mbit/s, with an optional unit as in the configuration file
+pub fn parse_max_bandwidth(value: &str) -> std::result::Result<f64, String> {
+    let max = units::parse_bandwidth(value)?;
+    check_max_bandwidth(max).map(|_| max)
+}
+
+fn check_max_bandwidth(max: f64) -> std::result::Result<(), String> {
+    if max.is_finite() && max >= MIN_BANDWIDTH {
+        Ok(())
+    } else {
+        Err(format!("{max}: must be finite and >= {MIN_BANDWIDTH}"))
+    }
+}
+
+impl DiodeConfig {
+    /// load base file `path`, drop-in fragments and environment overrides (see [MergedConfig])
+    pub fn load(path: &str) -> Result<DiodeConfig> {
+        let merged = MergedConfig::load(path)?;
+        let mut config = DiodeConfig::from_table(merged.table)?;
+        config.ignored_env = merged.ignored;
+        Ok(config)
+    }
+
+    /// warning about environment variables ignored when loading, None if there are none
+    pub fn ignored_env_warning(&self) -> Option<String> {
+        (!self.ignored_env.is_empty()).then(|| {
+            format!(
+                "Ignoring environment variables: {}",
+                self.ignored_env.join(", ")
+            )
+        })
+    }
+
+    #[cfg(test)]
+    fn parse(contents: &str) -> Result<DiodeConfig> {
+        let table: toml::Table = toml::from_str(contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{e}")))?;
+        DiodeConfig::from_table(table)
+    }
+
+    /// read and check options, syntax errors are already reported with their position, invalid
+    /// values are reported with their key path
+    pub fn from_table(table: toml::Table) -> Result<DiodeConfig> {
+        let mut errors = vec![];
+        schema::check_table(&table, schema::CONFIG, "", &mut errors);
+        DiodeConfig::report(errors)?;
+
+        // only missing keys are left to report
+        let config = DiodeConfig::deserialize(table).map_err(|e| {
+            let message = e.to_string();
+            let message = message.lines().filter(|line| !line.is_empty());
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                message.collect::<Vec<_>>().join(" "),
+            )
+        })?;
+
+        config.check()?;
+
+        Ok(config)
+    }
+
+    /// check consistency of options, all errors are reported at once
+    pub fn check(&self) -> Result<()> {
+        let mut errors = vec![];
+
+        DiodeConfig::check_mtu(self.udp_mtu, &mut errors);
+        DiodeConfig::check_link(self, &mut errors);
+        DiodeConfig::check_ports(self, &mut errors);
+        DiodeConfig::check_core_affinity(self, &mut errors);
+        DiodeConfig::check_ports_and_core_affinity(self, &mut errors);
+        DiodeConfig::check_encoding_threads(self, &mut errors);
+        DiodeConfig::check_bandwidth(self, &mut errors);
+
+        DiodeConfig::report(errors)
+    }
+
+    fn report(mut errors: Vec<String>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(Error::new(ErrorKind::InvalidData, errors.remove(0))),
+            n => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{n} errors:\n{}", errors.join("\n")),
+            )),
+        }
+    }
+
+    /// packets and blocks actually used on the UDP link
+    pub fn link_parameters(&self) -> Result<LinkParameters> {
+        LinkParameters::new(
+            self.udp_mtu,
+            self.encoding_block_size,
+            self.repair_block_size,
+        )
+    }
+
+    fn check_mtu(mtu: u16, errors: &mut Vec<String>) {
+        if mtu > MAX_MTU as _ {
+            errors.push(format!("Invalid 'udp_mtu': {mtu}: must be <= {MAX_MTU}"));
+        }
+    }
+
+    // check if blocks can be split in packets
+    fn check_link(config: &DiodeConfig, errors: &mut Vec<String>) {
+        if let Err(e) = config.link_parameters() {
+            errors.push(e.to_string());
+        }
+    }
+
+    // list of values found more than once
+    fn duplicates<T: Copy + Ord>(values: &[T]) -> Vec<T> {
+        let mut sorted = values.to_vec();
+        sorted.sort();
+        let mut duplicates: Vec<T> = sorted
+            .windows(2)
+            .filter(|pair| pair[0] == pair[1])
+            .map(|pair| pair[0])
+            .collect();
+        duplicates.dedup();
+        duplicates
+    }
+
+    // check if port list is valid (no duplicated values)
+    fn check_ports(config: &DiodeConfig, errors: &mut Vec<String>) {
+        if config.udp_port.is_empty() {
+            errors.push("Invalid 'udp_port' list: port list is empty".to_string());
+        }
+
+        let duplicates = DiodeConfig::duplicates(&config.udp_port);
+        if !duplicates.is_empty() {
+            errors.push(format!(
+                "Invalid 'udp_port' list: there are duplicated values: {duplicates:?}"
+            ));
+        }
+    }
+
+    // check if core_affinity list is valid (no duplicated values and core id exists)
+    fn check_core_affinity(config: &DiodeConfig, errors: &mut Vec<String>) {
+        let Some(core_affinity) = config
+            .receiver
+            .as_ref()
+            .and_then(|receiver| receiver.core_affinity.as_ref())
+        else {
+            return;
+        };
+
+        let duplicates = DiodeConfig::duplicates(core_affinity);
+        if !duplicates.is_empty() {
+            errors.push(format!(
+                "Invalid 'receiver.core_affinity' list: there are duplicated values: {duplicates:?}"
+            ));
+        }
+
+        // check there are only usable cores
+        match core_affinity::get_core_ids() {
+            Some(core_ids) => {
+                for core in core_affinity {
+                    if !core_ids.contains(&CoreId { id: *core }) {
+                        errors.push(format!(
+                            "Invalid 'receiver.core_affinity' list: impossible to run on core {core}"
+                        ));
+                    }
+                }
+            }
+            None => errors.push("Unable to get core list".to_string()),
+        }
+    }
+
+    // compare port list and core affinity list
+    fn check_ports_and_core_affinity(config: &DiodeConfig, errors: &mut Vec<String>) {
+        let Some(core_affinity) = config
+            .receiver
+            .as_ref()
+            .and_then(|receiver| receiver.core_affinity.as_ref())
+        else {
+            return;
+        };
+
+        if core_affinity.len() < config.udp_port.len() {
+            errors.push(format!(
+                "Invalid 'receiver.core_affinity' list: there are not enough core ids ({}) for all {} rx threads ({:?})",
+                core_affinity.len(), config.udp_port.len(), config.udp_port
+            ));
+        }
+
+        if core_affinity.len() > config.udp_port.len() + 2 {
+            errors.push(format!(
+                "Invalid 'receiver.core_affinity' list: there are too many core ids ({}) for rx threads ({:?}) plus the 2 extra threads (reorder/decode & tcp)",
+                core_affinity.len(), config.udp_port.len()
+            ));
+        }
+    }
+
+    // check if the number of encoding threads is valid
+    fn check_encoding_threads(config: &DiodeConfig, errors: &mut Vec<String>) {
+        if let Some(sender) = &config.sender {
+            match sender.encoding_threads {
+                Some(0) => errors.push(
+                    "Invalid 'sender.encoding_threads': there must be at least one thread"
+                        .to_string(),
+                ),
+                Some(threads) if threads > MAX_ENCODING_THREADS => errors.push(format!(
+                    "Invalid 'sender.encoding_threads': {threads}: must be <= {MAX_ENCODING_THREADS}"
+                )),
+                _ => (),
+            }
+        }
+    }
+
+    // check if bandwidth values are valid (strictly positive)
+    fn check_bandwidth(config: &DiodeConfig, errors: &mut Vec<String>) {
+        let bandwidth = match config
+            .sender
+            .as_ref()
+            .and_then(|sender| sender.max_bandwidth.as_ref())
+        {
+            Some(bandwidth) => bandwidth,
+            None => return,
+        };
+
+        let values = match bandwidth {
+            Bandwidth::Fixed(max) => vec![("sender.max_bandwidth".to_string(), *max)],
+            Bandwidth::Scheduled(schedule) => schedule
+                .schedule
+                .iter()
+                .enumerate()
+                .map(|(i, period)| {
+                    (
+                        format!("sender.max_bandwidth.schedule[{i}].max_bandwidth"),
+                        period.max_bandwidth,
+                    )
+                })
+                .chain(
+                    schedule
+                        .default
+                        .map(|max| ("sender.max_bandwidth.default".to_string(), max)),
+                )
+                .collect(),
+        };
+
+        for (key, max) in values {
+            if let Err(e) = check_max_bandwidth(max) {
+                errors.push(format!("Invalid '{key}': {e}"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bandwidth, DiodeConfig, LinkOverhead, TimeOfDay};
+
+    fn time(value: &str) -> TimeOfDay {
+        TimeOfDay::try_from(value.to_string()).expect("invalid time")
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        assert_eq!(time("00:00"), TimeOfDay(0));
+        assert_eq!(time("08:30"), TimeOfDay(8 * 3600 + 30 * 60));
+        assert_eq!(time("23:59:59"), TimeOfDay(86399));
+        assert_eq!(time("24:00"), TimeOfDay(86400));
+        assert!(TimeOfDay::try_from("24:01".to_string()).is_err());
+        assert!(TimeOfDay::try_from("8h30".to_string()).is_err());
+        assert!(TimeOfDay::try_from("08".to_string()).is_err());
+
+        assert_eq!(time("08:30").to_string(), "08:30");
+        assert_eq!(time("23:59:59").to_string(), "23:59:59");
+    }
+
+    #[test]
+    fn test_bandwidth_schedule() {
+        #[derive(serde::Deserialize)]
+        struct Sender {
+            max_bandwidth: Bandwidth,
+        }
+
+        let fixed: Sender = toml::from_str("max_bandwidth = 100").unwrap();
+        assert_eq!(fixed.max_bandwidth.at(time("12:00")), Some(100.0));
+
+        let scheduled: Sender = toml::from_str(
+            r#"
+            [max_bandwidth]
+            default = 2000
+            schedule = [
+                { start = "08:00", end = "19:00", max_bandwidth = 200 },
+                { start = "22:00", end = "02:00", max_bandwidth = 500 },
+            ]
+            "#,
+        )
+        .unwrap();
+        let bandwidth = scheduled.max_bandwidth;
+        assert_eq!(bandwidth.at(time("07:59:59")), Some(2000.0));
+        assert_eq!(bandwidth.at(time("08:00")), Some(200.0));
+        assert_eq!(bandwidth.at(time("18:59")), Some(200.0));
+        assert_eq!(bandwidth.at(time("19:00")), Some(2000.0));
+        assert_eq!(bandwidth.at(time("23:00")), Some(500.0));
+        assert_eq!(bandwidth.at(time("01:00")), Some(500.0));
+
+        let unlimited: Sender = toml::from_str(
+            r#"
+            [max_bandwidth]
+            schedule = [ { start = "08:00", end = "19:00", max_bandwidth = 200 } ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(unlimited.max_bandwidth.at(time("20:00")), None);
+    }
+
+    const CONFIG: &str = r#"
+        encoding_block_size = "60KB"
+        repair_block_size = "6KB"
+        udp_addr = "127.0.0.1"
+        udp_port = [ 5000, 5001 ]
+        udp_mtu = 1500
+        heartbeat = "1s"
+
+        [sender]
+        bind_tcp = "127.0.0.1:5001"
+        bind_udp = "127.0.0.1:0"
+        max_bandwidth = "1.5Gbit/s"
+        max_burst = "64KiB"
+
+        [receiver]
+        to_tcp = "127.0.0.1:5002"
+        session_expiration_timeout = "5s"
+    "#;
+
+    #[test]
+    fn test_units() {
+        let config = DiodeConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.encoding_block_size, 60000);
+        assert_eq!(config.repair_block_size, 6000);
+        assert_eq!(config.heartbeat, 1000);
+
+        let sender = config.sender.unwrap();
+        assert_eq!(
+            sender.max_bandwidth.unwrap().at(time("12:00")),
+            Some(1500.0)
+        );
+        assert_eq!(sender.max_burst, Some(65536));
+
+        let receiver = config.receiver.unwrap();
+        assert_eq!(receiver.session_expiration_timeout, Some(5000));
+        assert_eq!(receiver.block_expiration_timeout, None);
+    }
+
+    #[test]
+    fn test_validation() {
+        let error = DiodeConfig::parse(&CONFIG.replace("heartbeat =", "heart_beat ="))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Unknown key 'heart_beat'"));
+
+        let error = DiodeConfig::parse(&CONFIG.replace("\"5s\"", "\"5 seconds\""))
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid 'receiver.session_expiration_timeout': "));
+
+        // unknown keys and invalid values are all reported, with their key path
+        let error = DiodeConfig::parse(
+            &CONFIG
+                .replace("heartbeat =", "heart_beat =")
+                .replace("\"5s\"", "\"5 seconds\"")
+                .replace("\"64KiB\"", "\"64 kilobytes\"")
+                .replace("[sender]", "[sender]\nfoo = 1"),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.starts_with("4 errors:"));
+        assert!(error.contains("Unknown key 'heart_beat'"));
+        assert!(error.contains("Invalid 'receiver.session_expiration_timeout': "));
+        assert!(error.contains("Invalid 'sender.max_burst': "));
+        assert!(error.contains("Unknown key 'sender.foo'"));
+
+        // all errors are reported, even non adjacent duplicated ports
+        let error = DiodeConfig::parse(
+            &CONFIG
+                .replace("[ 5000, 5001 ]", "[ 5000, 5001, 5000 ]")
+                .replace("\"1.5Gbit/s\"", "0"),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.starts_with("2 errors:"));
+        assert!(error.contains("Invalid 'udp_port' list: there are duplicated values: [5000]"));
+        assert!(error.contains("Invalid 'sender.max_bandwidth': 0: must be finite and >= 0.001"));
+
+        // as set by an environment variable too (LIDI_SENDER__MAX_BANDWIDTH=inf)
+        for (value, shown) in [("inf", "inf"), ("nan", "NaN"), ("0.0001", "0.0001")] {
+            let error = DiodeConfig::parse(&CONFIG.replace("\"1.5Gbit/s\"", value))
+                .err()
+                .unwrap()
+                .to_string();
+            assert_eq!(
+                error,
+                format!("Invalid 'sender.max_bandwidth': {shown}: must be finite and >= 0.001")
+            );
+        }
+        let error = DiodeConfig::parse(&CONFIG.replace(
+            "max_bandwidth = \"1.5Gbit/s\"",
+            "max_bandwidth = { default = inf, schedule = [ { start = \"08:00\", end = \"19:00\", max_bandwidth = nan } ] }",
+        ))
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.starts_with("2 errors:"));
+        assert!(error.contains("Invalid 'sender.max_bandwidth.default': inf: "));
+        assert!(error.contains("Invalid 'sender.max_bandwidth.schedule[0].max_bandwidth': NaN: "));
+
+        let error =
+            DiodeConfig::parse(&CONFIG.replace("[sender]", "[sender]\nencoding_threads = 21"))
+                .err()
+                .unwrap();
+        assert!(error
+            .to_string()
+            .contains("Invalid 'sender.encoding_threads': 21: must be <= 20"));
+
+        // keys of bandwidth schedules too
+        let error = DiodeConfig::parse(&CONFIG.replace(
+            "max_bandwidth = \"1.5Gbit/s\"",
+            "max_bandwidth = { schedule = [ { start = \"25:00\", end = \"06:00\", max_bandwidth = 1, speed = 1 } ] }",
+        ))
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.starts_with("2 errors:"));
+        assert!(error.contains("Invalid 'sender.max_bandwidth.schedule[0].start': "));
+        assert!(error.contains("Unknown key 'sender.max_bandwidth.schedule[0].speed'"));
+    }
+
+    #[test]
+    fn test_link_overhead() {
+        // default: eth + ipv4 + udp
+        let overhead = LinkOverhead::default();
+        assert_eq!(overhead.wire_size(1000), 1042);
+
+        // vlan + ipv6 + udp, with ethernet layer 1 framing
+        let overhead: LinkOverhead = toml::from_str(
+            r#"
+            header_size = 66
+            min_frame_size = 60
+            framing_size = 24
+            "#,
+        )
+        .unwrap();
+        assert_eq!(overhead.wire_size(1000), 1090);
+        assert_eq!(overhead.wire_size(0), 90);
+    }
+}
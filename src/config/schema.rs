@@ -0,0 +1,330 @@
+//! Keys expected in the configuration, checked before deserialization so that every unknown key
+//! and every invalid value is reported at once, with its key path
+//!
+//! Tests check that keys match the fields of the configuration structs.
+
+use super::{units, Bandwidth, TimeOfDay};
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+/// check of a value alone, by deserializing it
+type Check = fn(Value) -> Result<(), toml::de::Error>;
+
+/// Expected content of a key
+pub(crate) enum Schema {
+    Value(Check),
+    Table(&'static [(&'static str, Schema)]),
+    /// array of values following the same schema
+    Array(&'static Schema),
+    /// value, or table when a table is found (ex bandwidth schedule)
+    ValueOrTable(Check, &'static [(&'static str, Schema)]),
+}
+
+fn value<T: DeserializeOwned>(value: Value) -> Result<(), toml::de::Error> {
+    T::deserialize(value).map(drop)
+}
+
+const BANDWIDTH_PERIOD: &[(&str, Schema)] = &[
+    ("start", Schema::Value(value::<TimeOfDay>)),
+    ("end", Schema::Value(value::<TimeOfDay>)),
+    (
+        "max_bandwidth",
+        Schema::Value(|v| units::bandwidth(v).map(drop)),
+    ),
+];
+
+const BANDWIDTH_SCHEDULE: &[(&str, Schema)] = &[
+    ("default", Schema::Value(|v| units::bandwidth(v).map(drop))),
+    ("schedule", Schema::Array(&Schema::Table(BANDWIDTH_PERIOD))),
+];
+
+const LINK_OVERHEAD: &[(&str, Schema)] = &[
+    ("header_size", Schema::Value(value::<usize>)),
+    ("min_frame_size", Schema::Value(value::<usize>)),
+    ("framing_size", Schema::Value(value::<usize>)),
+];
+
+const SENDER: &[(&str, Schema)] = &[
+    ("bind_tcp", Schema::Value(value::<String>)),
+    ("bind_udp", Schema::Value(value::<String>)),
+    (
+        "max_bandwidth",
+        Schema::ValueOrTable(value::<Bandwidth>, BANDWIDTH_SCHEDULE),
+    ),
+    (
+        "max_burst",
+        Schema::Value(|v| units::size::<_, u64>(v).map(drop)),
+    ),
+    ("encoding_threads", Schema::Value(value::<usize>)),
+    ("link_overhead", Schema::Table(LINK_OVERHEAD)),
+    ("metrics", Schema::Value(value::<String>)),
+    ("control_socket", Schema::Value(value::<String>)),
+    ("audit_log", Schema::Value(value::<String>)),
+    ("session_digest", Schema::Value(value::<bool>)),
+];
+
+const RECEIVER: &[(&str, Schema)] = &[
+    ("to_tcp", Schema::Value(value::<String>)),
+    (
+        "block_expiration_timeout",
+        Schema::Value(|v| units::millis::<_, u32>(v).map(drop)),
+    ),
+    (
+        "session_expiration_timeout",
+        Schema::Value(|v| units::millis::<_, u32>(v).map(drop)),
+    ),
+    ("core_affinity", Schema::Value(value::<Vec<usize>>)),
+    ("metrics", Schema::Value(value::<String>)),
+    ("control_socket", Schema::Value(value::<String>)),
+    ("audit_log", Schema::Value(value::<String>)),
+    ("udp_packets_queue_size", Schema::Value(value::<usize>)),
+    ("tcp_blocks_queue_size", Schema::Value(value::<usize>)),
+];
+
+/// keys of [super::DiodeConfig]
+pub(crate) const CONFIG: &[(&str, Schema)] = &[
+    (
+        "encoding_block_size",
+        Schema::Value(|v| units::size::<_, u64>(v).map(drop)),
+    ),
+    (
+        "repair_block_size",
+        Schema::Value(|v| units::size::<_, u32>(v).map(drop)),
+    ),
+    ("udp_addr", Schema::Value(value::<String>)),
+    ("udp_port", Schema::Value(value::<Vec<u16>>)),
+    ("udp_mtu", Schema::Value(value::<u16>)),
+    (
+        "heartbeat",
+        Schema::Value(|v| units::millis::<_, u32>(v).map(drop)),
+    ),
+    (
+        "shutdown_timeout",
+        Schema::Value(|v| units::millis::<_, u32>(v).map(drop)),
+    ),
+    ("log_config", Schema::Value(value::<String>)),
+    ("sender", Schema::Table(SENDER)),
+    ("receiver", Schema::Table(RECEIVER)),
+];
+
+/// check keys and values of `table` against `schema`, errors are appended to `errors`
+pub(crate) fn check_table(
+    table: &Table,
+    schema: &[(&str, Schema)],
+    prefix: &str,
+    errors: &mut Vec<String>,
+) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        match schema.iter().find(|(name, _)| name == key) {
+            Some((_, schema)) => schema.check(value, &path, errors),
+            None => errors.push(format!("Unknown key '{path}'")),
+        }
+    }
+}
+
+impl Schema {
+    fn check(&self, value: &Value, path: &str, errors: &mut Vec<String>) {
+        match (self, value) {
+            (Self::Table(schema) | Self::ValueOrTable(_, schema), Value::Table(table)) => {
+                check_table(table, schema, path, errors)
+            }
+            (Self::Array(schema), Value::Array(values)) => {
+                for (i, value) in values.iter().enumerate() {
+                    schema.check(value, &format!("{path}[{i}]"), errors);
+                }
+            }
+            (Self::Value(check) | Self::ValueOrTable(check, _), value) => {
+                if let Err(e) = check(value.clone()) {
+                    let message = e.to_string();
+                    let message = message.lines().filter(|line| !line.is_empty());
+                    errors.push(format!(
+                        "Invalid '{path}': {}",
+                        message.collect::<Vec<_>>().join(" ")
+                    ));
+                }
+            }
+            (Self::Table(_), _) => errors.push(format!("Invalid '{path}': expected a table")),
+            (Self::Array(_), _) => errors.push(format!("Invalid '{path}': expected an array")),
+        }
+    }
+}
+
+/// `path` names a key of the configuration (ex `["sender", "max_bandwidth"]`)
+pub(crate) fn known<S: AsRef<str>>(path: &[S]) -> bool {
+    let mut schema = CONFIG;
+    for (i, key) in path.iter().enumerate() {
+        let found = schema.iter().find(|(name, _)| *name == key.as_ref());
+        match (found, i + 1 == path.len()) {
+            (Some(_), true) => return true,
+            (Some((_, Schema::Table(inner) | Schema::ValueOrTable(_, inner))), false) => {
+                schema = inner
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_table, known, Schema};
+    use crate::config::{
+        BandwidthPeriod, BandwidthSchedule, DiodeConfig, DiodeReceiverConfig, DiodeSenderConfig,
+        LinkOverhead,
+    };
+    use serde::de::{value, Deserialize, Deserializer, Error, Visitor};
+    use serde::forward_to_deserialize_any;
+    use toml::{Table, Value};
+
+    /// gives the names of the fields of a struct deserialized with serde, as an error
+    struct Fields;
+
+    impl<'de> Deserializer<'de> for Fields {
+        type Error = value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(Error::custom(fields.join(" ")))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    fn fields<'de, T: Deserialize<'de>>() -> Vec<String> {
+        let mut fields: Vec<String> = match T::deserialize(Fields) {
+            Ok(_) => unreachable!(),
+            Err(e) => e.to_string().split(' ').map(String::from).collect(),
+        };
+        fields.sort();
+        fields
+    }
+
+    fn keys(schema: &[(&str, Schema)]) -> Vec<String> {
+        let mut keys: Vec<String> = schema.iter().map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    fn table(
+        schema: &'static [(&'static str, Schema)],
+        key: &str,
+    ) -> &'static [(&'static str, Schema)] {
+        match schema.iter().find(|(name, _)| *name == key) {
+            Some((_, Schema::Table(table) | Schema::ValueOrTable(_, table))) => table,
+            Some((_, Schema::Array(schema))) => match schema {
+                Schema::Table(table) => table,
+                _ => panic!("'{key}' is not an array of tables"),
+            },
+            _ => panic!("'{key}' is not a table"),
+        }
+    }
+
+    // a field added to the configuration structs and not here would be an unknown key
+    #[test]
+    fn test_fields() {
+        let config = super::CONFIG;
+        let sender = table(config, "sender");
+        let bandwidth = table(sender, "max_bandwidth");
+
+        assert_eq!(keys(config), fields::<DiodeConfig>());
+        assert_eq!(keys(sender), fields::<DiodeSenderConfig>());
+        assert_eq!(
+            keys(table(config, "receiver")),
+            fields::<DiodeReceiverConfig>()
+        );
+        assert_eq!(
+            keys(table(sender, "link_overhead")),
+            fields::<LinkOverhead>()
+        );
+        assert_eq!(keys(bandwidth), fields::<BandwidthSchedule>());
+        assert_eq!(
+            keys(table(bandwidth, "schedule")),
+            fields::<BandwidthPeriod>()
+        );
+
+        assert!(known(&["sender", "link_overhead", "header_size"]));
+        assert!(known(&["receiver", "tcp_blocks_queue_size"]));
+        assert!(!known(&["sender", "to_tcp"]));
+        assert!(!known(&["udp_mtu", "size"]));
+    }
+
+    // every option set: no error, and the same values are accepted by the configuration structs
+    #[test]
+    fn test_full_config() {
+        let full: Table = toml::from_str(
+            r#"
+            encoding_block_size = "60KB"
+            repair_block_size = 6000
+            udp_addr = "127.0.0.1"
+            udp_port = [ 5000, 5001 ]
+            udp_mtu = 1500
+            heartbeat = "1s"
+            shutdown_timeout = 5000
+            log_config = "log4rs.yml"
+
+            [sender]
+            bind_tcp = "127.0.0.1:5001"
+            bind_udp = "127.0.0.1:0"
+            max_burst = "64KiB"
+            encoding_threads = 2
+            metrics = "127.0.0.1:9001"
+            control_socket = "send.sock"
+            audit_log = "send.log"
+            session_digest = true
+            link_overhead = { header_size = 66, min_frame_size = 60, framing_size = 24 }
+
+            [sender.max_bandwidth]
+            default = "1Gbit"
+            schedule = [ { start = "08:00", end = "19:00", max_bandwidth = 200 } ]
+
+            [receiver]
+            to_tcp = "127.0.0.1:5002"
+            block_expiration_timeout = "500ms"
+            session_expiration_timeout = "5s"
+            core_affinity = [ 0 ]
+            metrics = "127.0.0.1:9002"
+            control_socket = "receive.sock"
+            audit_log = "receive.log"
+            udp_packets_queue_size = 10000
+            tcp_blocks_queue_size = 1000
+            "#,
+        )
+        .unwrap();
+
+        let mut errors = vec![];
+        check_table(&full, super::CONFIG, "", &mut errors);
+        assert_eq!(errors, Vec::<String>::new());
+        assert!(DiodeConfig::deserialize(Value::Table(full.clone())).is_ok());
+
+        // every key path is known, as set by environment variables
+        fn paths(table: &Table, prefix: &[String]) {
+            for (key, value) in table {
+                let mut path = prefix.to_vec();
+                path.push(key.clone());
+                assert!(known(&path), "{path:?}");
+                if let Value::Table(table) = value {
+                    paths(table, &path);
+                }
+            }
+        }
+        paths(&full, &[]);
+    }
+}
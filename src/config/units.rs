@@ -0,0 +1,202 @@
+//! Values with units accepted in configuration file
+//!
+//! Numbers use the default unit of each option (bytes, ms or Mbit/s). Strings may specify a unit:
+//! - sizes: `B`, `KB`, `MB`, `GB` (powers of 1000) or `KiB`, `MiB`, `GiB` (powers of 1024),
+//! - durations: `us`, `ms`, `s`, `min`, `h`,
+//! - bandwidths: `bit`, `kbit`, `Mbit`, `Gbit` or `B`, `KB`, `MB`, `GB`, with an optional `/s`
+//!   suffix, or `bps`, `kbps`, `Mbps`, `Gbps`.
+
+use serde::{de::Error, Deserialize, Deserializer};
+
+const SIZE_UNITS: &[(&str, f64)] = &[
+    ("B", 1.0),
+    ("KB", 1e3),
+    ("kB", 1e3),
+    ("MB", 1e6),
+    ("GB", 1e9),
+    ("KiB", 1024.0),
+    ("MiB", 1024.0 * 1024.0),
+    ("GiB", 1024.0 * 1024.0 * 1024.0),
+];
+
+/// durations are converted to ms
+const DURATION_UNITS: &[(&str, f64)] = &[
+    ("us", 1e-3),
+    ("ms", 1.0),
+    ("s", 1e3),
+    ("min", 60e3),
+    ("h", 3600e3),
+];
+
+/// bandwidths are converted to Mbit/s
+const BANDWIDTH_UNITS: &[(&str, f64)] = &[
+    ("bit", 1e-6),
+    ("kbit", 1e-3),
+    ("Kbit", 1e-3),
+    ("Mbit", 1.0),
+    ("Gbit", 1e3),
+    ("bps", 1e-6),
+    ("kbps", 1e-3),
+    ("Kbps", 1e-3),
+    ("Mbps", 1.0),
+    ("Gbps", 1e3),
+    ("B", 8e-6),
+    ("KB", 8e-3),
+    ("kB", 8e-3),
+    ("MB", 8.0),
+    ("GB", 8e3),
+];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Integer(u64),
+    Float(f64),
+    Text(String),
+}
+
+// value in the default unit, `units` gives the factor to apply for each unit
+fn parse(value: &str, units: &[(&str, f64)]) -> Result<f64, String> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+
+    let number = value[..end]
+        .parse::<f64>()
+        .map_err(|_| format!("invalid value '{value}': expected a number and a unit"))?;
+
+    let unit = value[end..].trim();
+    let unit = unit.strip_suffix("/s").unwrap_or(unit);
+    if unit.is_empty() {
+        return Ok(number);
+    }
+
+    match units.iter().find(|(name, _)| *name == unit) {
+        Some((_, factor)) => Ok(number * factor),
+        None => Err(format!(
+            "invalid unit in '{value}': expected one of {}",
+            units
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn integer<T: TryFrom<u64>>(value: Value, units: &[(&str, f64)]) -> Result<T, String> {
+    let (number, text) = match value {
+        Value::Integer(number) => (number, number.to_string()),
+        Value::Float(number) => return Err(format!("invalid value {number}: expected an integer")),
+        Value::Text(text) => {
+            let number = parse(&text, units)?;
+            // ignore floating point errors of unit conversion
+            let rounded = number.round();
+            if rounded < 0.0 || (number - rounded).abs() > 1e-6 || rounded > u64::MAX as f64 {
+                return Err(format!(
+                    "invalid value '{text}': expected an integer amount of {}",
+                    units
+                        .iter()
+                        .find(|(_, factor)| *factor == 1.0)
+                        .map_or("", |(name, _)| *name)
+                ));
+            }
+            (rounded as u64, text)
+        }
+    };
+
+    T::try_from(number).map_err(|_| format!("invalid value '{text}': out of range"))
+}
+
+fn float(value: Value, units: &[(&str, f64)]) -> Result<f64, String> {
+    match value {
+        Value::Integer(number) => Ok(number as f64),
+        Value::Float(number) => Ok(number),
+        Value::Text(text) => parse(&text, units),
+    }
+}
+
+/// size in bytes
+pub(crate) fn size<'de, D: Deserializer<'de>, T: TryFrom<u64>>(d: D) -> Result<T, D::Error> {
+    integer(Value::deserialize(d)?, SIZE_UNITS).map_err(D::Error::custom)
+}
+
+/// optional size in bytes
+pub(crate) fn opt_size<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    d: D,
+) -> Result<Option<T>, D::Error> {
+    size(d).map(Some)
+}
+
+/// duration in ms
+pub(crate) fn millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(d: D) -> Result<T, D::Error> {
+    integer(Value::deserialize(d)?, DURATION_UNITS).map_err(D::Error::custom)
+}
+
+/// optional duration in ms
+pub(crate) fn opt_millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    d: D,
+) -> Result<Option<T>, D::Error> {
+    millis(d).map(Some)
+}
+
+/// bandwidth in Mbit/s
+pub(crate) fn bandwidth<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    float(Value::deserialize(d)?, BANDWIDTH_UNITS).map_err(D::Error::custom)
+}
+
+/// bandwidth in Mbit/s from a string with unit
+pub(crate) fn parse_bandwidth(value: &str) -> Result<f64, String> {
+    parse(value, BANDWIDTH_UNITS)
+}
+
+/// optional bandwidth in Mbit/s
+pub(crate) fn opt_bandwidth<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    bandwidth(d).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{float, integer, Value, BANDWIDTH_UNITS, DURATION_UNITS, SIZE_UNITS};
+
+    fn text(value: &str) -> Value {
+        Value::Text(value.to_string())
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(integer::<u64>(Value::Integer(60000), SIZE_UNITS), Ok(60000));
+        assert_eq!(integer::<u64>(text("60000"), SIZE_UNITS), Ok(60000));
+        assert_eq!(integer::<u64>(text("60KB"), SIZE_UNITS), Ok(60000));
+        assert_eq!(integer::<u64>(text("1.5 MB"), SIZE_UNITS), Ok(1_500_000));
+        assert_eq!(integer::<u64>(text("64KiB"), SIZE_UNITS), Ok(65536));
+        assert!(integer::<u64>(text("1.5B"), SIZE_UNITS).is_err());
+        assert!(integer::<u64>(text("60 furlongs"), SIZE_UNITS).is_err());
+        assert!(integer::<u64>(text("KB"), SIZE_UNITS).is_err());
+        assert!(integer::<u64>(Value::Float(1.5), SIZE_UNITS).is_err());
+        assert!(integer::<u32>(text("5GB"), SIZE_UNITS).is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(integer::<u32>(Value::Integer(500), DURATION_UNITS), Ok(500));
+        assert_eq!(integer::<u32>(text("500ms"), DURATION_UNITS), Ok(500));
+        assert_eq!(integer::<u32>(text("10s"), DURATION_UNITS), Ok(10_000));
+        assert_eq!(integer::<u32>(text("2min"), DURATION_UNITS), Ok(120_000));
+        assert_eq!(integer::<u32>(text("1000us"), DURATION_UNITS), Ok(1));
+        assert_eq!(integer::<u32>(text("0.3s"), DURATION_UNITS), Ok(300));
+        assert!(integer::<u32>(text("1500us"), DURATION_UNITS).is_err());
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        assert_eq!(float(Value::Integer(100), BANDWIDTH_UNITS), Ok(100.0));
+        assert_eq!(float(Value::Float(2.5), BANDWIDTH_UNITS), Ok(2.5));
+        assert_eq!(float(text("1Gbit"), BANDWIDTH_UNITS), Ok(1000.0));
+        assert_eq!(float(text("500 Mbit/s"), BANDWIDTH_UNITS), Ok(500.0));
+        assert_eq!(float(text("10Mbps"), BANDWIDTH_UNITS), Ok(10.0));
+        assert_eq!(float(text("10MB/s"), BANDWIDTH_UNITS), Ok(80.0));
+        assert!(float(text("10 Mbit/h"), BANDWIDTH_UNITS).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+//! Local control socket, used to query the status of diode-send / diode-receive and to send them
+//! commands at runtime
+//!
+//! The protocol is line based: each request is a single line containing a command and its
+//! arguments, and each response is a single line containing a JSON object. Successful responses
+//! contain `"ok": true` and an optional `"status"` value, failures contain `"ok": false` and an
+//! `"error"` message.
+//!
+//! Available commands:
+//! - `status`: current sessions, queue lengths, last heartbeat...
+//! - `abort`: abort current session
+//! - `set-rate <Mbit/s>|none`: change rate limit (diode-send only)
+//! - `set-log-level <level>`: change log level (error, warn, info, debug, trace)
+//!
+//! For instance:
+//!
+//! ```text
+//! $ echo status | socat - UNIX-CONNECT:/run/lidi/diode-send.sock
+//! ```
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, process, thread};
+
+/// maximum time to wait for a command from a connected client
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Actions available on the control socket, implemented by diode-send and diode-receive
+pub trait Control: Send + Sync + 'static {
+    /// current status, as a JSON value
+    fn status(&self) -> Value;
+    /// abort current session
+    fn abort(&self) -> Result<()>;
+    /// change rate limit (in Mbit/s), None to disable it
+    fn set_rate(&self, max_bandwidth: Option<f64>) -> Result<()>;
+    /// number of sessions and bytes transferred since start
+    fn totals(&self) -> Totals;
+}
+
+/// Status of a session
+#[derive(Clone, Copy)]
+pub struct Session {
+    pub id: u8,
+    pub bytes: u64,
+    pub started: Instant,
+}
+
+impl Session {
+    pub fn new(id: u8) -> Self {
+        Self {
+            id,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Serialize for Session {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        json!({
+            "id": self.id,
+            "bytes": self.bytes,
+            "age_ms": self.started.elapsed().as_millis() as u64,
+        })
+        .serialize(serializer)
+    }
+}
+
+/// Number of sessions and bytes transferred since start
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Totals {
+    pub sessions: u64,
+    pub bytes: u64,
+}
+
+/// Current session and totals since start
+#[derive(Default)]
+pub struct Sessions {
+    current: Mutex<Option<Session>>,
+    totals: Mutex<Totals>,
+}
+
+impl Sessions {
+    pub fn start(&self, id: u8) {
+        if let Ok(mut current) = self.current.lock() {
+            *current = Some(Session::new(id));
+        }
+        if let Ok(mut totals) = self.totals.lock() {
+            totals.sessions += 1;
+        }
+    }
+
+    /// update number of bytes transferred in current session
+    pub fn set_bytes(&self, bytes: usize) {
+        if let Ok(mut current) = self.current.lock() {
+            if let Some(session) = current.as_mut() {
+                session.bytes = bytes as u64;
+            }
+        }
+    }
+
+    pub fn end(&self) {
+        let session = self
+            .current
+            .lock()
+            .ok()
+            .and_then(|mut current| current.take());
+        if let (Some(session), Ok(mut totals)) = (session, self.totals.lock()) {
+            totals.bytes += session.bytes;
+        }
+    }
+
+    pub fn current(&self) -> Option<Session> {
+        self.current.lock().ok().and_then(|current| *current)
+    }
+
+    pub fn totals(&self) -> Totals {
+        let mut totals = self.totals.lock().map(|totals| *totals).unwrap_or_default();
+        if let Some(session) = self.current() {
+            totals.bytes += session.bytes;
+        }
+        totals
+    }
+}
+
+/// number of milliseconds since `instant`, for status output
+pub fn elapsed_ms(instant: Option<Instant>) -> Option<u64> {
+    instant.map(|instant| instant.elapsed().as_millis() as u64)
+}
+
+/// bind the control socket and start a thread accepting clients, each one served by its own
+/// thread
+pub fn start<C: Control>(path: &str, control: Arc<C>) -> Result<thread::JoinHandle<()>> {
+    // remove a socket left by a previous instance, unless this instance is still running
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Cannot bind control socket {path}: file exists and is not a socket"),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("Cannot bind control socket {path}: used by a running instance"),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = bind(Path::new(path))
+        .map_err(|e| Error::new(e.kind(), format!("Cannot bind control socket {path}: {e}")))?;
+
+    log::info!("control socket listening at {path}");
+
+    thread::Builder::new()
+        .name("lidi_control".into())
+        .spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        log::warn!("control: can't accept new client: {e}");
+                        continue;
+                    }
+                };
+                // a client waiting before its command does not block other ones
+                let control = control.clone();
+                let spawned = thread::Builder::new()
+                    .name("lidi_control_client".into())
+                    .spawn(move || {
+                        if let Err(e) = handle_client(client, control.as_ref()) {
+                            log::debug!("control: client error: {e}");
+                        }
+                    });
+                if let Err(e) = spawned {
+                    log::warn!("control: can't start client thread: {e}");
+                }
+            }
+        })
+}
+
+/// bind the socket in a private directory and move it to `path` once only its owner can use it,
+/// so that it is never reachable with wider permissions
+fn bind(path: &Path) -> Result<UnixListener> {
+    let mut private = path.as_os_str().to_owned();
+    private.push(format!(".{}", process::id()));
+    let private = PathBuf::from(private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("control.sock");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+
+    if let Err(e) = fs::remove_dir_all(&private) {
+        log::warn!("control: cannot delete \"{}\": {e}", private.display());
+    }
+
+    listener
+}
+
+fn handle_client<C: Control>(client: UnixStream, control: &C) -> Result<()> {
+    client.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = client.try_clone()?;
+
+    for line in BufReader::new(client).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match execute(&line, control) {
+            Ok(None) => json!({ "ok": true }),
+            Ok(Some(status)) => json!({ "ok": true, "status": status }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        };
+
+        writeln!(writer, "{response}")?;
+    }
+
+    Ok(())
+}
+
+fn execute<C: Control>(line: &str, control: &C) -> Result<Option<Value>> {
+    log::debug!("control: command '{line}'");
+
+    let mut args = line.split_whitespace();
+    let command = args.next().unwrap_or_default();
+    let arg = args.next();
+
+    if args.next().is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("too many arguments for command '{command}'"),
+        ));
+    }
+
+    match (command, arg) {
+        ("status", None) => Ok(Some(control.status())),
+        ("abort", None) => {
+            log::info!("control: abort current session");
+            control.abort().map(|_| None)
+        }
+        ("set-rate", Some(rate)) => {
+            let max_bandwidth = match rate {
+                "none" => None,
+                rate => match crate::config::parse_max_bandwidth(rate) {
+                    Ok(rate) => Some(rate),
+                    Err(e) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid rate '{rate}': {e}"),
+                        ))
+                    }
+                },
+            };
+            control.set_rate(max_bandwidth).map(|_| None)
+        }
+        ("set-log-level", Some(level)) => crate::set_log_level(level).map(|_| None),
+        ("status" | "abort", Some(_)) | ("set-rate" | "set-log-level", None) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid arguments for command '{command}'"),
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown command '{command}'"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{execute, start, Control, Totals};
+    use crate::test::TempDir;
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Dummy {
+        rate: Mutex<Option<Option<f64>>>,
+    }
+
+    impl Control for Dummy {
+        fn status(&self) -> Value {
+            json!({ "session": null })
+        }
+
+        fn abort(&self) -> Result<()> {
+            Err(Error::new(ErrorKind::NotFound, "no active session"))
+        }
+
+        fn set_rate(&self, max_bandwidth: Option<f64>) -> Result<()> {
+            *self.rate.lock().unwrap() = Some(max_bandwidth);
+            Ok(())
+        }
+
+        fn totals(&self) -> Totals {
+            Totals::default()
+        }
+    }
+
+    #[test]
+    fn test_execute() {
+        let control = Dummy::default();
+
+        assert_eq!(
+            execute("status", &control).unwrap(),
+            Some(json!({ "session": null }))
+        );
+        assert!(execute("abort", &control).is_err());
+
+        assert_eq!(execute("set-rate 250.5", &control).unwrap(), None);
+        assert_eq!(*control.rate.lock().unwrap(), Some(Some(250.5)));
+        assert_eq!(execute("set-rate none", &control).unwrap(), None);
+        assert_eq!(*control.rate.lock().unwrap(), Some(None));
+
+        // same units and limits as max_bandwidth in the configuration file
+        assert_eq!(execute("set-rate 1.5Gbit/s", &control).unwrap(), None);
+        assert_eq!(*control.rate.lock().unwrap(), Some(Some(1500.0)));
+        assert_eq!(execute("set-rate 2kbit", &control).unwrap(), None);
+        assert_eq!(*control.rate.lock().unwrap(), Some(Some(0.002)));
+
+        assert!(execute("set-rate 0", &control).is_err());
+        assert!(execute("set-rate 0.0001", &control).is_err());
+        assert!(execute("set-rate inf", &control).is_err());
+        assert!(execute("set-rate 1e-300", &control).is_err());
+        assert!(execute("set-rate NaN", &control).is_err());
+        assert_eq!(*control.rate.lock().unwrap(), Some(Some(0.002)));
+        assert!(execute("set-rate fast", &control).is_err());
+        assert!(execute("set-rate", &control).is_err());
+        assert!(execute("status now", &control).is_err());
+        assert!(execute("set-log-level debug info", &control).is_err());
+        assert!(execute("set-log-level loud", &control).is_err());
+        assert!(execute("reboot", &control).is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = TempDir::new("control");
+        let path = dir.join("control.sock");
+        let path = path.to_str().unwrap();
+
+        start(path, Arc::new(Dummy::default())).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // socket was bound in a private directory, now removed
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+
+        // socket of a running instance is kept
+        let error = start(path, Arc::new(Dummy::default())).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+
+        // a client waiting before its command does not block other ones
+        let _idle = UnixStream::connect(path).unwrap();
+        let client = UnixStream::connect(path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        writeln!(&client, "status").unwrap();
+        let mut response = String::new();
+        BufReader::new(&client).read_line(&mut response).unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(
+            response,
+            json!({ "ok": true, "status": { "session": null } })
+        );
+
+        // socket left by a stopped instance is replaced
+        let stale = dir.join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        start(stale.to_str().unwrap(), Arc::new(Dummy::default())).unwrap();
+        assert!(UnixStream::connect(&stale).is_ok());
+
+        // other files are never removed
+        let other = dir.join("other");
+        std::fs::write(&other, "").unwrap();
+        assert!(start(other.to_str().unwrap(), Arc::new(Dummy::default())).is_err());
+        assert!(other.is_file());
+    }
+}
@@ -0,0 +1,208 @@
+//! Chunks of large files, received in separate sessions and reassembled by diode-receive-file
+//!
+//! Chunks are written at their offset in `<output_dir>/.lidi-chunks/<file id>/data`. Received
+//! chunks are listed in `state.json` next to it, so that a transfer survives restarts and a
+//! resend of the file only needs the missing chunks.
+
+use crate::file::{self, protocol::Chunk};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path};
+
+/// directory of partially received files, in output directory
+pub const CHUNKS_DIR: &str = ".lidi-chunks";
+
+const STATE_FILE: &str = "state.json";
+const DATA_FILE: &str = "data";
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct State {
+    file_name: String,
+    file_length: u64,
+    chunk_size: u64,
+    count: u64,
+    /// indexes of received chunks, sorted
+    received: Vec<u64>,
+}
+
+/// File being received in chunks
+pub struct PartialFile {
+    dir: path::PathBuf,
+    state: State,
+}
+
+impl PartialFile {
+    /// file of `chunk`, with chunks already received. State of another file with the same
+    /// identifier, or of the same file with other chunk sizes, is discarded.
+    pub fn open(output_dir: &path::Path, file_name: &str, chunk: &Chunk) -> io::Result<Self> {
+        let dir = output_dir
+            .join(CHUNKS_DIR)
+            .join(format!("{:016x}", chunk.file_id));
+        fs::create_dir_all(&dir)?;
+
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(state) => serde_json::from_slice::<State>(&state).ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let state = match state {
+            Some(state)
+                if state.file_name == file_name
+                    && state.file_length == chunk.file_length
+                    && state.chunk_size == chunk.chunk_size
+                    && state.count == chunk.count =>
+            {
+                state
+            }
+            _ => {
+                let state = State {
+                    file_name: file_name.to_string(),
+                    file_length: chunk.file_length,
+                    chunk_size: chunk.chunk_size,
+                    count: chunk.count,
+                    received: vec![],
+                };
+                let data = fs::File::create(dir.join(DATA_FILE))?;
+                // sparse file, chunks are written at their offset
+                data.set_len(chunk.file_length)?;
+                write_state(&dir, &state)?;
+                state
+            }
+        };
+
+        Ok(Self { dir, state })
+    }
+
+    pub fn data_path(&self) -> path::PathBuf {
+        self.dir.join(DATA_FILE)
+    }
+
+    pub fn is_received(&self, index: u64) -> bool {
+        self.state.received.binary_search(&index).is_ok()
+    }
+
+    /// record a chunk written and synced to data file
+    pub fn set_received(&mut self, index: u64) -> io::Result<()> {
+        if let Err(position) = self.state.received.binary_search(&index) {
+            self.state.received.insert(position, index);
+            write_state(&self.dir, &self.state)?;
+        }
+        Ok(())
+    }
+
+    pub fn received(&self) -> u64 {
+        self.state.received.len() as u64
+    }
+
+    pub fn count(&self) -> u64 {
+        self.state.count
+    }
+
+    pub fn missing(&self) -> Vec<u64> {
+        (0..self.state.count)
+            .filter(|index| !self.is_received(*index))
+            .collect()
+    }
+
+    /// delete state and data left after delivery
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+}
+
+/// state is replaced atomically, and synced to disk
+fn write_state(dir: &path::Path, state: &State) -> io::Result<()> {
+    let json = serde_json::to_vec(state).map_err(io::Error::other)?;
+    file::write_atomic(&dir.join(STATE_FILE), &json)
+}
+
+/// chunk indexes as ranges, ex "0-2,5"
+pub fn format_ranges(indexes: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &index in indexes {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// chunk indexes from ranges, ex "0-2,5"
+pub fn parse_ranges(ranges: &str) -> Result<Vec<u64>, String> {
+    let parse = |index: &str| {
+        index
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| format!("invalid chunk index '{index}' in '{ranges}'"))
+    };
+
+    let mut indexes = vec![];
+    for range in ranges.split(',') {
+        match range.split_once('-') {
+            Some((start, end)) => indexes.extend(parse(start)?..=parse(end)?),
+            None => indexes.push(parse(range)?),
+        }
+    }
+    indexes.sort_unstable();
+    indexes.dedup();
+    Ok(indexes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_ranges, parse_ranges, PartialFile};
+    use crate::file::protocol::Chunk;
+    use crate::test::TempDir;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(parse_ranges("0-2,5"), Ok(vec![0, 1, 2, 5]));
+        assert_eq!(parse_ranges("7, 3,3-4"), Ok(vec![3, 4, 7]));
+        assert!(parse_ranges("1-x").is_err());
+        assert!(parse_ranges("").is_err());
+        assert_eq!(format_ranges(&[0, 1, 2, 5, 7, 8]), "0-2,5,7-8");
+        assert_eq!(format_ranges(&[]), "");
+    }
+
+    #[test]
+    fn test_partial_file() {
+        let dir = TempDir::new("chunks");
+
+        let chunk = Chunk {
+            file_id: 42,
+            index: 1,
+            count: 3,
+            chunk_size: 10,
+            file_length: 25,
+        };
+
+        let mut partial = PartialFile::open(&dir, "big.bin", &chunk).unwrap();
+        assert_eq!(
+            std::fs::metadata(partial.data_path()).unwrap().len(),
+            chunk.file_length
+        );
+        partial.set_received(1).unwrap();
+
+        // state survives a restart
+        let mut partial = PartialFile::open(&dir, "big.bin", &chunk).unwrap();
+        assert!(partial.is_received(1));
+        assert_eq!(partial.missing(), vec![0, 2]);
+        partial.set_received(2).unwrap();
+        partial.set_received(0).unwrap();
+        assert_eq!(partial.received(), partial.count());
+
+        // another file with the same identifier starts over
+        let partial = PartialFile::open(&dir, "other.bin", &chunk).unwrap();
+        assert_eq!(partial.received(), 0);
+        partial.remove().unwrap();
+    }
+}
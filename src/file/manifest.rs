@@ -0,0 +1,262 @@
+//! Manifests of sent files, so that diode-receive-file detects files which never arrived
+//!
+//! diode-send-dir periodically sends a manifest listing recently sent files, as the last entry of
+//! a session. It is signed with HMAC-SHA256 when a key is shared by both sides. diode-receive-file
+//! checks each listed file against the files it stored, and alerts about missing ones.
+
+use crate::audit::to_hex;
+use crate::file::receive::relative_path;
+use chrono::{SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs, io, path,
+};
+
+/// name of manifest entries in the stream, never stored
+pub const MANIFEST_NAME: &str = ".lidi-manifest";
+
+/// maximum size of a received manifest
+pub const MAX_MANIFEST_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// maximum number of stored files remembered by the receiver
+const MAX_DELIVERED: usize = 100_000;
+
+/// File listed in a manifest
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Entry {
+    /// number of the file, in sending order
+    pub seq: u64,
+    pub name: String,
+    pub size: u64,
+    /// hash sent in the footer of the file, empty without hash
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    /// number of the manifest, in sending order
+    number: u64,
+    created: String,
+    hash_algorithm: String,
+    files: Vec<Entry>,
+}
+
+/// key shared by sender and receiver
+pub fn load_key(path: &path::Path) -> io::Result<Vec<u8>> {
+    let key = fs::read(path)?;
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "empty manifest key",
+        ));
+    }
+    Ok(key)
+}
+
+fn mac(key: &[u8], content: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(content);
+    mac
+}
+
+/// Files recently sent, listed in the next manifests
+pub struct Sender {
+    key: Option<Vec<u8>>,
+    /// number of files listed in a manifest
+    capacity: usize,
+    number: u64,
+    files: VecDeque<Entry>,
+}
+
+impl Sender {
+    pub fn new(key: Option<Vec<u8>>, capacity: usize) -> Self {
+        Self {
+            key,
+            capacity,
+            number: 0,
+            files: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// record file `seq` sent
+    pub fn add(&mut self, seq: u64, name: &str, size: u64, hash: &[u8]) {
+        if self.files.len() == self.capacity {
+            self.files.pop_front();
+        }
+        self.files.push_back(Entry {
+            seq,
+            name: name.to_string(),
+            size,
+            hash: to_hex(hash),
+        });
+    }
+
+    /// content of next manifest, and its signature (empty without key)
+    pub fn next(&mut self, hash_algorithm: &str) -> (Vec<u8>, Vec<u8>) {
+        self.number += 1;
+        let manifest = Manifest {
+            number: self.number,
+            created: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            hash_algorithm: hash_algorithm.to_string(),
+            files: self.files.iter().cloned().collect(),
+        };
+        let content = serde_json::to_vec(&manifest).expect("manifest serializes to JSON");
+        let signature = match &self.key {
+            Some(key) => mac(key, &content).finalize().into_bytes().to_vec(),
+            None => vec![],
+        };
+        (content, signature)
+    }
+}
+
+/// Files stored by the receiver, checked against manifests
+pub struct Checker {
+    key: Option<Vec<u8>>,
+    /// size and hash of stored files, by name
+    delivered: HashMap<String, (u64, Vec<u8>)>,
+    /// names in storing order, to forget the oldest files
+    order: VecDeque<String>,
+    /// missing files of last manifest, already reported
+    alerted: HashSet<(u64, String)>,
+}
+
+impl Checker {
+    pub fn new(key: Option<Vec<u8>>) -> Self {
+        Self {
+            key,
+            delivered: HashMap::new(),
+            order: VecDeque::new(),
+            alerted: HashSet::new(),
+        }
+    }
+
+    /// record a file stored, `hash` is empty when sent without hash
+    pub fn delivered(&mut self, name: &str, size: u64, hash: &[u8]) {
+        if self
+            .delivered
+            .insert(name.to_string(), (size, hash.to_vec()))
+            .is_none()
+        {
+            self.order.push_back(name.to_string());
+            if self.order.len() > MAX_DELIVERED {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.delivered.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// files listed in the manifest were stored, or are still in `output_dir` with the same size
+    fn stored(&self, output_dir: &path::Path, entry: &Entry) -> bool {
+        if let Some((size, hash)) = self.delivered.get(&entry.name) {
+            return *size == entry.size
+                && (hash.is_empty() || entry.hash.is_empty() || to_hex(hash) == entry.hash);
+        }
+        // stored before diode-receive-file restarted
+        relative_path(&entry.name)
+            .ok()
+            .and_then(|relative| fs::symlink_metadata(output_dir.join(relative)).ok())
+            .is_some_and(|metadata| metadata.is_file() && metadata.len() == entry.size)
+    }
+
+    /// verify signature of a manifest and check its files, returns missing ones. Each missing file
+    /// is reported once, as long as next manifests list it.
+    pub fn check(
+        &mut self,
+        output_dir: &path::Path,
+        content: &[u8],
+        signature: &[u8],
+    ) -> Result<Vec<Entry>, String> {
+        match &self.key {
+            Some(key) if mac(key, content).verify_slice(signature).is_err() => {
+                counter!("rx_file_manifests", "status" => "invalid_signature").increment(1);
+                return Err("manifest with invalid signature".to_string());
+            }
+            None if !signature.is_empty() => {
+                log::debug!("manifest signature not verified, no key");
+            }
+            _ => (),
+        }
+
+        let manifest: Manifest = serde_json::from_slice(content).map_err(|e| {
+            counter!("rx_file_manifests", "status" => "invalid").increment(1);
+            format!("invalid manifest: {e}")
+        })?;
+        counter!("rx_file_manifests", "status" => "valid").increment(1);
+
+        let mut missing = vec![];
+        let mut alerted = HashSet::new();
+        for entry in manifest.files {
+            if self.stored(output_dir, &entry) {
+                continue;
+            }
+            let key = (entry.seq, entry.name.clone());
+            if !self.alerted.contains(&key) {
+                log::error!(
+                    "file #{} \"{}\" ({} bytes), listed in manifest {}, is missing",
+                    entry.seq,
+                    entry.name,
+                    entry.size,
+                    manifest.number
+                );
+            }
+            alerted.insert(key);
+            missing.push(entry);
+        }
+        self.alerted = alerted;
+
+        gauge!("rx_file_missing_files").set(missing.len() as f64);
+        log::info!(
+            "manifest {} created at {}: {} missing files",
+            manifest.number,
+            manifest.created,
+            missing.len()
+        );
+
+        Ok(missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checker, Sender};
+    use crate::test::TempDir;
+
+    #[test]
+    fn test_manifest() {
+        let dir = TempDir::new("manifest");
+
+        let mut sender = Sender::new(Some(b"secret".to_vec()), 3);
+        sender.add(1, "old", 1, &[]);
+        sender.add(2, "a", 5, &[0xab]);
+        sender.add(3, "b", 5, &[0xcd]);
+        sender.add(4, "c", 3, &[]);
+        let (content, signature) = sender.next("sha256");
+
+        let mut checker = Checker::new(Some(b"secret".to_vec()));
+        checker.delivered("a", 5, &[0xab]);
+        checker.delivered("b", 5, &[0xee]);
+        // received before a restart
+        std::fs::write(dir.join("c"), "abc").unwrap();
+
+        // "old" is not listed anymore, "b" differs
+        let missing = checker.check(&dir, &content, &signature).unwrap();
+        assert_eq!(
+            missing.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        assert!(checker.check(&dir, &content, b"forged").is_err());
+        let mut other = Checker::new(Some(b"other".to_vec()));
+        assert!(other.check(&dir, &content, &signature).is_err());
+
+        // without key, signature is not verified
+        let mut unsigned = Checker::new(None);
+        assert_eq!(unsigned.check(&dir, &content, &[]).unwrap().len(), 2);
+        assert!(unsigned.check(&dir, b"not json", &[]).is_err());
+    }
+}
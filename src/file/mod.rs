@@ -1,21 +1,160 @@
 //! Module for sending/receiving entire files into/from Lidi TCP or Unix sockets
+pub mod chunks;
+pub mod manifest;
 pub mod protocol;
+mod quarantine;
 pub mod receive;
 pub mod send;
+pub mod sequence;
 
-use std::{fmt, io, net};
+use std::{fmt, fs, io, io::Write, net, path, str::FromStr};
 
-#[derive(Copy, Clone)]
+/// How owner of files is sent or restored
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OwnerMapping {
+    /// owner is not sent, or not restored
+    #[default]
+    None,
+    /// same user and group ids
+    Id,
+    /// same user and group names, owner is left unchanged for names unknown to the receiver
+    Name,
+}
+
+impl fmt::Display for OwnerMapping {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let name = match self {
+            Self::None => "none",
+            Self::Id => "id",
+            Self::Name => "name",
+        };
+        write!(fmt, "{name}")
+    }
+}
+
+impl FromStr for OwnerMapping {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [Self::None, Self::Id, Self::Name]
+            .into_iter()
+            .find(|mapping| mapping.to_string() == name.to_lowercase())
+            .ok_or_else(|| format!("unknown owner mapping '{name}', expected none, id or name"))
+    }
+}
+
+/// What diode-receive-file does with a file whose name is already used
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConflictPolicy {
+    /// keep existing file, received file is dropped
+    #[default]
+    Reject,
+    /// replace existing file
+    Overwrite,
+    /// store received file with a numeric suffix
+    Rename,
+    /// store received file with a timestamp suffix
+    RenameTimestamp,
+    /// move existing file to the versions subdirectory, then replace it
+    Version,
+}
+
+impl ConflictPolicy {
+    const ALL: [Self; 5] = [
+        Self::Reject,
+        Self::Overwrite,
+        Self::Rename,
+        Self::RenameTimestamp,
+        Self::Version,
+    ];
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let name = match self {
+            Self::Reject => "reject",
+            Self::Overwrite => "overwrite",
+            Self::Rename => "rename",
+            Self::RenameTimestamp => "rename-timestamp",
+            Self::Version => "version",
+        };
+        write!(fmt, "{name}")
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.to_string() == name.to_lowercase())
+            .ok_or_else(|| {
+                format!("unknown conflict policy '{name}', expected reject, overwrite, rename, rename-timestamp or version")
+            })
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub diode: net::SocketAddr,
     pub buffer_size: usize,
-    pub hash: bool,
+    /// hash algorithm used by sender, or required by receiver
+    pub hash: protocol::HashAlgorithm,
+    /// owner sent by sender (any value but none), or restored by receiver when running as root
+    pub owner: OwnerMapping,
+    /// namespaces of extended attributes sent by sender, or restored by receiver (ex "user")
+    pub xattrs: Vec<String>,
+    /// what receiver does when a file already exists
+    pub on_conflict: ConflictPolicy,
+    /// where receiver moves files failing checks, they are deleted otherwise
+    pub quarantine_dir: Option<path::PathBuf>,
+    /// sender splits larger files in chunks of this size, each one sent in its own session
+    pub chunk_size: Option<u64>,
+    /// chunks sent by sender, all if none
+    pub chunks: Option<Vec<u64>>,
+    /// sender sends content identifiers, so that receiver skips copies of files it already holds
+    pub content_id: bool,
+    /// file of the key signing manifests, shared by sender and receiver
+    pub manifest_key: Option<path::PathBuf>,
+    /// receiver keeps setuid and setgid bits of received files and directories
+    pub keep_setid: bool,
+}
+
+impl Config {
+    /// extended attribute `name` belongs to one of the configured namespaces
+    pub fn xattr_selected(&self, name: &str) -> bool {
+        name.split_once('.')
+            .is_some_and(|(namespace, _)| self.xattrs.iter().any(|ns| ns == namespace))
+    }
+}
+
+/// replace file `path` with `content` atomically, surviving power loss: the temporary file is
+/// synced before being renamed, then the directory is synced so that the rename is persisted
+pub(crate) fn write_atomic(path: &path::Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => path::Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
 }
 
 pub enum Error {
     Io(io::Error),
     Diode(protocol::Error),
     Other(String),
+    /// file skipped, next files of the session can be received
+    Rejected(String),
+    /// copy of a file already received, skipped
+    AlreadyReceived(String),
 }
 
 impl fmt::Display for Error {
@@ -24,6 +163,8 @@ impl fmt::Display for Error {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::Diode(e) => write!(fmt, "diode error: {e}"),
             Self::Other(e) => write!(fmt, "error: {e}"),
+            Self::Rejected(e) => write!(fmt, "{e}"),
+            Self::AlreadyReceived(e) => write!(fmt, "{e} already received"),
         }
     }
 }
@@ -1,14 +1,22 @@
+use fasthash::HasherExt;
+use sha2::{Digest, Sha256};
 use std::{
-    fmt, io,
+    fmt,
+    hash::Hasher,
+    io,
     io::{Read, Write},
+    str::FromStr,
     string::FromUtf8Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub enum Error {
     Io(io::Error),
     StringFormatError(FromUtf8Error),
     InvalidFileSize(usize, usize),
-    InvalidHash(u128, u128),
+    InvalidHash(String, String),
+    /// hash algorithm used by sender (second) is not the one required by receiver (first)
+    UnexpectedHashAlgorithm(HashAlgorithm, HashAlgorithm),
 }
 
 impl fmt::Display for Error {
@@ -17,7 +25,10 @@ impl fmt::Display for Error {
             Self::Io(e) => write!(fmt, "I/O error: {e}"),
             Self::StringFormatError(e) => write!(fmt, "string format error: {e}"),
             Self::InvalidFileSize(s1, s2) => write!(fmt, "invalid file size: {s1} != {s2}"),
-            Self::InvalidHash(h1, h2) => write!(fmt, "invalid hash: {h1:x} != {h2:x}"),
+            Self::InvalidHash(h1, h2) => write!(fmt, "invalid hash: {h1} != {h2}"),
+            Self::UnexpectedHashAlgorithm(required, used) => {
+                write!(fmt, "hash algorithm {used} used, {required} required")
+            }
         }
     }
 }
@@ -34,10 +45,485 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+/// Algorithm of the hash of file content, sent in the footer of each file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// no hash
+    #[default]
+    None,
+    /// Murmur3 128 bits, not cryptographic
+    Murmur3,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    const ALL: [Self; 4] = [Self::None, Self::Murmur3, Self::Sha256, Self::Blake3];
+
+    /// identifier in file header
+    fn id(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Murmur3 => 1,
+            Self::Sha256 => 2,
+            Self::Blake3 => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, Error> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+            .ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown hash algorithm {id}"),
+                ))
+            })
+    }
+
+    /// size of the hash in footer
+    pub fn size(self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Murmur3 => 16,
+            Self::Sha256 | Self::Blake3 => 32,
+        }
+    }
+
+    pub fn hasher(self) -> FileHasher {
+        match self {
+            Self::None => FileHasher::None,
+            Self::Murmur3 => FileHasher::Murmur3(fasthash::Murmur3HasherExt::default()),
+            Self::Sha256 => FileHasher::Sha256(Sha256::new()),
+            Self::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let name = match self {
+            Self::None => "none",
+            Self::Murmur3 => "murmur3",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        };
+        write!(fmt, "{name}")
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.to_string() == name.to_lowercase())
+            .ok_or_else(|| {
+                format!("unknown hash algorithm '{name}', expected none, murmur3, sha256 or blake3")
+            })
+    }
+}
+
+/// Hash of file content being sent or received
+pub enum FileHasher {
+    None,
+    Murmur3(fasthash::Murmur3HasherExt),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::None => (),
+            Self::Murmur3(hasher) => hasher.write(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Self::None => vec![],
+            Self::Murmur3(hasher) => hasher.finish_ext().to_le_bytes().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// version of metadata written by this implementation. Later versions only append fields, so
+/// readers ignore the trailing bytes they do not know.
+/// - 1: times, owner and extended attributes,
+/// - 2: chunk of a large file,
+/// - 3: content identifier,
+/// - 4: manifest of sent files,
+/// - 5: sender and sequence number.
+const METADATA_VERSION: u8 = 5;
+
+/// maximum size of serialized metadata
+const MAX_METADATA_LENGTH: usize = 1024 * 1024;
+
+const METADATA_TIMES: u8 = 0b01;
+const METADATA_OWNER: u8 = 0b10;
+const METADATA_CHUNK: u8 = 0b100;
+const METADATA_CONTENT_ID: u8 = 0b1000;
+const METADATA_MANIFEST: u8 = 0b10000;
+const METADATA_SEQUENCE: u8 = 0b100000;
+
+/// Identifier of file content, its sha256 hash. Receivers recognise copies of files they
+/// already hold.
+pub type ContentId = [u8; 32];
+
+/// content identifier of all data read from `r`
+pub fn content_id<R: Read>(r: &mut R) -> io::Result<ContentId> {
+    let mut hasher = ContentIdHasher::default();
+    io::copy(r, &mut hasher.0)?;
+    Ok(hasher.finish())
+}
+
+/// content identifier computed while data is written
+#[derive(Default)]
+pub struct ContentIdHasher(Sha256);
+
+impl ContentIdHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> ContentId {
+        self.0.finalize().into()
+    }
+}
+
+/// Part of a large file, sent in its own session
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    /// identifier of the file, the same for all its chunks and when the file is sent again
+    pub file_id: u64,
+    /// from 0 to count - 1
+    pub index: u64,
+    pub count: u64,
+    /// size of all chunks but the last one
+    pub chunk_size: u64,
+    /// size of the whole file
+    pub file_length: u64,
+}
+
+impl Chunk {
+    /// position of the chunk in the file
+    pub fn offset(&self) -> u64 {
+        self.index * self.chunk_size
+    }
+
+    pub fn length(&self) -> u64 {
+        self.chunk_size
+            .min(self.file_length.saturating_sub(self.offset()))
+    }
+
+    /// chunks of a file of `file_length` bytes
+    pub fn count(file_length: u64, chunk_size: u64) -> u64 {
+        file_length.div_ceil(chunk_size)
+    }
+
+    /// fields are consistent, and the chunk has `length` bytes
+    pub fn is_valid(&self, length: u64) -> bool {
+        self.chunk_size != 0
+            && self.count == Self::count(self.file_length, self.chunk_size)
+            && self.index < self.count
+            && self.length() == length
+    }
+}
+
+/// Position of a file among the files sent by a sender
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sequence {
+    /// identifier of the sender
+    pub sender: String,
+    /// incremented for each file, kept across restarts of the sender
+    pub seq: u64,
+}
+
+/// Owner of a file, names are empty when unknown to the sender
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    pub user: String,
+    pub group: String,
+}
+
+/// Optional metadata of a file or directory, restored by the receiver once content is checked
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Metadata {
+    /// modification and access times
+    pub times: Option<(SystemTime, SystemTime)>,
+    pub owner: Option<Owner>,
+    /// extended attributes, with their namespace (ex "user.comment")
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+fn serialize_time<W: Write>(w: &mut W, time: SystemTime) -> Result<(), Error> {
+    // times before epoch have negative seconds and positive nanoseconds
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    };
+    w.write_all(&secs.to_le_bytes())?;
+    w.write_all(&nanos.to_le_bytes())?;
+    Ok(())
+}
+
+fn deserialize_time<R: Read>(r: &mut R) -> Result<SystemTime, Error> {
+    let mut secs = [0u8; 8];
+    r.read_exact(&mut secs)?;
+    let secs = i64::from_le_bytes(secs);
+    let mut nanos = [0u8; 4];
+    r.read_exact(&mut nanos)?;
+    let nanos = Duration::from_nanos(u64::from(u32::from_le_bytes(nanos)));
+
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64) + nanos)
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(nanos))
+    };
+    time.ok_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid timestamp",
+        ))
+    })
+}
+
+fn serialize_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+fn deserialize_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_METADATA_LENGTH {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid metadata length",
+        )));
+    }
+    let mut bytes = vec![0; len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl Header {
+    /// version, length and fields, so that readers can skip what they do not know
+    fn serialize_metadata<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let metadata = &self.metadata;
+        let mut data = vec![];
+
+        let mut flags = 0;
+        if metadata.times.is_some() {
+            flags |= METADATA_TIMES;
+        }
+        if metadata.owner.is_some() {
+            flags |= METADATA_OWNER;
+        }
+        if self.chunk.is_some() {
+            flags |= METADATA_CHUNK;
+        }
+        if self.content_id.is_some() {
+            flags |= METADATA_CONTENT_ID;
+        }
+        if self.manifest.is_some() {
+            flags |= METADATA_MANIFEST;
+        }
+        if self.sequence.is_some() {
+            flags |= METADATA_SEQUENCE;
+        }
+        data.push(flags);
+
+        if let Some((mtime, atime)) = metadata.times {
+            serialize_time(&mut data, mtime)?;
+            serialize_time(&mut data, atime)?;
+        }
+
+        if let Some(owner) = &metadata.owner {
+            data.extend_from_slice(&owner.uid.to_le_bytes());
+            data.extend_from_slice(&owner.gid.to_le_bytes());
+            serialize_bytes(&mut data, owner.user.as_bytes())?;
+            serialize_bytes(&mut data, owner.group.as_bytes())?;
+        }
+
+        data.extend_from_slice(&(metadata.xattrs.len() as u32).to_le_bytes());
+        for (name, value) in &metadata.xattrs {
+            serialize_bytes(&mut data, name.as_bytes())?;
+            serialize_bytes(&mut data, value)?;
+        }
+
+        if let Some(chunk) = self.chunk {
+            for field in [
+                chunk.file_id,
+                chunk.index,
+                chunk.count,
+                chunk.chunk_size,
+                chunk.file_length,
+            ] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        if let Some(content_id) = &self.content_id {
+            data.extend_from_slice(content_id);
+        }
+
+        if let Some(signature) = &self.manifest {
+            serialize_bytes(&mut data, signature)?;
+        }
+
+        if let Some(sequence) = &self.sequence {
+            serialize_bytes(&mut data, sequence.sender.as_bytes())?;
+            data.extend_from_slice(&sequence.seq.to_le_bytes());
+        }
+
+        if data.len() > MAX_METADATA_LENGTH {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "metadata too large",
+            )));
+        }
+
+        w.write_all(&[METADATA_VERSION])?;
+        serialize_bytes(w, &data)
+    }
+
+    /// read metadata, chunk, content identifier, manifest signature and sequence into header
+    fn deserialize_metadata<R: Read>(&mut self, r: &mut R) -> Result<(), Error> {
+        let mut version = [0u8; 1];
+        r.read_exact(&mut version)?;
+        let data = deserialize_bytes(r)?;
+
+        // version 0 carries no metadata
+        if version[0] == 0 {
+            return Ok(());
+        }
+
+        let metadata = &mut self.metadata;
+
+        let mut r = data.as_slice();
+
+        let mut flags = [0u8; 1];
+        r.read_exact(&mut flags)?;
+        let flags = flags[0];
+
+        if flags & METADATA_TIMES != 0 {
+            let mtime = deserialize_time(&mut r)?;
+            let atime = deserialize_time(&mut r)?;
+            metadata.times = Some((mtime, atime));
+        }
+
+        if flags & METADATA_OWNER != 0 {
+            let mut id = [0u8; 4];
+            r.read_exact(&mut id)?;
+            let uid = u32::from_le_bytes(id);
+            r.read_exact(&mut id)?;
+            let gid = u32::from_le_bytes(id);
+            let user = String::from_utf8(deserialize_bytes(&mut r)?)?;
+            let group = String::from_utf8(deserialize_bytes(&mut r)?)?;
+            metadata.owner = Some(Owner {
+                uid,
+                gid,
+                user,
+                group,
+            });
+        }
+
+        let mut count = [0u8; 4];
+        r.read_exact(&mut count)?;
+        for _ in 0..u32::from_le_bytes(count) {
+            let name = String::from_utf8(deserialize_bytes(&mut r)?)?;
+            let value = deserialize_bytes(&mut r)?;
+            metadata.xattrs.push((name, value));
+        }
+
+        if flags & METADATA_CHUNK != 0 {
+            let mut fields = [0u64; 5];
+            for field in &mut fields {
+                let mut value = [0u8; 8];
+                r.read_exact(&mut value)?;
+                *field = u64::from_le_bytes(value);
+            }
+            let [file_id, index, count, chunk_size, file_length] = fields;
+            self.chunk = Some(Chunk {
+                file_id,
+                index,
+                count,
+                chunk_size,
+                file_length,
+            });
+        }
+
+        if flags & METADATA_CONTENT_ID != 0 {
+            let mut content_id = ContentId::default();
+            r.read_exact(&mut content_id)?;
+            self.content_id = Some(content_id);
+        }
+
+        if flags & METADATA_MANIFEST != 0 {
+            self.manifest = Some(deserialize_bytes(&mut r)?);
+        }
+
+        if flags & METADATA_SEQUENCE != 0 {
+            let sender = String::from_utf8(deserialize_bytes(&mut r)?)?;
+            let mut seq = [0u8; 8];
+            r.read_exact(&mut seq)?;
+            self.sequence = Some(Sequence {
+                sender,
+                seq: u64::from_le_bytes(seq),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// file type bits of unix mode
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+
+/// maximum length of a file name, relative path included
+const MAX_NAME_LENGTH: usize = 4096;
+
+#[derive(Clone)]
 pub struct Header {
+    /// relative path, with `/` separators
     pub file_name: String,
+    /// unix mode, with file type bits
     pub mode: u32,
+    /// length of content, the chunk only for a chunk
     pub file_length: u64,
+    /// algorithm of the hash in footer
+    pub hash: HashAlgorithm,
+    pub metadata: Metadata,
+    pub chunk: Option<Chunk>,
+    pub content_id: Option<ContentId>,
+    /// content is a manifest of sent files, not a file, with its signature (empty if unsigned)
+    pub manifest: Option<Vec<u8>>,
+    pub sequence: Option<Sequence>,
 }
 
 impl Header {
@@ -46,7 +532,8 @@ impl Header {
         w.write_all(self.file_name.as_bytes())?;
         w.write_all(&self.mode.to_le_bytes())?;
         w.write_all(&self.file_length.to_le_bytes())?;
-        Ok(())
+        w.write_all(&[self.hash.id()])?;
+        self.serialize_metadata(w)
     }
 
     pub fn deserialize_from<R: Read>(r: &mut R) -> Result<Self, Error> {
@@ -56,7 +543,7 @@ impl Header {
         r.read_exact(&mut file_name_len)?;
         let file_name_len = usize::from_le_bytes(file_name_len);
 
-        if file_name_len > 1024 {
+        if file_name_len > MAX_NAME_LENGTH {
             return Err(Error::Io(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Invalid name length",
@@ -75,22 +562,41 @@ impl Header {
         r.read_exact(&mut file_length)?;
         let file_length = u64::from_le_bytes(file_length);
 
-        Ok(Self {
+        let mut hash = [0u8; 1];
+        r.read_exact(&mut hash)?;
+        let hash = HashAlgorithm::from_id(hash[0])?;
+
+        let mut header = Self {
             file_name,
             mode,
             file_length,
-        })
+            hash,
+            metadata: Metadata::default(),
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: None,
+        };
+        header.deserialize_metadata(r)?;
+
+        Ok(header)
+    }
+
+    /// entry is a directory, without content
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
     }
 }
 
 pub struct Footer {
-    pub hash: u128,
+    /// hash of file content, its size depends on the algorithm in header
+    pub hash: Vec<u8>,
     pub stream_end: bool,
 }
 
 impl Footer {
     pub fn serialize_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
-        w.write_all(&self.hash.to_le_bytes())?;
+        w.write_all(&self.hash)?;
         let mut stream_end = [0u8; 1];
         if self.stream_end {
             stream_end[0] = 1;
@@ -102,10 +608,9 @@ impl Footer {
         Ok(())
     }
 
-    pub fn deserialize_from<R: Read>(r: &mut R) -> Result<Self, Error> {
-        let mut hash = [0u8; 16];
+    pub fn deserialize_from<R: Read>(r: &mut R, algorithm: HashAlgorithm) -> Result<Self, Error> {
+        let mut hash = vec![0u8; algorithm.size()];
         r.read_exact(&mut hash)?;
-        let hash = u128::from_le_bytes(hash);
 
         let mut stream_end = [0u8; 1];
         r.read_exact(&mut stream_end)?;
@@ -116,3 +621,200 @@ impl Footer {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Chunk, Footer, HashAlgorithm, Header, Metadata, Owner, Sequence, METADATA_TIMES,
+        METADATA_VERSION,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_hash_algorithms() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+
+            // hash does not depend on how data is split
+            let mut hasher = algorithm.hasher();
+            hasher.update(b"hello ");
+            hasher.update(b"world");
+            let mut other = algorithm.hasher();
+            other.update(b"hello world");
+            let hash = hasher.finish();
+            assert_eq!(hash, other.finish());
+            assert_eq!(hash.len(), algorithm.size());
+        }
+
+        assert_eq!("SHA256".parse(), Ok(HashAlgorithm::Sha256));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(b"abc");
+        assert_eq!(
+            crate::audit::to_hex(&hasher.finish()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_header_footer() {
+        let header = Header {
+            file_name: "file.bin".to_string(),
+            mode: 0o100644,
+            file_length: 12,
+            hash: HashAlgorithm::Blake3,
+            metadata: Metadata::default(),
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: None,
+        };
+        let footer = Footer {
+            hash: vec![1; 32],
+            stream_end: true,
+        };
+
+        let mut data = vec![];
+        if header.serialize_to(&mut data).is_err() || footer.serialize_to(&mut data).is_err() {
+            panic!("serialization failed");
+        }
+
+        let mut r = data.as_slice();
+        let Ok(header) = Header::deserialize_from(&mut r) else {
+            panic!("header deserialization failed");
+        };
+        assert_eq!(header.file_name, "file.bin");
+        assert_eq!(header.mode, 0o100644);
+        assert_eq!(header.file_length, 12);
+        assert_eq!(header.hash, HashAlgorithm::Blake3);
+        assert!(!header.is_dir());
+
+        let Ok(footer) = Footer::deserialize_from(&mut r, header.hash) else {
+            panic!("footer deserialization failed");
+        };
+        assert_eq!(footer.hash, vec![1; 32]);
+        assert!(footer.stream_end);
+        assert!(r.is_empty());
+
+        let dir = Header {
+            file_name: "dir/sub".to_string(),
+            mode: 0o040755,
+            file_length: 0,
+            hash: HashAlgorithm::None,
+            metadata: Metadata::default(),
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: None,
+        };
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            times: Some((
+                UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+                UNIX_EPOCH - Duration::new(10, 1),
+            )),
+            owner: Some(Owner {
+                uid: 1000,
+                gid: 100,
+                user: "alice".to_string(),
+                group: String::new(),
+            }),
+            xattrs: vec![
+                ("user.classification".to_string(), b"restricted".to_vec()),
+                ("security.selinux".to_string(), vec![]),
+            ],
+        };
+
+        let mut header = Header {
+            file_name: "file.bin".to_string(),
+            mode: 0o100644,
+            file_length: 5,
+            hash: HashAlgorithm::None,
+            metadata: metadata.clone(),
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: None,
+        };
+        let read_metadata = |data: &[u8]| {
+            let mut read = Header {
+                file_name: String::new(),
+                mode: 0,
+                file_length: 0,
+                hash: HashAlgorithm::None,
+                metadata: Metadata::default(),
+                chunk: None,
+                content_id: None,
+                manifest: None,
+                sequence: None,
+            };
+            let mut r = data;
+            match read.deserialize_metadata(&mut r) {
+                Ok(()) => (read, r.to_vec()),
+                Err(e) => panic!("deserialization failed: {e}"),
+            }
+        };
+
+        let mut data = vec![];
+        if header.serialize_metadata(&mut data).is_err() {
+            panic!("serialization failed");
+        }
+        let (read, _) = read_metadata(&data);
+        assert_eq!(read.metadata, metadata);
+        assert_eq!(read.chunk, None);
+        assert_eq!(read.content_id, None);
+
+        let chunk = Chunk {
+            file_id: 0x0123_4567_89ab_cdef,
+            index: 2,
+            count: 3,
+            chunk_size: 10,
+            file_length: 25,
+        };
+        assert!(chunk.is_valid(5));
+        assert!(!chunk.is_valid(10));
+        assert_eq!(chunk.offset(), 20);
+        header.chunk = Some(chunk);
+        header.content_id = Some([7; 32]);
+        header.manifest = Some(vec![1, 2, 3]);
+        header.sequence = Some(Sequence {
+            sender: "host".to_string(),
+            seq: 42,
+        });
+        let mut data = vec![];
+        if header.serialize_metadata(&mut data).is_err() {
+            panic!("serialization failed");
+        }
+        let (read, _) = read_metadata(&data);
+        assert_eq!(read.metadata, metadata);
+        assert_eq!(read.chunk, Some(chunk));
+        assert_eq!(read.content_id, Some([7; 32]));
+        assert_eq!(read.manifest, Some(vec![1, 2, 3]));
+        assert_eq!(read.sequence, header.sequence);
+
+        // version 1, without chunk nor content identifier
+        let mut data = vec![1];
+        let fields = [&[METADATA_TIMES][..], &[0; 12], &[0; 12], &[0; 4]].concat();
+        data.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        data.extend_from_slice(&fields);
+        let (read, _) = read_metadata(&data);
+        assert_eq!(read.metadata.times, Some((UNIX_EPOCH, UNIX_EPOCH)));
+        assert_eq!(read.chunk, None);
+        assert_eq!(read.content_id, None);
+
+        // fields of a later version are ignored
+        let mut data = vec![METADATA_VERSION + 1];
+        let fields = [&[0u8, 0, 0, 0, 0][..], b"future field"].concat();
+        data.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        data.extend_from_slice(&fields);
+        data.push(42);
+        let (read, rest) = read_metadata(&data);
+        assert_eq!(read.metadata, Metadata::default());
+        assert_eq!(rest, [42]);
+    }
+}
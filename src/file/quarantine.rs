@@ -0,0 +1,201 @@
+//! Quarantine of received files failing size, hash or policy checks
+//!
+//! Each file is moved to the quarantine directory with a sidecar `<name>.json` telling why.
+
+use crate::audit::to_hex;
+use crate::file::{self, protocol};
+use chrono::{SecondsFormat, Utc};
+use metrics::counter;
+use serde::Serialize;
+use std::{fs, io, path};
+
+/// Why a file is quarantined
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// connection closed before the end of the file
+    InvalidSize,
+    /// content does not match the hash computed by the sender
+    InvalidHash,
+    /// hash algorithm is not the one required by the receiver
+    UnexpectedHashAlgorithm,
+    /// file name already used, with reject policy
+    Conflict,
+}
+
+impl Reason {
+    /// reason of errors leading to quarantine, other errors delete the file
+    pub fn of(error: &file::Error) -> Option<Self> {
+        match error {
+            file::Error::Diode(protocol::Error::InvalidFileSize(..)) => Some(Self::InvalidSize),
+            file::Error::Diode(protocol::Error::InvalidHash(..)) => Some(Self::InvalidHash),
+            file::Error::Diode(protocol::Error::UnexpectedHashAlgorithm(..)) => {
+                Some(Self::UnexpectedHashAlgorithm)
+            }
+            file::Error::Rejected(_) => Some(Self::Conflict),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::InvalidSize => "invalid_size",
+            Self::InvalidHash => "invalid_hash",
+            Self::UnexpectedHashAlgorithm => "unexpected_hash_algorithm",
+            Self::Conflict => "conflict",
+        }
+    }
+}
+
+/// Content of the sidecar file
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// name sent by diode-send-file, relative path included
+    file_name: &'a str,
+    reason: Reason,
+    error: String,
+    /// number of the TCP connection from diode-receive, since diode-receive-file started
+    session: u64,
+    timestamp: String,
+    expected_size: u64,
+    received_size: u64,
+    hash_algorithm: String,
+    /// hash sent by diode-send-file, unknown if the file is incomplete
+    expected_hash: Option<String>,
+    /// hash of received content, unknown if the file is incomplete
+    actual_hash: Option<String>,
+}
+
+/// move `part_path` to `quarantine_dir` with its sidecar, returns the new path. `hash` is the hash
+/// of content, when completely received.
+pub fn quarantine(
+    quarantine_dir: &path::Path,
+    part_path: &path::Path,
+    header: &protocol::Header,
+    error: &file::Error,
+    reason: Reason,
+    hash: Option<&[u8]>,
+    session: u64,
+) -> io::Result<path::PathBuf> {
+    let now = Utc::now();
+
+    let (expected_hash, actual_hash) = match (error, hash) {
+        (file::Error::Diode(protocol::Error::InvalidHash(computed, expected)), _) => {
+            (Some(expected.clone()), Some(computed.clone()))
+        }
+        // footer was checked
+        (_, Some(hash)) => (Some(to_hex(hash)), Some(to_hex(hash))),
+        (_, None) => (None, None),
+    };
+
+    let record = Record {
+        file_name: &header.file_name,
+        reason,
+        error: error.to_string(),
+        session,
+        timestamp: now.to_rfc3339_opts(SecondsFormat::Millis, true),
+        expected_size: header.file_length,
+        received_size: fs::metadata(part_path)?.len(),
+        hash_algorithm: header.hash.to_string(),
+        expected_hash,
+        actual_hash,
+    };
+
+    // flat name, which sorts by time
+    let name = format!(
+        "{}_{session}_{}",
+        now.format("%Y%m%dT%H%M%S%.3fZ"),
+        header.file_name.replace('/', "_")
+    );
+    let mut quarantined = quarantine_dir.join(&name);
+    let mut n = 1;
+    while fs::symlink_metadata(&quarantined).is_ok() {
+        quarantined = quarantine_dir.join(format!("{name}.{n}"));
+        n += 1;
+    }
+
+    match fs::rename(part_path, &quarantined) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(part_path, &quarantined)?;
+            fs::remove_file(part_path)?;
+        }
+        Err(e) => return Err(e),
+    }
+
+    let mut sidecar = quarantined.clone().into_os_string();
+    sidecar.push(".json");
+    let json = serde_json::to_string_pretty(&record).map_err(io::Error::other)?;
+    fs::write(&sidecar, json + "\n")?;
+
+    counter!("rx_file_quarantined", "reason" => reason.label()).increment(1);
+
+    Ok(quarantined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quarantine, Reason};
+    use crate::file::{self, protocol};
+    use crate::test::TempDir;
+
+    #[test]
+    fn test_quarantine() {
+        let dir = TempDir::new("quarantine");
+        let part_path = dir.join(".file.lidi-part");
+
+        let header = protocol::Header {
+            file_name: "dir/file".to_string(),
+            mode: 0o100644,
+            file_length: 5,
+            hash: protocol::HashAlgorithm::Sha256,
+            metadata: protocol::Metadata::default(),
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: None,
+        };
+        let error = file::Error::Diode(protocol::Error::InvalidHash(
+            "ab".to_string(),
+            "cd".to_string(),
+        ));
+        assert_eq!(Reason::of(&error), Some(Reason::InvalidHash));
+        assert_eq!(
+            Reason::of(&file::Error::Other("disk full".to_string())),
+            None
+        );
+
+        let mut paths = vec![];
+        for _ in 0..2 {
+            std::fs::write(&part_path, "hello").unwrap();
+            let quarantined = quarantine(
+                &dir,
+                &part_path,
+                &header,
+                &error,
+                Reason::InvalidHash,
+                Some(&[0xab]),
+                3,
+            )
+            .unwrap();
+            assert!(!part_path.exists());
+            assert_eq!(std::fs::read_to_string(&quarantined).unwrap(), "hello");
+            paths.push(quarantined);
+        }
+        assert_ne!(paths[0], paths[1]);
+
+        let name = paths[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("_3_dir_file"));
+
+        let sidecar = std::fs::read_to_string(format!("{}.json", paths[0].display())).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+        assert_eq!(record["file_name"], "dir/file");
+        assert_eq!(record["reason"], "invalid_hash");
+        assert_eq!(record["session"], 3);
+        assert_eq!(record["expected_size"], 5);
+        assert_eq!(record["received_size"], 5);
+        assert_eq!(record["hash_algorithm"], "sha256");
+        assert_eq!(record["expected_hash"], "cd");
+        assert_eq!(record["actual_hash"], "ab");
+    }
+}
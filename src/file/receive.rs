@@ -1,14 +1,20 @@
-use fasthash::HasherExt;
-
-use crate::file::{self, protocol};
+use crate::audit::to_hex;
+use crate::file::{self, chunks, manifest, protocol, quarantine, sequence};
+use chrono::Utc;
+use metrics::counter;
+use nix::unistd::{geteuid, Group, User};
 use std::{
-    fs,
-    hash::Hash,
-    io::{Read, Write},
+    collections::HashMap,
+    ffi, fs, io,
+    io::{Read, Seek, Write},
     net::{self, TcpStream},
-    os::unix::fs::PermissionsExt,
+    os::unix::{
+        self,
+        fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
     path,
 };
+use xattr::FileExt;
 
 pub fn receive_files(config: &file::Config, output_dir: &path::Path) -> Result<(), file::Error> {
     if !output_dir.is_dir() {
@@ -17,12 +23,115 @@ pub fn receive_files(config: &file::Config, output_dir: &path::Path) -> Result<(
         ));
     }
 
-    receive_tcp_loop(config, output_dir)?;
+    if let Some(quarantine_dir) = &config.quarantine_dir {
+        if !quarantine_dir.is_dir() {
+            return Err(file::Error::Other(
+                "quarantine directory is not a directory".to_string(),
+            ));
+        }
+    }
+
+    let manifest_key = match &config.manifest_key {
+        Some(path) => Some(manifest::load_key(path)?),
+        None => None,
+    };
+
+    receive_tcp_loop(config, output_dir, manifest_key)?;
 
     Ok(())
 }
 
-fn receive_tcp_loop(config: &file::Config, output_dir: &path::Path) -> Result<(), file::Error> {
+/// suffix of files being received, hidden by a leading `.`
+const PART_SUFFIX: &str = ".lidi-part";
+
+/// subdirectory of previous versions of files, with the version policy
+const VERSIONS_DIR: &str = ".lidi-versions";
+
+/// maximum number of numeric suffixes tried to find a free name
+const MAX_RENAME: usize = 10000;
+
+/// device, inode, size and change time of a file, which change whenever its content does
+type FileVersion = (u64, u64, u64, i64, i64);
+
+/// Content identifiers of files of output directory, computed while files are received, or when
+/// a copy of a file received before a restart is received
+#[derive(Default)]
+struct ContentIds(HashMap<path::PathBuf, (FileVersion, protocol::ContentId)>);
+
+fn file_version(metadata: &fs::Metadata) -> FileVersion {
+    (
+        metadata.dev(),
+        metadata.ino(),
+        metadata.len(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+    )
+}
+
+impl ContentIds {
+    /// record content identifier of a delivered file
+    fn insert(&mut self, file_path: &path::Path, content_id: protocol::ContentId) {
+        match fs::symlink_metadata(file_path) {
+            Ok(metadata) => {
+                self.0.insert(
+                    file_path.to_path_buf(),
+                    (file_version(&metadata), content_id),
+                );
+            }
+            Err(e) => log::warn!("Cannot read \"{}\": {e}", file_path.display()),
+        }
+    }
+
+    /// `file_path` is a regular file of `file_length` bytes, with content `content_id`
+    fn holds(
+        &mut self,
+        file_path: &path::Path,
+        file_length: u64,
+        content_id: &protocol::ContentId,
+    ) -> bool {
+        let metadata = match fs::symlink_metadata(file_path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == file_length => metadata,
+            _ => return false,
+        };
+        let version = file_version(&metadata);
+
+        let known = match self.0.get(file_path) {
+            Some((known_version, known)) if *known_version == version => *known,
+            // not received since diode-receive-file started
+            _ => {
+                let computed = fs::OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NOFOLLOW)
+                    .open(file_path)
+                    .and_then(|mut file| protocol::content_id(&mut file));
+                match computed {
+                    Ok(computed) => {
+                        self.0.insert(file_path.to_path_buf(), (version, computed));
+                        computed
+                    }
+                    Err(e) => {
+                        log::warn!("Cannot read \"{}\": {e}", file_path.display());
+                        return false;
+                    }
+                }
+            }
+        };
+        known == *content_id
+    }
+}
+
+/// State of receiver kept across sessions
+struct State {
+    content_ids: ContentIds,
+    manifests: manifest::Checker,
+    sequences: sequence::Tracker,
+}
+
+fn receive_tcp_loop(
+    config: &file::Config,
+    output_dir: &path::Path,
+    manifest_key: Option<Vec<u8>>,
+) -> Result<(), file::Error> {
     let (tx, rx) = crossbeam_channel::bounded::<TcpStream>(100);
 
     let server = net::TcpListener::bind(config.diode)?;
@@ -49,6 +158,15 @@ fn receive_tcp_loop(config: &file::Config, output_dir: &path::Path) -> Result<()
         log::error!("Can't start new thread: {e}");
     }
 
+    // number of TCP connections from diode-receive
+    let mut session = 0;
+
+    let mut state = State {
+        content_ids: ContentIds::default(),
+        manifests: manifest::Checker::new(manifest_key),
+        sequences: sequence::Tracker::open(output_dir)?,
+    };
+
     loop {
         let mut client = match rx.recv() {
             Ok(client) => client,
@@ -58,6 +176,11 @@ fn receive_tcp_loop(config: &file::Config, output_dir: &path::Path) -> Result<()
             }
         };
 
+        session += 1;
+
+        // metadata of received directories, restored once their content is received
+        let mut directories = vec![];
+
         // try to read files until diode-receive disconnects
         loop {
             /*
@@ -70,49 +193,281 @@ fn receive_tcp_loop(config: &file::Config, output_dir: &path::Path) -> Result<()
                     // connection closed, close "diode" and wait for a new connection
                     break;
                 }
-                Ok(_) => match receive_file(config, &mut client, output_dir) {
-                    Ok((filename, total, _stream_end)) => {
-                        log::info!("{filename} received, {total} bytes");
-                    }
-                    Err(e) => {
-                        log::error!("failed to receive file: {e}");
-                        break;
+                Ok(_) => {
+                    match receive_file(
+                        config,
+                        &mut client,
+                        output_dir,
+                        session,
+                        &mut directories,
+                        &mut state,
+                    ) {
+                        Ok((filename, total, _stream_end)) => {
+                            log::info!("{filename} received, {total} bytes");
+                        }
+                        Err(e @ file::Error::AlreadyReceived(_)) => {
+                            log::debug!("file skipped: {e}");
+                        }
+                        // file skipped, next files of the session can be received
+                        Err(
+                            e @ (file::Error::Rejected(_)
+                            | file::Error::Diode(protocol::Error::UnexpectedHashAlgorithm(..))),
+                        ) => {
+                            log::error!("file rejected: {e}");
+                        }
+                        Err(e) => {
+                            log::error!("failed to receive file: {e}");
+                            break;
+                        }
                     }
-                },
+                }
                 Err(e) => {
                     log::error!("failed to read data from socket: {e}");
                     break;
                 }
             };
         }
+
+        restore_directories(config, directories);
     }
 }
 
-fn finish_file(
+/// permission bits of `mode` to restore: setuid and setgid bits are cleared, unless
+/// configuration keeps them
+fn permissions(config: &file::Config, mode: u32) -> u32 {
+    if config.keep_setid {
+        mode & 0o7777
+    } else {
+        mode & 0o1777
+    }
+}
+
+/// restore extended attributes, owner, mode and times, in this order: changing owner clears
+/// setuid and setgid bits, so mode is set after it, and times must be set last. Errors are logged.
+fn restore_metadata(
+    config: &file::Config,
+    file: &fs::File,
+    file_path: &path::Path,
+    mode: u32,
+    metadata: &protocol::Metadata,
+) {
+    for (name, value) in &metadata.xattrs {
+        if !config.xattr_selected(name) {
+            log::debug!(
+                "ignoring extended attribute {name} of \"{}\"",
+                file_path.display()
+            );
+            continue;
+        }
+        if let Err(e) = file.set_xattr(name, value) {
+            log::warn!(
+                "Cannot set extended attribute {name} of \"{}\": {e}",
+                file_path.display()
+            );
+        }
+    }
+
+    if let Some(owner) = &metadata.owner {
+        restore_owner(config, file, file_path, owner);
+    }
+
+    let mode = permissions(config, mode);
+    log::debug!("setting mode to {mode:o}");
+    if let Err(e) = file.set_permissions(fs::Permissions::from_mode(mode)) {
+        log::warn!("Cannot set mode of \"{}\": {e}", file_path.display());
+    }
+
+    if let Some((mtime, atime)) = metadata.times {
+        let times = fs::FileTimes::new().set_modified(mtime).set_accessed(atime);
+        if let Err(e) = file.set_times(times) {
+            log::warn!("Cannot set times of \"{}\": {e}", file_path.display());
+        }
+    }
+}
+
+/// change owner when running as root, with ids or names depending on configuration
+fn restore_owner(
+    config: &file::Config,
+    file: &fs::File,
+    file_path: &path::Path,
+    owner: &protocol::Owner,
+) {
+    // only root can give files away
+    if !geteuid().is_root() {
+        log::debug!(
+            "not running as root, owner of \"{}\" is unchanged",
+            file_path.display()
+        );
+        return;
+    }
+
+    let (uid, gid) = match config.owner {
+        file::OwnerMapping::None => return,
+        file::OwnerMapping::Id => (Some(owner.uid), Some(owner.gid)),
+        file::OwnerMapping::Name => {
+            let uid = User::from_name(&owner.user)
+                .ok()
+                .flatten()
+                .map(|user| user.uid.as_raw());
+            if uid.is_none() {
+                log::warn!(
+                    "unknown user \"{}\", owner of \"{}\" is unchanged",
+                    owner.user,
+                    file_path.display()
+                );
+            }
+            let gid = Group::from_name(&owner.group)
+                .ok()
+                .flatten()
+                .map(|group| group.gid.as_raw());
+            if gid.is_none() {
+                log::warn!(
+                    "unknown group \"{}\", group of \"{}\" is unchanged",
+                    owner.group,
+                    file_path.display()
+                );
+            }
+            (uid, gid)
+        }
+    };
+
+    if let Err(e) = unix::fs::fchown(file, uid, gid) {
+        log::warn!("Cannot change owner of \"{}\": {e}", file_path.display());
+    }
+}
+
+/// restore metadata of directories, deepest first since restoring times of a directory must
+/// happen after its content is created
+fn restore_directories(
     config: &file::Config,
+    directories: Vec<(path::PathBuf, u32, protocol::Metadata)>,
+) {
+    for (dir_path, mode, metadata) in directories.into_iter().rev() {
+        match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+            .open(&dir_path)
+        {
+            Ok(dir) => restore_metadata(config, &dir, &dir_path, mode, &metadata),
+            Err(e) => log::warn!("Cannot open directory \"{}\": {e}", dir_path.display()),
+        }
+    }
+}
+
+/// relative path of a received file or directory. Absolute paths, `..` components and empty
+/// paths are rejected, `.` and empty components are ignored.
+pub(super) fn relative_path(file_name: &str) -> Result<path::PathBuf, file::Error> {
+    let invalid = || file::Error::Other(format!("invalid file name \"{file_name}\""));
+
+    if file_name.starts_with('/') || file_name.contains('\0') {
+        return Err(invalid());
+    }
+
+    let mut relative = path::PathBuf::new();
+    for component in file_name.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return Err(invalid()),
+            component => relative.push(component),
+        }
+    }
+
+    if relative.as_os_str().is_empty() {
+        return Err(invalid());
+    }
+    Ok(relative)
+}
+
+/// create missing directories of `relative` under `output_dir`, one component at a time.
+/// Existing components must be directories: symbolic links are never followed.
+fn create_dirs(
+    output_dir: &path::Path,
+    relative: &path::Path,
+) -> Result<path::PathBuf, file::Error> {
+    let mut dir_path = output_dir.to_path_buf();
+    for component in relative.components() {
+        dir_path.push(component);
+        match fs::symlink_metadata(&dir_path) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => {
+                return Err(file::Error::Other(format!(
+                    "\"{}\" exists and is not a directory",
+                    dir_path.display()
+                )))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir_path)?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(dir_path)
+}
+
+/// skip file content and footer, to stay in sync with the stream. Returns end of stream flag of
+/// footer.
+fn skip_file(
     diode: &mut net::TcpStream,
+    header: &file::protocol::Header,
+) -> Result<bool, file::Error> {
+    let skipped = io::copy(&mut diode.take(header.file_length), &mut io::sink())?;
+    if skipped != header.file_length {
+        return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
+            header.file_length as usize,
+            skipped as usize,
+        )));
+    }
+    let footer = file::protocol::Footer::deserialize_from(diode, header.hash)?;
+    Ok(footer.stream_end)
+}
+
+fn reject(file_path: &path::Path) -> file::Error {
+    counter!("rx_file_conflicts", "outcome" => "rejected").increment(1);
+    file::Error::Rejected(format!("file \"{}\" already exists", file_path.display()))
+}
+
+/// deliver checked file content once on disk, with its metadata, and record its content
+/// identifier if computed
+fn finish_file(
+    config: &file::Config,
     mut file: fs::File,
-    header: file::protocol::Header,
-    hasher: fasthash::Murmur3HasherExt,
-) -> Result<(String, usize, bool), file::Error> {
+    file_path: &path::Path,
+    part_path: &path::Path,
+    header: &file::protocol::Header,
+    content_id: Option<protocol::ContentId>,
+    content_ids: &mut ContentIds,
+) -> Result<(), file::Error> {
     file.flush()?;
+    file.sync_all()?;
+    restore_metadata(config, &file, file_path, header.mode, &header.metadata);
+
+    let delivered = deliver(config.on_conflict, part_path, file_path)?;
+    if let Some(content_id) = content_id {
+        content_ids.insert(&delivered, content_id);
+    }
+
+    Ok(())
+}
 
+/// check footer against `hash`, computed from received content
+fn read_footer(
+    diode: &mut net::TcpStream,
+    header: &file::protocol::Header,
+    hash: &[u8],
+) -> Result<(String, usize, bool), file::Error> {
     log::trace!("parsing footer");
-    let footer = file::protocol::Footer::deserialize_from(diode)?;
-
-    if config.hash {
-        let hash = hasher.finish_ext();
-        log::debug!("expected hash = {}", footer.hash);
-        log::debug!("computed hash = {hash}");
-        if footer.hash != hash {
-            return Err(file::Error::Diode(protocol::Error::InvalidHash(
-                hash,
-                footer.hash,
-            )));
-        }
+    let footer = file::protocol::Footer::deserialize_from(diode, header.hash)?;
+
+    // hash is checked whenever sender provides one
+    if footer.hash != hash {
+        let expected = to_hex(&footer.hash);
+        let computed = to_hex(hash);
+        log::debug!("expected {} hash = {expected}", header.hash);
+        log::debug!("computed {} hash = {computed}", header.hash);
+        return Err(file::Error::Diode(protocol::Error::InvalidHash(
+            computed, expected,
+        )));
     }
     Ok((
-        header.file_name,
+        header.file_name.clone(),
         header.file_length as usize,
         footer.stream_end,
     ))
@@ -122,6 +477,9 @@ fn receive_file(
     config: &file::Config,
     diode: &mut net::TcpStream,
     output_dir: &path::Path,
+    session: u64,
+    directories: &mut Vec<(path::PathBuf, u32, protocol::Metadata)>,
+    state: &mut State,
 ) -> Result<(String, usize, bool), file::Error> {
     log::trace!("parsing header");
     let header = file::protocol::Header::deserialize_from(diode)?;
@@ -129,37 +487,373 @@ fn receive_file(
     log::debug!("receiving file \"{}\"", header.file_name);
     log::debug!("file size = {}", header.file_length);
 
-    let file_path = path::PathBuf::from(header.file_name.clone());
-    let file_name = file_path
-        .file_name()
-        .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?;
-    let file_path = output_dir.join(path::PathBuf::from(file_name));
+    if let Some(sequence) = &header.sequence {
+        state
+            .sequences
+            .record(&sequence.sender, sequence.seq, &header.file_name);
+    }
+
+    // file failing a policy check is skipped, or received to be quarantined
+    let mut rejected = None;
+    if config.hash != protocol::HashAlgorithm::None && header.hash != config.hash {
+        rejected = Some(file::Error::Diode(
+            protocol::Error::UnexpectedHashAlgorithm(config.hash, header.hash),
+        ));
+    }
+
+    if let Some(signature) = &header.manifest {
+        if let Some(e) = rejected {
+            skip_file(diode, &header)?;
+            return Err(e);
+        }
+        return receive_manifest(diode, output_dir, &header, signature, state);
+    }
+
+    let relative = relative_path(&header.file_name)?;
+
+    if header.is_dir() {
+        if let Some(e) = rejected {
+            skip_file(diode, &header)?;
+            return Err(e);
+        }
+
+        if header.file_length != 0 {
+            return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
+                0,
+                header.file_length as usize,
+            )));
+        }
+
+        let dir_path = create_dirs(output_dir, &relative)?;
+        log::debug!("created directory \"{}\"", dir_path.display());
+
+        // owner keeps full access, to create the files of the directory
+        let mode = permissions(config, header.mode) | 0o700;
+        log::debug!("setting mode to {mode:o}");
+        fs::set_permissions(&dir_path, fs::Permissions::from_mode(mode))?;
+
+        let received = read_footer(diode, &header, &header.hash.hasher().finish())?;
+
+        directories.push((dir_path, header.mode, header.metadata));
+        return Ok(received);
+    }
+
+    let parent = match relative.parent() {
+        Some(parent) => create_dirs(output_dir, parent)?,
+        None => output_dir.to_path_buf(),
+    };
+    let file_path = parent.join(relative.file_name().unwrap_or_default());
 
     log::debug!("storing at \"{}\"", file_path.display());
 
-    if file_path.exists() {
-        return Err(file::Error::Other(format!(
-            "file \"{}\" already exists",
-            file_path.display()
-        )));
+    // copy sent again by a carousel, whatever the conflict policy
+    if let (None, Some(content_id)) = (&rejected, &header.content_id) {
+        let file_length = header
+            .chunk
+            .map_or(header.file_length, |chunk| chunk.file_length);
+        if state.content_ids.holds(&file_path, file_length, content_id) {
+            skip_file(diode, &header)?;
+            counter!("rx_file_duplicates").increment(1);
+            return Err(file::Error::AlreadyReceived(header.file_name));
+        }
+    }
+
+    if let Some(chunk) = header.chunk {
+        if let Some(e) = rejected {
+            skip_file(diode, &header)?;
+            return Err(e);
+        }
+        return receive_chunk(
+            config,
+            diode,
+            output_dir,
+            &file_path,
+            &header,
+            &chunk,
+            session,
+            &mut state.content_ids,
+        );
+    }
+
+    if rejected.is_none() && fs::symlink_metadata(&file_path).is_ok() {
+        if config.on_conflict == file::ConflictPolicy::Reject {
+            rejected = Some(reject(&file_path));
+        } else {
+            log::debug!(
+                "file \"{}\" already exists, policy is {}",
+                file_path.display(),
+                config.on_conflict
+            );
+        }
     }
 
+    if config.quarantine_dir.is_none() {
+        if let Some(e) = rejected {
+            skip_file(diode, &header)?;
+            return Err(e);
+        }
+    }
+
+    let mut part_name = ffi::OsString::from(".");
+    part_name.push(relative.file_name().unwrap_or_default());
+    part_name.push(PART_SUFFIX);
+    let part_path = parent.join(part_name);
+
+    // left by an interrupted transfer
+    if let Err(e) = fs::remove_file(&part_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    // mode of the sender is only restored once the file is delivered, not when it is quarantined
     let mut file = fs::OpenOptions::new()
         .read(false)
         .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_path)?;
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&part_path)?;
+
+    // hash of content, once completely received
+    let mut hash = None;
+    let mut content_id = None;
+    let received = receive_content(config, diode, &mut file, &header)
+        .and_then(|(hasher, id)| {
+            content_id = id;
+            read_footer(diode, &header, hash.insert(hasher.finish()))
+        })
+        .and_then(|received| match rejected {
+            Some(e) => Err(e),
+            None => Ok(received),
+        })
+        .and_then(|received| {
+            finish_file(
+                config,
+                file,
+                &file_path,
+                &part_path,
+                &header,
+                content_id,
+                &mut state.content_ids,
+            )?;
+            Ok(received)
+        });
+
+    if received.is_ok() {
+        state.manifests.delivered(
+            &header.file_name,
+            header.file_length,
+            hash.as_deref().unwrap_or_default(),
+        );
+    }
+
+    if let Err(e) = &received {
+        match (&config.quarantine_dir, quarantine::Reason::of(e)) {
+            (Some(quarantine_dir), Some(reason)) => {
+                match quarantine::quarantine(
+                    quarantine_dir,
+                    &part_path,
+                    &header,
+                    e,
+                    reason,
+                    hash.as_deref(),
+                    session,
+                ) {
+                    Ok(quarantined) => {
+                        log::warn!(
+                            "\"{}\" moved to quarantine as \"{}\"",
+                            header.file_name,
+                            quarantined.display()
+                        )
+                    }
+                    Err(e) => log::error!("Cannot quarantine \"{}\": {e}", part_path.display()),
+                }
+            }
+            _ => {
+                log::debug!("deleting \"{}\"", part_path.display());
+                if let Err(e) = fs::remove_file(&part_path) {
+                    log::warn!("Cannot delete \"{}\": {e}", part_path.display());
+                }
+            }
+        }
+    }
+
+    received
+}
+
+/// check a manifest of sent files against stored files
+fn receive_manifest(
+    diode: &mut net::TcpStream,
+    output_dir: &path::Path,
+    header: &file::protocol::Header,
+    signature: &[u8],
+    state: &mut State,
+) -> Result<(String, usize, bool), file::Error> {
+    if header.file_length > manifest::MAX_MANIFEST_LENGTH {
+        skip_file(diode, header)?;
+        return Err(file::Error::Rejected(format!(
+            "manifest too large: {} bytes",
+            header.file_length
+        )));
+    }
+
+    let mut content = vec![];
+    diode.take(header.file_length).read_to_end(&mut content)?;
+    if content.len() as u64 != header.file_length {
+        return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
+            header.file_length as usize,
+            content.len(),
+        )));
+    }
+
+    let mut hasher = header.hash.hasher();
+    hasher.update(&content);
+    let received = read_footer(diode, header, &hasher.finish())?;
+
+    state
+        .manifests
+        .check(output_dir, &content, signature)
+        .map_err(file::Error::Rejected)?;
+
+    Ok(received)
+}
+
+/// write a chunk of a large file at its offset. The file is delivered once all its chunks are
+/// received, in this session or previous ones.
+#[allow(clippy::too_many_arguments)]
+fn receive_chunk(
+    config: &file::Config,
+    diode: &mut net::TcpStream,
+    output_dir: &path::Path,
+    file_path: &path::Path,
+    header: &file::protocol::Header,
+    chunk: &protocol::Chunk,
+    session: u64,
+    content_ids: &mut ContentIds,
+) -> Result<(String, usize, bool), file::Error> {
+    if !chunk.is_valid(header.file_length) {
+        skip_file(diode, header)?;
+        return Err(file::Error::Rejected(format!(
+            "invalid chunk {} of \"{}\"",
+            chunk.index, header.file_name
+        )));
+    }
+
+    let mut partial = chunks::PartialFile::open(output_dir, &header.file_name, chunk)?;
+
+    if partial.is_received(chunk.index) {
+        let stream_end = skip_file(diode, header)?;
+        log::info!(
+            "chunk {} of \"{}\" already received, skipped",
+            chunk.index,
+            header.file_name
+        );
+        return Ok((header.file_name.clone(), 0, stream_end));
+    }
+
+    let mut data = fs::OpenOptions::new()
+        .write(true)
+        .open(partial.data_path())?;
+    data.seek(io::SeekFrom::Start(chunk.offset()))?;
+
+    let (hasher, _) = receive_content(config, diode, &mut data, header)?;
+    let received = read_footer(diode, header, &hasher.finish())?;
+    data.sync_data()?;
+    partial.set_received(chunk.index)?;
+
+    log::info!(
+        "chunk {} of \"{}\" received, {}/{} chunks",
+        chunk.index,
+        header.file_name,
+        partial.received(),
+        partial.count()
+    );
+
+    let missing = partial.missing();
+    if !missing.is_empty() {
+        log::info!(
+            "missing chunks of \"{}\": {}",
+            header.file_name,
+            chunks::format_ranges(&missing)
+        );
+        return Ok(received);
+    }
+
+    restore_metadata(config, &data, file_path, header.mode, &header.metadata);
+
+    let data_path = partial.data_path();
+    match deliver(config.on_conflict, &data_path, file_path) {
+        Ok(delivered) => {
+            log::info!(
+                "\"{}\" reassembled from {} chunks",
+                delivered.display(),
+                chunk.count
+            );
+            // chunks are written out of order: content is read once, while still in cache
+            if header.content_id.is_some() {
+                match fs::File::open(&delivered)
+                    .and_then(|mut file| protocol::content_id(&mut file))
+                {
+                    Ok(content_id) => content_ids.insert(&delivered, content_id),
+                    Err(e) => log::warn!("Cannot read \"{}\": {e}", delivered.display()),
+                }
+            }
+        }
+        Err(e) => {
+            if let (Some(quarantine_dir), Some(reason)) =
+                (&config.quarantine_dir, quarantine::Reason::of(&e))
+            {
+                // whole file is quarantined
+                let mut file_header = header.clone();
+                file_header.file_length = chunk.file_length;
+                match quarantine::quarantine(
+                    quarantine_dir,
+                    &data_path,
+                    &file_header,
+                    &e,
+                    reason,
+                    None,
+                    session,
+                ) {
+                    Ok(quarantined) => log::warn!(
+                        "\"{}\" moved to quarantine as \"{}\"",
+                        header.file_name,
+                        quarantined.display()
+                    ),
+                    Err(e) => log::error!("Cannot quarantine \"{}\": {e}", data_path.display()),
+                }
+            }
+            if let Err(e) = partial.remove() {
+                log::warn!("Cannot delete chunks of \"{}\": {e}", header.file_name);
+            }
+            return Err(e);
+        }
+    }
+
+    if let Err(e) = partial.remove() {
+        log::warn!("Cannot delete chunks of \"{}\": {e}", header.file_name);
+    }
 
-    log::debug!("setting mode to {}", header.mode);
-    file.set_permissions(fs::Permissions::from_mode(header.mode))?;
+    Ok(received)
+}
 
+/// write file content, returns its hash, and its content identifier when the sender sends one
+/// for a whole file: the file may be sent again
+fn receive_content(
+    config: &file::Config,
+    diode: &mut net::TcpStream,
+    file: &mut fs::File,
+    header: &file::protocol::Header,
+) -> Result<(protocol::FileHasher, Option<protocol::ContentId>), file::Error> {
     let mut buffer = vec![0; config.buffer_size];
     let mut remaining = header.file_length as usize;
 
-    let mut hasher = fasthash::Murmur3HasherExt::default();
+    let mut hasher = header.hash.hasher();
+    let mut content_id = (header.content_id.is_some() && header.chunk.is_none())
+        .then(protocol::ContentIdHasher::default);
 
-    loop {
+    while remaining != 0 {
         let end = if remaining >= (config.buffer_size) {
             config.buffer_size
         } else {
@@ -170,30 +864,549 @@ fn receive_file(
 
         match diode.read(&mut buffer[..end])? {
             0 => {
-                if remaining != 0 {
-                    let received = header.file_length as usize - remaining;
-                    log::debug!("expected file size = {}", header.file_length);
-                    log::debug!("received file size = {received}");
-                    return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
-                        header.file_length as usize,
-                        received,
-                    )));
-                }
-
-                return finish_file(config, diode, file, header, hasher);
+                let received = header.file_length as usize - remaining;
+                log::debug!("expected file size = {}", header.file_length);
+                log::debug!("received file size = {received}");
+                return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
+                    header.file_length as usize,
+                    received,
+                )));
             }
             nread => {
                 remaining -= nread;
 
-                if config.hash {
-                    buffer[..nread].hash(&mut hasher);
+                hasher.update(&buffer[..nread]);
+                if let Some(content_id) = &mut content_id {
+                    content_id.update(&buffer[..nread]);
                 }
                 file.write_all(&buffer[..nread])?;
+            }
+        }
+    }
 
-                if remaining == 0 {
-                    return finish_file(config, diode, file, header, hasher);
-                }
+    Ok((hasher, content_id.map(protocol::ContentIdHasher::finish)))
+}
+
+/// give a complete file its final name, `policy` tells what to do if the name is already used.
+/// Returns the path of the delivered file.
+fn deliver(
+    policy: file::ConflictPolicy,
+    part_path: &path::Path,
+    file_path: &path::Path,
+) -> Result<path::PathBuf, file::Error> {
+    let delivered = match fs::hard_link(part_path, file_path) {
+        Ok(()) => {
+            if let Err(e) = fs::remove_file(part_path) {
+                log::warn!("Cannot delete \"{}\": {e}", part_path.display());
             }
+            file_path.to_path_buf()
         }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            resolve_conflict(policy, part_path, file_path)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // new directory entry survives a crash
+    if let Some(parent) = delivered.parent() {
+        if let Err(e) = fs::File::open(parent).and_then(|dir| dir.sync_all()) {
+            log::warn!("Cannot sync directory \"{}\": {e}", parent.display());
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// `file_path` exists, deliver `part_path` according to `policy`
+fn resolve_conflict(
+    policy: file::ConflictPolicy,
+    part_path: &path::Path,
+    file_path: &path::Path,
+) -> Result<path::PathBuf, file::Error> {
+    let outcome = match policy {
+        file::ConflictPolicy::Reject => return Err(reject(file_path)),
+        file::ConflictPolicy::Overwrite => {
+            fs::rename(part_path, file_path)?;
+            log::info!("file \"{}\" overwritten", file_path.display());
+            "overwritten"
+        }
+        file::ConflictPolicy::Rename | file::ConflictPolicy::RenameTimestamp => {
+            let timestamp = (policy == file::ConflictPolicy::RenameTimestamp)
+                .then(|| Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string());
+            let renamed = link_free_name(part_path, file_path, timestamp.as_deref())?;
+            if let Err(e) = fs::remove_file(part_path) {
+                log::warn!("Cannot delete \"{}\": {e}", part_path.display());
+            }
+            log::info!(
+                "file \"{}\" already exists, received file stored as \"{}\"",
+                file_path.display(),
+                renamed.display()
+            );
+            counter!("rx_file_conflicts", "outcome" => "renamed").increment(1);
+            return Ok(renamed);
+        }
+        file::ConflictPolicy::Version => {
+            let parent = file_path.parent().unwrap_or(path::Path::new("."));
+            let versions = create_dirs(parent, path::Path::new(VERSIONS_DIR))?;
+            let version = link_free_name(
+                file_path,
+                &versions.join(file_path.file_name().unwrap_or_default()),
+                None,
+            )?;
+            // file name always exists, with previous or new content
+            fs::rename(part_path, file_path)?;
+            log::info!(
+                "file \"{}\" replaced, previous version moved to \"{}\"",
+                file_path.display(),
+                version.display()
+            );
+            "versioned"
+        }
+    };
+
+    counter!("rx_file_conflicts", "outcome" => outcome).increment(1);
+    Ok(file_path.to_path_buf())
+}
+
+/// hard link `source` to the first free name made of `file_path` and a suffix: `suffix`, if
+/// any, then numbers. Suffix is inserted before the extension.
+fn link_free_name(
+    source: &path::Path,
+    file_path: &path::Path,
+    suffix: Option<&str>,
+) -> Result<path::PathBuf, file::Error> {
+    let stem = file_path.file_stem().unwrap_or_default();
+    let suffixes = suffix
+        .map(str::to_string)
+        .into_iter()
+        .chain((1..MAX_RENAME).map(|n| match suffix {
+            Some(suffix) => format!("{suffix}-{n}"),
+            None => n.to_string(),
+        }));
+
+    for suffix in suffixes {
+        let mut name = stem.to_os_string();
+        name.push(".");
+        name.push(suffix);
+        if let Some(extension) = file_path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let candidate = file_path.with_file_name(name);
+
+        match fs::hard_link(source, &candidate) {
+            Ok(()) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(file::Error::Other(format!(
+        "no free name for \"{}\"",
+        file_path.display()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_dirs, deliver, finish_file, receive_file, relative_path, ContentIds, State,
+    };
+    use crate::file::{self, manifest, protocol, sequence, ConflictPolicy};
+    use crate::test::TempDir;
+    use nix::sys::socket::{setsockopt, sockopt::RcvBuf};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+
+    fn config(hash: protocol::HashAlgorithm) -> file::Config {
+        file::Config {
+            diode: "127.0.0.1:0".parse().unwrap(),
+            buffer_size: 1024,
+            hash,
+            owner: file::OwnerMapping::None,
+            xattrs: vec![],
+            on_conflict: ConflictPolicy::Reject,
+            quarantine_dir: None,
+            chunk_size: None,
+            chunks: None,
+            content_id: false,
+            manifest_key: None,
+            keep_setid: false,
+        }
+    }
+
+    // send `files` with diode-send-file, the first byte of `altered` in the stream being changed on
+    // the link, and receive them in `output_dir`. Returns the name or error of each entry.
+    fn transfer(
+        send_config: &file::Config,
+        receive_config: &file::Config,
+        files: &[&Path],
+        altered: Option<&[u8]>,
+        output_dir: &Path,
+    ) -> Vec<Result<String, String>> {
+        let diode_send = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut send_config = send_config.clone();
+        send_config.diode = diode_send.local_addr().unwrap();
+        let files: Vec<String> = files
+            .iter()
+            .map(|file| file.to_str().unwrap().to_string())
+            .collect();
+        assert!(crate::file::send::send_files(&send_config, &files).is_ok());
+
+        let mut stream = vec![];
+        let (mut from_send, _) = diode_send.accept().unwrap();
+        from_send.read_to_end(&mut stream).unwrap();
+        if let Some(altered) = altered {
+            let offset = stream
+                .windows(altered.len())
+                .position(|window| window == altered)
+                .unwrap();
+            stream[offset] ^= 0x20;
+        }
+
+        receive_stream(receive_config, stream, output_dir)
+    }
+
+    // receive the files of `stream`, as sent by diode-receive. Returns the name or error of each
+    // entry.
+    fn receive_stream(
+        receive_config: &file::Config,
+        stream: Vec<u8>,
+        output_dir: &Path,
+    ) -> Vec<Result<String, String>> {
+        let diode_receive = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut to_receive = TcpStream::connect(diode_receive.local_addr().unwrap()).unwrap();
+        let writer = std::thread::spawn(move || to_receive.write_all(&stream).unwrap());
+
+        let (mut diode, _) = diode_receive.accept().unwrap();
+        let mut state = State {
+            content_ids: ContentIds::default(),
+            manifests: manifest::Checker::new(None),
+            sequences: sequence::Tracker::open(output_dir).unwrap(),
+        };
+        let mut directories = vec![];
+        let mut received = vec![];
+        while diode.peek(&mut [0]).unwrap() != 0 {
+            let result = receive_file(
+                receive_config,
+                &mut diode,
+                output_dir,
+                1,
+                &mut directories,
+                &mut state,
+            );
+            received.push(result.map(|(name, _, _)| name).map_err(|e| e.to_string()));
+        }
+        super::restore_directories(receive_config, directories);
+        writer.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn test_send_receive() {
+        let input = TempDir::new("send_receive_input");
+        let output = TempDir::new("send_receive_output");
+        let quarantine_dir = TempDir::new("send_receive_quarantine");
+        std::fs::create_dir_all(input.join("tree/sub")).unwrap();
+        std::fs::write(input.join("tree/a.txt"), "first file").unwrap();
+        std::fs::write(input.join("tree/sub/b.txt"), "second file").unwrap();
+        std::fs::write(input.join("bad.txt"), "corrupted content").unwrap();
+
+        let sha256 = config(protocol::HashAlgorithm::Sha256);
+
+        // directory tree, named relative to the parent of the argument
+        let received = transfer(&sha256, &sha256, &[&input.join("tree")], None, &output);
+        let names = ["tree", "tree/a.txt", "tree/sub", "tree/sub/b.txt"];
+        assert_eq!(received, names.map(|name| Ok(name.to_string())));
+        let read = |name: &str| std::fs::read_to_string(output.join(name)).ok();
+        assert_eq!(read("tree/a.txt").as_deref(), Some("first file"));
+        assert_eq!(read("tree/sub/b.txt").as_deref(), Some("second file"));
+
+        // hash algorithm other than the required one
+        let bad_path = input.join("bad.txt");
+        let bad = [bad_path.as_path()];
+        let blake3 = config(protocol::HashAlgorithm::Blake3);
+        let received = transfer(&blake3, &sha256, &bad, None, &output);
+        assert_eq!(
+            received,
+            [Err(
+                "diode error: hash algorithm blake3 used, sha256 required".to_string()
+            )]
+        );
+        assert!(!output.join("bad.txt").exists());
+
+        // content altered on the link: file is deleted
+        let received = transfer(&sha256, &sha256, &bad, Some(b"corrupted"), &output);
+        assert!(matches!(&received[..], [Err(e)] if e.contains("invalid hash")));
+        assert!(!output.join("bad.txt").exists());
+        assert!(!output.join(".bad.txt.lidi-part").exists());
+
+        // or quarantined
+        let mut quarantine = sha256.clone();
+        quarantine.quarantine_dir = Some(quarantine_dir.to_path_buf());
+        let received = transfer(&sha256, &quarantine, &bad, Some(b"corrupted"), &output);
+        assert!(matches!(&received[..], [Err(e)] if e.contains("invalid hash")));
+        assert!(!output.join("bad.txt").exists());
+        let mut quarantined: Vec<PathBuf> = std::fs::read_dir(&*quarantine_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        quarantined.sort();
+        assert_eq!(quarantined.len(), 2);
+        assert_eq!(
+            std::fs::read_to_string(&quarantined[0]).unwrap(),
+            "Corrupted content"
+        );
+        let sidecar = std::fs::read_to_string(&quarantined[1]).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+        assert_eq!(record["file_name"], "bad.txt");
+        assert_eq!(record["reason"], "invalid_hash");
+    }
+
+    #[test]
+    fn test_shrinking_file() {
+        let input = TempDir::new("shrinking_input");
+        let output = TempDir::new("shrinking_output");
+        std::fs::create_dir_all(input.join("tree")).unwrap();
+        let large = input.join("tree/a.bin");
+        std::fs::File::create(&large)
+            .unwrap()
+            .set_len(16 << 20)
+            .unwrap();
+        std::fs::write(input.join("tree/b.txt"), "next file").unwrap();
+
+        // small receive buffer: diode-send-file is blocked long before the end of the large file
+        let diode_send = TcpListener::bind("127.0.0.1:0").unwrap();
+        setsockopt(&diode_send, RcvBuf, &(64 << 10)).unwrap();
+        let mut send_config = config(protocol::HashAlgorithm::None);
+        send_config.diode = diode_send.local_addr().unwrap();
+        let tree = input.join("tree").to_str().unwrap().to_string();
+        let sender =
+            std::thread::spawn(move || file::send::send_files(&send_config, &[tree]).is_ok());
+
+        // file shrinks once its header and part of its content are sent
+        let (from_send, _) = diode_send.accept().unwrap();
+        let mut stream = vec![];
+        let mut from_send = from_send.take(1 << 20);
+        from_send.read_to_end(&mut stream).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&large)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+
+        // connection is closed in the middle of the file, which is rejected
+        from_send.into_inner().read_to_end(&mut stream).unwrap();
+        let config = config(protocol::HashAlgorithm::None);
+        let received = receive_stream(&config, stream, &output);
+        assert!(matches!(&received[..], [Ok(tree), Err(_)] if tree == "tree"));
+        assert!(!output.join("tree/a.bin").exists());
+        assert!(!output.join("tree/.a.bin.lidi-part").exists());
+
+        // next files are sent on a new connection
+        diode_send.set_nonblocking(true).unwrap();
+        let mut from_send = (0..500)
+            .find_map(|_| match diode_send.accept() {
+                Ok((from_send, _)) => Some(from_send),
+                Err(_) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    None
+                }
+            })
+            .expect("no new connection");
+        from_send.set_nonblocking(false).unwrap();
+        let mut stream = vec![];
+        from_send.read_to_end(&mut stream).unwrap();
+        assert!(sender.join().unwrap());
+        let received = receive_stream(&config, stream, &output);
+        assert_eq!(received, [Ok("tree/b.txt".to_string())]);
+        assert_eq!(
+            std::fs::read_to_string(output.join("tree/b.txt")).unwrap(),
+            "next file"
+        );
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path("dir/sub/file").ok(),
+            Some(PathBuf::from("dir/sub/file"))
+        );
+        assert_eq!(relative_path("file").ok(), Some(PathBuf::from("file")));
+        assert_eq!(
+            relative_path("./dir//file/").ok(),
+            Some(PathBuf::from("dir/file"))
+        );
+        assert!(relative_path("").is_err());
+        assert!(relative_path(".").is_err());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert!(relative_path("../file").is_err());
+        assert!(relative_path("dir/../../file").is_err());
+        assert!(relative_path("dir/..").is_err());
+        assert!(relative_path("fi\0le").is_err());
+    }
+
+    #[test]
+    fn test_create_dirs() {
+        let output_dir = TempDir::new("create_dirs");
+
+        let dir = create_dirs(&output_dir, Path::new("a/b")).ok();
+        assert_eq!(dir, Some(output_dir.join("a/b")));
+        assert!(output_dir.join("a/b").is_dir());
+        assert!(create_dirs(&output_dir, Path::new("a/b")).is_ok());
+
+        // symbolic links are not followed, even to a directory
+        std::os::unix::fs::symlink(std::env::temp_dir(), output_dir.join("link")).unwrap();
+        assert!(create_dirs(&output_dir, Path::new("link/c")).is_err());
+    }
+
+    #[test]
+    fn test_deliver() {
+        let dir = TempDir::new("deliver");
+        let part_path = dir.join(".file.txt.lidi-part");
+        let file_path = dir.join("file.txt");
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        let deliver = |content: &str, policy| {
+            std::fs::write(&part_path, content).unwrap();
+            deliver(policy, &part_path, &file_path).ok()
+        };
+
+        assert_eq!(
+            deliver("new", ConflictPolicy::Reject),
+            Some(file_path.clone())
+        );
+        assert!(!part_path.exists());
+        assert_eq!(read("file.txt").as_deref(), Some("new"));
+
+        // existing file is kept
+        assert_eq!(deliver("other", ConflictPolicy::Reject), None);
+        assert_eq!(read("file.txt").as_deref(), Some("new"));
+
+        assert_eq!(
+            deliver("overwrite", ConflictPolicy::Overwrite),
+            Some(file_path.clone())
+        );
+        assert_eq!(read("file.txt").as_deref(), Some("overwrite"));
+
+        assert_eq!(
+            deliver("renamed", ConflictPolicy::Rename),
+            Some(dir.join("file.1.txt"))
+        );
+        assert_eq!(
+            deliver("renamed again", ConflictPolicy::Rename),
+            Some(dir.join("file.2.txt"))
+        );
+        assert_eq!(read("file.2.txt").as_deref(), Some("renamed again"));
+
+        assert_eq!(
+            deliver("v2", ConflictPolicy::Version),
+            Some(file_path.clone())
+        );
+        assert_eq!(
+            deliver("v3", ConflictPolicy::Version),
+            Some(file_path.clone())
+        );
+        assert_eq!(read("file.txt").as_deref(), Some("v3"));
+        assert_eq!(
+            read(".lidi-versions/file.1.txt").as_deref(),
+            Some("overwrite")
+        );
+        assert_eq!(read(".lidi-versions/file.2.txt").as_deref(), Some("v2"));
+        assert!(!part_path.exists());
+    }
+
+    #[test]
+    fn test_content_ids() {
+        let dir = TempDir::new("content_ids");
+        let file_path = dir.join("file.txt");
+
+        let id = |content: &[u8]| protocol::content_id(&mut &content[..]).unwrap();
+        let mut content_ids = ContentIds::default();
+
+        assert!(!content_ids.holds(&file_path, 5, &id(b"hello")));
+
+        std::fs::write(&file_path, "hello").unwrap();
+        assert!(content_ids.holds(&file_path, 5, &id(b"hello")));
+        assert!(!content_ids.holds(&file_path, 5, &id(b"world")));
+        assert!(!content_ids.holds(&file_path, 6, &id(b"hello")));
+
+        // file replaced after its identifier was computed
+        std::fs::remove_file(&file_path).unwrap();
+        std::fs::write(&file_path, "world").unwrap();
+        assert!(content_ids.holds(&file_path, 5, &id(b"world")));
+        assert!(!content_ids.holds(&file_path, 5, &id(b"hello")));
+
+        // directories never hold content
+        assert!(!content_ids.holds(&dir, 5, &id(b"world")));
+
+        // identifier computed while receiving is recorded on delivery, file is not read again
+        let part_path = dir.join(".received.txt.lidi-part");
+        let received_path = dir.join("received.txt");
+        std::fs::write(&part_path, "hello").unwrap();
+        let header = protocol::Header {
+            file_name: "received.txt".to_string(),
+            mode: 0o100644,
+            file_length: 5,
+            hash: protocol::HashAlgorithm::None,
+            metadata: protocol::Metadata::default(),
+            chunk: None,
+            content_id: Some(id(b"hello")),
+            manifest: None,
+            sequence: None,
+        };
+        assert!(finish_file(
+            &config(protocol::HashAlgorithm::None),
+            std::fs::File::open(&part_path).unwrap(),
+            &received_path,
+            &part_path,
+            &header,
+            Some(id(b"recorded")),
+            &mut content_ids,
+        )
+        .is_ok());
+        assert!(content_ids.holds(&received_path, 5, &id(b"recorded")));
+        assert!(!content_ids.holds(&received_path, 5, &id(b"hello")));
+    }
+
+    #[test]
+    fn test_setid() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("setid");
+        let path = dir.join("program");
+        std::fs::write(&path, "").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        let mut config = config(protocol::HashAlgorithm::None);
+        let metadata = protocol::Metadata::default();
+        super::restore_metadata(&config, &file, &path, 0o106755, &metadata);
+        assert_eq!(mode(), 0o755);
+
+        config.keep_setid = true;
+        super::restore_metadata(&config, &file, &path, 0o106755, &metadata);
+        assert_eq!(mode(), 0o6755);
+
+        // quarantined files keep the mode of partial files, whatever the mode sent
+        let input = TempDir::new("setid_input");
+        let output = TempDir::new("setid_output");
+        let quarantine_dir = TempDir::new("setid_quarantine");
+        let program = input.join("program");
+        std::fs::write(&program, "setid content").unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o6777)).unwrap();
+        config.quarantine_dir = Some(quarantine_dir.to_path_buf());
+        config.hash = protocol::HashAlgorithm::Sha256;
+        let received = transfer(&config, &config, &[&program], Some(b"setid"), &output);
+        assert!(matches!(&received[..], [Err(e)] if e.contains("invalid hash")));
+        let quarantined = std::fs::read_dir(&*quarantine_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_none_or(|ext| ext != "json"))
+            .unwrap();
+        let mode = std::fs::metadata(quarantined).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
     }
 }
@@ -1,93 +1,464 @@
-use fasthash::HasherExt;
-
-use crate::file;
+use crate::file::{self, manifest, protocol};
+use nix::unistd::{Gid, Group, Uid, User};
+use sha2::{Digest, Sha256};
 use std::{
-    fs,
-    hash::Hash,
-    io::{Read, Write},
+    ffi, fs, io,
+    io::{Read, Seek, Write},
     net,
-    os::unix::fs::PermissionsExt,
+    os::unix::fs::{MetadataExt, PermissionsExt},
     path,
 };
+use xattr::FileExt;
+
+/// How a file is sent, besides its content and metadata
+#[derive(Clone, Default)]
+struct Sending {
+    /// only this chunk of the file
+    chunk: Option<protocol::Chunk>,
+    content_id: Option<protocol::ContentId>,
+    sequence: Option<protocol::Sequence>,
+}
 
+/// Send files and directory trees. A directory is sent with all its content, files and
+/// directories are named on the receiver side by their path relative to the parent directory of
+/// the argument.
+///
+/// Files larger than the configured chunk size are split in chunks, each one sent in its own
+/// session. Other files share a first session.
 pub fn send_files(config: &file::Config, files: &[String]) -> Result<(), file::Error> {
-    log::debug!("connecting to {}", config.diode);
-    let mut diode = net::TcpStream::connect(config.diode)?;
-    files.iter().enumerate().for_each(|(count, file)| {
-        match send_file(config, &mut diode, file, count == files.len() - 1) {
-            Ok(total) => log::info!("{file} sent, {total} bytes"),
-            Err(e) => log::error!("Cannot send file {file}: {e}"),
+    let mut entries = vec![];
+    for file in files {
+        if let Err(e) = list_entries(path::Path::new(file), &mut entries) {
+            log::error!("Cannot send {file}: {e}");
         }
-    });
+    }
+
+    let mut sessions = vec![vec![]];
+    for (file_path, file_name) in entries {
+        let chunks = chunks(config, &file_path, &file_name).and_then(|chunks| {
+            let content_id = content_id(config, &file_path)?;
+            Ok((chunks, content_id))
+        });
+        match chunks {
+            Ok((None, content_id)) => {
+                let sending = Sending {
+                    content_id,
+                    ..Sending::default()
+                };
+                sessions[0].push((file_path, file_name, sending));
+            }
+            Ok((Some(chunks), content_id)) => sessions.extend(chunks.into_iter().map(|chunk| {
+                let sending = Sending {
+                    chunk: Some(chunk),
+                    content_id,
+                    ..Sending::default()
+                };
+                vec![(file_path.clone(), file_name.clone(), sending)]
+            })),
+            Err(e) => log::error!("Cannot send file {}: {e}", file_path.display()),
+        }
+    }
+
+    for entries in sessions.iter().filter(|entries| !entries.is_empty()) {
+        let mut remaining = &entries[..];
+        while !remaining.is_empty() {
+            log::debug!("connecting to {}", config.diode);
+            let mut diode = net::TcpStream::connect(config.diode)?;
+            remaining = send_session(config, &mut diode, remaining);
+        }
+    }
+    Ok(())
+}
+
+/// send `entries` on connection `diode` until one fails. The connection may then be in the middle
+/// of a file, so it is dropped: diode-receive-file rejects this file only, and the entries left
+/// are returned to be sent on a new connection.
+fn send_session<'a>(
+    config: &file::Config,
+    diode: &mut net::TcpStream,
+    entries: &'a [(path::PathBuf, String, Sending)],
+) -> &'a [(path::PathBuf, String, Sending)] {
+    for (count, (file_path, file_name, sending)) in entries.iter().enumerate() {
+        let stream_end = count == entries.len() - 1;
+        match send_entry(
+            config,
+            diode,
+            file_path,
+            file_name,
+            sending.clone(),
+            stream_end,
+        ) {
+            Ok((total, _)) => match sending.chunk {
+                Some(chunk) => log::info!(
+                    "{} chunk {}/{} sent, {total} bytes",
+                    file_path.display(),
+                    chunk.index,
+                    chunk.count
+                ),
+                None => log::info!("{} sent, {total} bytes", file_path.display()),
+            },
+            Err(e) => {
+                log::error!("Cannot send file {}: {e}", file_path.display());
+                return &entries[count + 1..];
+            }
+        }
+    }
+    &[]
+}
+
+/// content identifier of a regular file, if configured
+fn content_id(
+    config: &file::Config,
+    file_path: &path::Path,
+) -> Result<Option<protocol::ContentId>, file::Error> {
+    if !config.content_id || !fs::metadata(file_path)?.is_file() {
+        return Ok(None);
+    }
+    let mut file = fs::File::open(file_path)?;
+    Ok(Some(protocol::content_id(&mut file)?))
+}
+
+/// chunks to send for a file larger than chunk size, `None` if it is sent whole
+fn chunks(
+    config: &file::Config,
+    file_path: &path::Path,
+    file_name: &str,
+) -> Result<Option<Vec<protocol::Chunk>>, file::Error> {
+    let Some(chunk_size) = config.chunk_size else {
+        return Ok(None);
+    };
+
+    let metadata = fs::metadata(file_path)?;
+    if !metadata.is_file() || metadata.len() <= chunk_size {
+        return Ok(None);
+    }
+
+    // same identifier when the same file is sent again
+    let mut hasher = Sha256::new();
+    hasher.update(file_name.as_bytes());
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(metadata.mtime().to_le_bytes());
+    hasher.update(metadata.mtime_nsec().to_le_bytes());
+    let mut file_id = [0u8; 8];
+    file_id.copy_from_slice(&hasher.finalize()[..8]);
+    let file_id = u64::from_le_bytes(file_id);
+
+    let count = protocol::Chunk::count(metadata.len(), chunk_size);
+    let chunks = (0..count)
+        .filter(|index| {
+            config
+                .chunks
+                .as_ref()
+                .is_none_or(|only| only.contains(index))
+        })
+        .map(|index| protocol::Chunk {
+            file_id,
+            index,
+            count,
+            chunk_size,
+            file_length: metadata.len(),
+        })
+        .collect();
+
+    Ok(Some(chunks))
+}
+
+fn os_to_string(name: &ffi::OsStr) -> Result<String, file::Error> {
+    name.to_os_string()
+        .into_string()
+        .map_err(|_| file::Error::Other("conversion from OsString to String failed".to_string()))
+}
+
+/// append `path` and, for a directory, its content to `entries`, with their name on the
+/// receiver side
+fn list_entries(
+    path: &path::Path,
+    entries: &mut Vec<(path::PathBuf, String)>,
+) -> Result<(), file::Error> {
+    // "." or "dir/.." have no file name
+    let file_name = match path.file_name() {
+        Some(file_name) => os_to_string(file_name)?,
+        None => os_to_string(
+            path.canonicalize()?
+                .file_name()
+                .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?,
+        )?,
+    };
+
+    // symbolic links given on command line are followed
+    let metadata = fs::metadata(path)?;
+    if metadata.is_file() {
+        entries.push((path.to_path_buf(), file_name));
+        Ok(())
+    } else if metadata.is_dir() {
+        entries.push((path.to_path_buf(), file_name.clone()));
+        list_directory(path, &file_name, entries)
+    } else {
+        Err(file::Error::Other("not a file or directory".to_string()))
+    }
+}
+
+/// append content of directory `dir`, named `dir_name` on the receiver side, to `entries`.
+/// Directories come before their content, symbolic links and special files are skipped.
+fn list_directory(
+    dir: &path::Path,
+    dir_name: &str,
+    entries: &mut Vec<(path::PathBuf, String)>,
+) -> Result<(), file::Error> {
+    let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let file_name = match os_to_string(&child.file_name()) {
+            Ok(file_name) => format!("{dir_name}/{file_name}"),
+            Err(e) => {
+                log::warn!("skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+
+        let file_type = child.file_type()?;
+        if file_type.is_file() {
+            entries.push((path, file_name));
+        } else if file_type.is_dir() {
+            entries.push((path.clone(), file_name.clone()));
+            list_directory(&path, &file_name, entries)?;
+        } else {
+            log::warn!(
+                "skipping {}: not a regular file or directory",
+                path.display()
+            );
+        }
+    }
+
     Ok(())
 }
 
+/// send a regular file, named on the receiver side by its file name, with its position among the
+/// files of the sender if any. Returns this name, size and hash of content sent.
 pub fn send_file(
     config: &file::Config,
     diode: &mut net::TcpStream,
     file_path: &str,
+    sequence: Option<protocol::Sequence>,
     stream_end: bool,
-) -> Result<usize, file::Error> {
-    log::debug!("opening file \"{}\"", file_path);
-
+) -> Result<(String, usize, Vec<u8>), file::Error> {
     let file_path = path::PathBuf::from(file_path);
 
     if !file_path.is_file() {
         return Err(file::Error::Other("not a file".to_string()));
     }
 
+    let file_name = os_to_string(
+        file_path
+            .file_name()
+            .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?,
+    )?;
+
+    let sending = Sending {
+        chunk: None,
+        content_id: content_id(config, &file_path)?,
+        sequence,
+    };
+    let (total, hash) = send_entry(config, diode, &file_path, &file_name, sending, stream_end)?;
+    Ok((file_name, total, hash))
+}
+
+/// send next manifest of recently sent files
+pub fn send_manifest(
+    config: &file::Config,
+    diode: &mut net::TcpStream,
+    manifest: &mut manifest::Sender,
+    stream_end: bool,
+) -> Result<usize, file::Error> {
+    let (content, signature) = manifest.next(&config.hash.to_string());
+
+    let header = file::protocol::Header {
+        file_name: manifest::MANIFEST_NAME.to_string(),
+        mode: 0o100600,
+        file_length: content.len() as u64,
+        hash: config.hash,
+        metadata: protocol::Metadata::default(),
+        chunk: None,
+        content_id: None,
+        manifest: Some(signature),
+        sequence: None,
+    };
+    header.serialize_to(diode)?;
+
+    diode.write_all(&content)?;
+
+    let mut hasher = config.hash.hasher();
+    hasher.update(&content);
+    let footer = file::protocol::Footer {
+        hash: hasher.finish(),
+        stream_end,
+    };
+    footer.serialize_to(diode)?;
+
+    diode.flush()?;
+    Ok(content.len())
+}
+
+/// times, and owner and extended attributes if configured. Errors are logged, the file is sent
+/// anyway.
+fn file_metadata(
+    config: &file::Config,
+    file: &fs::File,
+    metadata: &fs::Metadata,
+) -> protocol::Metadata {
+    let times = metadata.modified().ok().zip(metadata.accessed().ok());
+
+    let owner = (config.owner != file::OwnerMapping::None).then(|| {
+        let user = User::from_uid(Uid::from_raw(metadata.uid()))
+            .ok()
+            .flatten()
+            .map(|user| user.name);
+        let group = Group::from_gid(Gid::from_raw(metadata.gid()))
+            .ok()
+            .flatten()
+            .map(|group| group.name);
+        protocol::Owner {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            user: user.unwrap_or_default(),
+            group: group.unwrap_or_default(),
+        }
+    });
+
+    let mut xattrs = vec![];
+    if !config.xattrs.is_empty() {
+        match file.list_xattr() {
+            Ok(names) => {
+                for name in names {
+                    let Some(name) = name.to_str().filter(|name| config.xattr_selected(name))
+                    else {
+                        continue;
+                    };
+                    match file.get_xattr(name) {
+                        Ok(Some(value)) => xattrs.push((name.to_string(), value)),
+                        Ok(None) => (),
+                        Err(e) => log::warn!("Cannot read extended attribute {name}: {e}"),
+                    }
+                }
+            }
+            Err(e) => log::warn!("Cannot list extended attributes: {e}"),
+        }
+    }
+
+    protocol::Metadata {
+        times,
+        owner,
+        xattrs,
+    }
+}
+
+/// send file or directory `file_path`, named `file_name` on the receiver side, or one chunk of
+/// the file. Directories are sent without content. Returns size and hash of content sent.
+fn send_entry(
+    config: &file::Config,
+    diode: &mut net::TcpStream,
+    file_path: &path::Path,
+    file_name: &str,
+    sending: Sending,
+    stream_end: bool,
+) -> Result<(usize, Vec<u8>), file::Error> {
+    log::debug!("opening file \"{}\"", file_path.display());
+
     let mut file = fs::OpenOptions::new()
         .read(true)
         .write(false)
         .create(false)
-        .open(&file_path)?;
-
-    let file_name = file_path
-        .file_name()
-        .ok_or(file::Error::Other("unwrap of file_name failed".to_string()))?
-        .to_os_string()
-        .into_string()
-        .map_err(|_| file::Error::Other("conversion from OsString to String failed".to_string()))?;
+        .open(file_path)?;
 
     log::debug!("file name is \"{file_name}\"");
 
     let metadata = file.metadata()?;
     let permissions = metadata.permissions();
+    let file_metadata = file_metadata(config, &file, &metadata);
+
+    if metadata.is_dir() {
+        let header = file::protocol::Header {
+            file_name: file_name.to_string(),
+            mode: permissions.mode(),
+            file_length: 0,
+            hash: config.hash,
+            metadata: file_metadata,
+            chunk: None,
+            content_id: None,
+            manifest: None,
+            sequence: sending.sequence,
+        };
+        header.serialize_to(diode)?;
+
+        let footer = file::protocol::Footer {
+            hash: config.hash.hasher().finish(),
+            stream_end,
+        };
+        footer.serialize_to(diode)?;
+
+        diode.flush()?;
+        return Ok((0, vec![]));
+    }
+
+    let file_length = match sending.chunk {
+        Some(chunk) => {
+            file.seek(io::SeekFrom::Start(chunk.offset()))?;
+            chunk.length()
+        }
+        None => metadata.len(),
+    };
 
     let header = file::protocol::Header {
-        file_name,
+        file_name: file_name.to_string(),
         mode: permissions.mode(),
-        file_length: metadata.len(),
+        file_length,
+        hash: config.hash,
+        metadata: file_metadata,
+        chunk: sending.chunk,
+        content_id: sending.content_id,
+        manifest: None,
+        sequence: sending.sequence,
     };
 
     header.serialize_to(diode)?;
 
+    // content announced in header, even if the file grows
+    let mut file = file.take(file_length);
+
     let mut buffer = vec![0; config.buffer_size];
     let mut cursor = 0;
     let mut total = 0;
 
-    let mut hasher = fasthash::Murmur3HasherExt::default();
+    let mut hasher = config.hash.hasher();
 
     loop {
         match file.read(&mut buffer[cursor..])? {
             0 => {
                 if 0 < cursor {
                     total += cursor;
-                    if config.hash {
-                        buffer[..cursor].hash(&mut hasher);
-                    }
+                    hasher.update(&buffer[..cursor]);
                     diode.write_all(&buffer[..cursor])?;
                 }
 
+                if total as u64 != file_length {
+                    return Err(file::Error::Diode(protocol::Error::InvalidFileSize(
+                        file_length as usize,
+                        total,
+                    )));
+                }
+
+                let hash = hasher.finish();
                 let footer = file::protocol::Footer {
-                    hash: if config.hash { hasher.finish_ext() } else { 0 },
+                    hash: hash.clone(),
                     stream_end,
                 };
 
                 footer.serialize_to(diode)?;
 
                 diode.flush()?;
-                return Ok(total);
+                return Ok((total, hash));
             }
             nread => {
                 if (cursor + nread) < config.buffer_size {
@@ -95,9 +466,7 @@ pub fn send_file(
                     continue;
                 }
                 total += config.buffer_size;
-                if config.hash {
-                    buffer.hash(&mut hasher);
-                }
+                hasher.update(&buffer);
                 diode.write_all(&buffer)?;
                 cursor = 0;
             }
@@ -0,0 +1,249 @@
+//! Sequence numbers of sent files, so that diode-receive-file detects gaps and reorderings
+//!
+//! diode-send-dir numbers each file it sends, the last number being kept in a state file across
+//! restarts. diode-receive-file keeps the last number seen of each sender in
+//! `<output_dir>/.lidi-sequences.json`.
+
+use crate::file::write_atomic;
+use metrics::{counter, gauge};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io, path,
+};
+
+/// last sequence number of each sender, in output directory
+pub const SEQUENCES_FILE: &str = ".lidi-sequences.json";
+
+/// maximum number of senders with their own metrics, others share the `other` label
+pub const MAX_SENDER_LABELS: usize = 32;
+
+/// Sequence numbers of the files of a sender
+pub struct Counter {
+    /// state file, numbers start from 1 at each start without it
+    path: Option<path::PathBuf>,
+    last: u64,
+}
+
+impl Counter {
+    /// an empty or corrupted state file is logged, numbering starts from 1 again
+    pub fn open(path: Option<path::PathBuf>) -> io::Result<Self> {
+        let last = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(last) => last.trim().parse().unwrap_or_else(|e| {
+                    log::warn!(
+                        "invalid sequence number in {}: {e}, numbering files from #1",
+                        path.display()
+                    );
+                    0
+                }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            },
+            None => 0,
+        };
+        Ok(Self { path, last })
+    }
+
+    /// number of next file, the same until a file is sent (see [Counter::sent]): files failing
+    /// to be sent do not leave gaps
+    pub fn next(&self) -> io::Result<u64> {
+        self.last
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no sequence number left"))
+    }
+
+    /// file `seq` is sent, its number is saved so that numbering goes on after a restart
+    pub fn sent(&mut self, seq: u64) -> io::Result<()> {
+        self.last = seq;
+        match &self.path {
+            Some(path) => write_atomic(path, format!("{seq}\n").as_bytes()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Order of a received file among the files of its sender
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Order {
+    /// first file seen from this sender
+    First,
+    InOrder,
+    /// files between last one and this one were not seen, or not yet
+    Gap(u64),
+    /// same number as the last one seen: file sent again after a failure
+    Repeated,
+    /// file numbered before the last one seen
+    Reordered(u64),
+    /// numbering started again from 1, sender lost its state
+    Restarted(u64),
+}
+
+/// Last sequence number seen of each sender
+pub struct Tracker {
+    path: path::PathBuf,
+    last: BTreeMap<String, u64>,
+    /// senders with their own metrics
+    labels: BTreeSet<String>,
+}
+
+impl Tracker {
+    /// an empty or corrupted state file is logged, and replaced once a file is received
+    pub fn open(output_dir: &path::Path) -> io::Result<Self> {
+        let path = output_dir.join(SEQUENCES_FILE);
+        let last = match fs::read(&path) {
+            Ok(last) => serde_json::from_slice(&last).unwrap_or_else(|e| {
+                log::warn!(
+                    "invalid sequence numbers in {}: {e}, next files of each sender are the first ones",
+                    path.display()
+                );
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            last,
+            labels: BTreeSet::new(),
+        })
+    }
+
+    /// metric label of `sender`: names come from file headers, so their number is bounded
+    fn label(&mut self, sender: &str) -> String {
+        if self.labels.contains(sender) {
+            return sender.to_string();
+        }
+        if self.labels.len() < MAX_SENDER_LABELS {
+            self.labels.insert(sender.to_string());
+            return sender.to_string();
+        }
+        "other".to_string()
+    }
+
+    /// record file `seq` of `sender`, logs and counts gaps and reorderings
+    pub fn record(&mut self, sender: &str, seq: u64, file_name: &str) -> Order {
+        let order = match self.last.get(sender) {
+            None => Order::First,
+            Some(&last) if last.checked_add(1) == Some(seq) => Order::InOrder,
+            Some(&last) if seq > last => Order::Gap(seq - last - 1),
+            Some(&last) if seq == last => Order::Repeated,
+            Some(&last) if seq == 1 => Order::Restarted(last),
+            Some(&last) => Order::Reordered(last),
+        };
+
+        let labels = [("sender", self.label(sender))];
+        match order {
+            Order::First => log::info!("first file of sender {sender}: #{seq} \"{file_name}\""),
+            Order::InOrder => (),
+            Order::Repeated => {
+                log::info!("file #{seq} \"{file_name}\" of sender {sender} sent again")
+            }
+            Order::Gap(missing) => {
+                log::warn!(
+                    "{missing} files of sender {sender} not received before #{seq} \"{file_name}\": #{}-#{}",
+                    seq - missing,
+                    seq - 1
+                );
+                counter!("rx_file_sequence_gaps", &labels).increment(missing);
+            }
+            Order::Reordered(last) => {
+                log::warn!("file #{seq} \"{file_name}\" of sender {sender} received after #{last}");
+                counter!("rx_file_sequence_reordered", &labels).increment(1);
+            }
+            Order::Restarted(last) => {
+                log::warn!("sender {sender} numbers files from #1 again, last file was #{last}");
+                counter!("rx_file_sequence_restarts", &labels).increment(1);
+            }
+        }
+
+        // later files are compared to the last one sent
+        if !matches!(order, Order::Reordered(_) | Order::Repeated) {
+            self.last.insert(sender.to_string(), seq);
+            gauge!("rx_file_sequence_last", &labels).set(seq as f64);
+            if let Err(e) = self.save() {
+                log::warn!("Cannot save {}: {e}", self.path.display());
+            }
+        }
+
+        order
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec(&self.last).map_err(io::Error::other)?;
+        write_atomic(&self.path, &json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, Order, Tracker};
+    use crate::test::TempDir;
+
+    #[test]
+    fn test_sequence() {
+        let dir = TempDir::new("sequence");
+
+        // numbers go on after a restart
+        let state = dir.join("sequence");
+        let mut counter = Counter::open(Some(state.clone())).unwrap();
+        assert_eq!(counter.next().unwrap(), 1);
+        counter.sent(1).unwrap();
+        // number is used again until a file is sent
+        assert_eq!(counter.next().unwrap(), 2);
+        assert_eq!(counter.next().unwrap(), 2);
+        counter.sent(2).unwrap();
+        let counter = Counter::open(Some(state.clone())).unwrap();
+        assert_eq!(counter.next().unwrap(), 3);
+
+        // empty or corrupted state: numbering starts again
+        for content in ["", "3x"] {
+            std::fs::write(&state, content).unwrap();
+            let counter = Counter::open(Some(state.clone())).unwrap();
+            assert_eq!(counter.next().unwrap(), 1);
+        }
+        std::fs::write(&state, u64::MAX.to_string()).unwrap();
+        assert!(Counter::open(Some(state)).unwrap().next().is_err());
+
+        let mut tracker = Tracker::open(&dir).unwrap();
+        assert_eq!(tracker.record("a", 1, "f1"), Order::First);
+        assert_eq!(tracker.record("a", 2, "f2"), Order::InOrder);
+        assert_eq!(tracker.record("b", 7, "g7"), Order::First);
+        assert_eq!(tracker.record("a", 5, "f5"), Order::Gap(2));
+        assert_eq!(tracker.record("a", 4, "f4"), Order::Reordered(5));
+
+        // last numbers are kept across restarts
+        let mut tracker = Tracker::open(&dir).unwrap();
+        assert_eq!(tracker.record("a", 6, "f6"), Order::InOrder);
+        assert_eq!(tracker.record("b", 8, "g8"), Order::InOrder);
+        assert_eq!(tracker.record("b", 1, "h1"), Order::Restarted(8));
+        assert_eq!(tracker.record("b", 2, "h2"), Order::InOrder);
+
+        // sent again after a failure
+        assert_eq!(tracker.record("b", 2, "h2"), Order::Repeated);
+        assert_eq!(tracker.record("b", 3, "h3"), Order::InOrder);
+
+        // no overflow
+        assert_eq!(tracker.record("c", u64::MAX, "i"), Order::First);
+        assert_eq!(tracker.record("c", 1, "j"), Order::Restarted(u64::MAX));
+
+        // corrupted state
+        std::fs::write(dir.join(super::SEQUENCES_FILE), "{\"a\": ").unwrap();
+        let mut tracker = Tracker::open(&dir).unwrap();
+        assert_eq!(tracker.record("a", 7, "f7"), Order::First);
+        let mut tracker = Tracker::open(&dir).unwrap();
+        assert_eq!(tracker.record("a", 8, "f8"), Order::InOrder);
+
+        // metrics of a bounded number of senders
+        let mut tracker = Tracker::open(&dir).unwrap();
+        let senders: Vec<String> = (0..=super::MAX_SENDER_LABELS)
+            .map(|i| format!("s{i}"))
+            .collect();
+        let labels: Vec<String> = senders.iter().map(|s| tracker.label(s)).collect();
+        assert_eq!(
+            labels[..super::MAX_SENDER_LABELS],
+            senders[..super::MAX_SENDER_LABELS]
+        );
+        assert_eq!(labels[super::MAX_SENDER_LABELS], "other");
+        assert_eq!(tracker.label("s0"), "s0");
+    }
+}
@@ -1,8 +1,11 @@
+pub mod audit;
 pub mod config;
+pub mod control;
 pub mod file;
 pub mod protocol;
 pub mod receive;
 pub mod send;
+pub mod systemd;
 pub mod test;
 pub mod udp;
 
@@ -11,15 +14,56 @@ use log4rs::{
     append::console::{ConsoleAppender, Target},
     config::{Appender, Root},
     filter::threshold::ThresholdFilter,
-    Config,
+    Config, Handle,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
 };
-use metrics_exporter_prometheus::PrometheusBuilder;
 use std::{
     io::{Error, ErrorKind, Result},
     net::SocketAddr,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+static LOG_HANDLE: OnceLock<Handle> = OnceLock::new();
+
+fn parse_log_level(log_level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(log_level).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid log level string: {log_level}: {e}"),
+        )
+    })
+}
+
+fn console_log_config(level: LevelFilter) -> Result<Config> {
+    // Build a stderr logger.
+    let stdout = ConsoleAppender::builder().target(Target::Stdout).build();
+    // Log Trace level output to file where trace is the default level
+    // and the programmatically specified level to stderr.
+    Config::builder()
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(level)))
+                .build("stdout", Box::new(stdout)),
+        )
+        .build(Root::builder().appender("stdout").build(level))
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Cannot build log config: {e}"),
+            )
+        })
+}
+
 pub fn init_logger(log_config: Option<&String>, log_level: &str) -> Result<()> {
     // use log4rs configuration file if set in main config
     if let Some(file) = log_config {
@@ -27,46 +71,44 @@ pub fn init_logger(log_config: Option<&String>, log_level: &str) -> Result<()> {
         let _handle = log4rs::init_file(file, Default::default());
     } else {
         // use log level set in parameter
-        let level = match LevelFilter::from_str(log_level) {
-            Ok(level_filter) => level_filter,
-
-            Err(e) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Invalid log level string: {log_level}: {e}"),
-                ));
-            }
-        };
-
-        // Build a stderr logger.
-        let stdout = ConsoleAppender::builder().target(Target::Stdout).build();
-        // Log Trace level output to file where trace is the default level
-        // and the programmatically specified level to stderr.
-        let config = Config::builder()
-            .appender(
-                Appender::builder()
-                    .filter(Box::new(ThresholdFilter::new(level)))
-                    .build("stdout", Box::new(stdout)),
-            )
-            .build(Root::builder().appender("stdout").build(level))
-            .map_err(|e| {
-                Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Cannot build log config: {e}"),
-                )
-            })?;
+        let level = parse_log_level(log_level)?;
 
-        log4rs::init_config(config).map_err(|e| {
+        let handle = log4rs::init_config(console_log_config(level)?).map_err(|e| {
             Error::new(
                 ErrorKind::InvalidData,
                 format!("Cannot init log4rs config: {e}"),
             )
         })?;
+
+        let _ = LOG_HANDLE.set(handle);
     }
 
     Ok(())
 }
 
+/// change log level at runtime
+///
+/// When logs are configured with a log4rs configuration file, the level can only be lowered
+/// compared to the one set in this file.
+pub fn set_log_level(log_level: &str) -> Result<()> {
+    let level = parse_log_level(log_level)?;
+
+    match LOG_HANDLE.get() {
+        Some(handle) => handle.set_config(console_log_config(level)?),
+        None => log::set_max_level(level),
+    }
+
+    log::info!("log level set to {level}");
+
+    Ok(())
+}
+
+/// histogram buckets of session metrics: duration in seconds, size in bytes and in blocks
+const SESSION_DURATION_BUCKETS: [f64; 9] =
+    [0.01, 0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 86400.0];
+const SESSION_BYTES_BUCKETS: [f64; 9] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11];
+const SESSION_BLOCKS_BUCKETS: [f64; 8] = [1.0, 10.0, 100.0, 1e3, 1e4, 1e5, 1e6, 1e7];
+
 pub fn init_metrics(prom_url: Option<&str>) -> Result<()> {
     if let Some(addr) = prom_url {
         let addr = SocketAddr::from_str(addr).map_err(|e| {
@@ -78,7 +120,23 @@ pub fn init_metrics(prom_url: Option<&str>) -> Result<()> {
 
         PrometheusBuilder::new()
             .with_http_listener(addr)
-            .install()
+            .set_buckets_for_metric(
+                Matcher::Suffix("_session_duration_seconds".into()),
+                &SESSION_DURATION_BUCKETS,
+            )
+            .and_then(|builder| {
+                builder.set_buckets_for_metric(
+                    Matcher::Suffix("_session_bytes".into()),
+                    &SESSION_BYTES_BUCKETS,
+                )
+            })
+            .and_then(|builder| {
+                builder.set_buckets_for_metric(
+                    Matcher::Suffix("_session_blocks".into()),
+                    &SESSION_BLOCKS_BUCKETS,
+                )
+            })
+            .and_then(|builder| builder.install())
             .map_err(|e| {
                 Error::new(
                     ErrorKind::InvalidInput,
@@ -91,3 +149,59 @@ pub fn init_metrics(prom_url: Option<&str>) -> Result<()> {
 
     Ok(())
 }
+
+/// start a thread setting `shutdown` when SIGTERM or SIGINT is received
+///
+/// A second signal exits immediately, without waiting for the end of the current session.
+pub fn handle_shutdown_signals(shutdown: Arc<AtomicBool>) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+
+    thread::Builder::new()
+        .name("lidi_shutdown".into())
+        .spawn(move || {
+            for signal in signals.forever() {
+                if shutdown.swap(true, Ordering::Relaxed) {
+                    log::warn!("signal {signal} received again: exiting now");
+                    std::process::exit(1);
+                }
+                log::info!("signal {signal} received: shutting down");
+
+                if let Some(notifier) = systemd::Notifier::from_env() {
+                    if let Err(e) = notifier.notify("STOPPING=1") {
+                        log::warn!("systemd: unable to send notification: {e}");
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// wait for all `threads` to finish, until `deadline`
+///
+/// Return false if some threads are still running at `deadline`. Those threads are left in
+/// `threads`.
+pub fn join_until(threads: &mut Vec<thread::JoinHandle<()>>, deadline: Instant) -> bool {
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) = std::mem::take(threads)
+            .into_iter()
+            .partition(|thread| thread.is_finished());
+        *threads = running;
+
+        for thread in finished {
+            if let Err(e) = thread.join() {
+                log::warn!("Cannot join thread: {e:?}");
+            }
+        }
+
+        if threads.is_empty() {
+            return true;
+        }
+
+        if Instant::now() > deadline {
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -1,11 +1,20 @@
 //! Definition of the Lidi protocol used to transfer data over UDP
 //!
 //! The Lidi protocol is rather simple: since the communications are unidirectional, it is defined
-//! by the messages structure. There are 5 message types:
+//! by the messages structure. There are 6 message types:
 //! - `MessageType::Heartbeat` message without data to tell receiver the other side is alive
 //! - `MessageType::Start` informs the receiver that the sent data chunk represents the beginning of a new transfer,
 //! - `MessageType::Data` is used to inform this packet contains data
 //! - `MessageType::End` informs the receiver that the current transfer is completed (i.e. this is the last message for the current connection)
+//! - `MessageType::Abort` is set along with `MessageType::End` when the current transfer has been aborted on sender side
+//! - `MessageType::Digest` is set along with `MessageType::End` when the last block carries the
+//!   SHA-256 digest of the session data, right after its data (see [block_digest]). Previous
+//!   receivers reject this flag, as any unknown bit of `message_flags`: diode-send only sets it
+//!   when `session_digest` is enabled.
+//!
+//! `MessageType::Init` is sent once when diode-send starts. Its payload is the parameters of
+//! diode-send followed by its epoch (see [serialize_init]): previous receivers only read the
+//! parameters.
 //!
 //! A message is stored in a `Vec` of `u8`s, with the following representation:
 //!
@@ -53,6 +62,7 @@ bitflags! {
         const Data      = 0b00000100;
         const End       = 0b00001000;
         const Abort     = 0b00010000;
+        const Digest    = 0b00100000;
         const Init      = 0b10000000;
     }
 }
@@ -83,6 +93,8 @@ impl fmt::Display for MessageType {
         display_bit(fmt, *self, MessageType::Start, "Start", &mut count)?;
         display_bit(fmt, *self, MessageType::Data, "Data", &mut count)?;
         display_bit(fmt, *self, MessageType::End, "End", &mut count)?;
+        display_bit(fmt, *self, MessageType::Abort, "Abort", &mut count)?;
+        display_bit(fmt, *self, MessageType::Digest, "Digest", &mut count)?;
         display_bit(fmt, *self, MessageType::Init, "Init", &mut count)?;
 
         Ok(())
@@ -100,6 +112,8 @@ pub struct Header {
 const SERIALIZE_OVERHEAD: u16 = 4;
 /// data added to each block to store real data size (without protocol padding)
 pub const PAYLOAD_OVERHEAD: usize = 4;
+/// size of the session digest carried by the last block
+pub const DIGEST_SIZE: usize = 32;
 pub const FIRST_BLOCK_ID: u8 = 0;
 pub const FIRST_SESSION_ID: u8 = 0;
 
@@ -240,6 +254,106 @@ pub fn nb_repair_packets(
     repair_block_size / u32::from(data_mtu(oti))
 }
 
+/// smallest MTU leaving room for headers and one aligned RaptorQ symbol
+pub const MIN_MTU: u16 =
+    PACKET_HEADER_SIZE + RAPTORQ_HEADER_SIZE + SERIALIZE_OVERHEAD + RAPTORQ_ALIGNMENT;
+
+/// maximum number of source symbols in a RaptorQ block (see RFC 6330)
+const MAX_SOURCE_PACKETS: u64 = 56403;
+
+/// Packets and blocks actually used on the UDP link for a given configuration
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LinkParameters {
+    /// size of the RaptorQ symbol carried by each packet
+    pub data_mtu: u16,
+    /// size of the UDP payload of each packet (lidi and RaptorQ headers included)
+    pub udp_payload_size: u16,
+    /// number of source packets per block
+    pub nb_encoding_packets: u64,
+    /// number of repair packets per block
+    pub nb_repair_packets: u32,
+    /// encoding block size, rounded to a multiple of `data_mtu`
+    pub encoding_block_size: u64,
+    /// repair block size, rounded to a multiple of `data_mtu`
+    pub repair_block_size: u32,
+}
+
+impl LinkParameters {
+    /// compute link parameters, rejecting configurations which cannot be encoded
+    pub fn new(mtu: u16, encoding_block_size: u64, repair_block_size: u32) -> Result<Self, Error> {
+        if mtu < MIN_MTU {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 'udp_mtu': {mtu}: must be >= {MIN_MTU} to hold headers ({} bytes) and at least one aligned symbol", MIN_MTU - RAPTORQ_ALIGNMENT),
+            ));
+        }
+
+        let data_mtu = RAPTORQ_ALIGNMENT
+            * ((mtu - PACKET_HEADER_SIZE - RAPTORQ_HEADER_SIZE - SERIALIZE_OVERHEAD)
+                / RAPTORQ_ALIGNMENT);
+
+        let nb_encoding_packets =
+            encoding_block_size.saturating_add(PAYLOAD_OVERHEAD as u64) / u64::from(data_mtu);
+
+        if nb_encoding_packets == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 'encoding_block_size': {encoding_block_size}: must be >= {} to fill at least one packet of {data_mtu} bytes with udp_mtu {mtu}", u64::from(data_mtu) - PAYLOAD_OVERHEAD as u64),
+            ));
+        }
+
+        if nb_encoding_packets > MAX_SOURCE_PACKETS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 'encoding_block_size': {encoding_block_size}: produces {nb_encoding_packets} packets per block with udp_mtu {mtu}, RaptorQ supports at most {MAX_SOURCE_PACKETS}"),
+            ));
+        }
+
+        let nb_repair_packets = repair_block_size / u32::from(data_mtu);
+
+        if repair_block_size > 0 && nb_repair_packets == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 'repair_block_size': {repair_block_size}: must be 0 or >= {data_mtu} (size of one packet with udp_mtu {mtu}), otherwise no repair packet is sent"),
+            ));
+        }
+
+        if nb_encoding_packets + u64::from(nb_repair_packets) > u64::from(u16::MAX) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid 'repair_block_size': {repair_block_size}: too many packets per block ({nb_encoding_packets} source + {nb_repair_packets} repair), must be <= {}", u16::MAX),
+            ));
+        }
+
+        Ok(Self {
+            data_mtu,
+            udp_payload_size: data_mtu + RAPTORQ_HEADER_SIZE + SERIALIZE_OVERHEAD,
+            nb_encoding_packets,
+            nb_repair_packets,
+            encoding_block_size: nb_encoding_packets * u64::from(data_mtu),
+            repair_block_size: nb_repair_packets * u32::from(data_mtu),
+        })
+    }
+
+    /// number of packets sent for each block
+    pub fn nb_packets(&self) -> u64 {
+        self.nb_encoding_packets + u64::from(self.nb_repair_packets)
+    }
+
+    /// maximum amount of TCP data carried by one block
+    pub fn block_payload_size(&self) -> u64 {
+        self.encoding_block_size - PAYLOAD_OVERHEAD as u64
+    }
+
+    /// ratio of repair packets to source packets, in percent
+    pub fn fec_overhead(&self) -> f64 {
+        f64::from(self.nb_repair_packets) * 100.0 / self.nb_encoding_packets as f64
+    }
+}
+
+/// size of serialized [LidiParameters]
+const PARAMETERS_SIZE: usize = 19;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct LidiParameters {
     encoding_block_size: u64,
@@ -270,8 +384,8 @@ impl LidiParameters {
         self.heartbeat
     }
 
-    pub fn serialize(&self) -> [u8; 19] {
-        let mut payload = [0; 19];
+    pub fn serialize(&self) -> [u8; PARAMETERS_SIZE] {
+        let mut payload = [0; PARAMETERS_SIZE];
 
         let data = u64::to_be_bytes(self.encoding_block_size);
         payload[0..8].copy_from_slice(&data);
@@ -291,7 +405,7 @@ impl LidiParameters {
     }
 
     pub fn deserialize(data: &[u8]) -> Result<Self, Error> {
-        if data.len() < 19 {
+        if data.len() < PARAMETERS_SIZE {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "UDP init packet payload deserialize",
@@ -325,3 +439,99 @@ impl LidiParameters {
         ))
     }
 }
+
+/// session digest stored after the data of a block (`payload` starts with the data length)
+pub fn block_digest(payload: &[u8]) -> Option<[u8; DIGEST_SIZE]> {
+    let length = u32::from_be_bytes(payload.get(0..PAYLOAD_OVERHEAD)?.try_into().ok()?) as usize;
+    let start = PAYLOAD_OVERHEAD + length;
+    payload.get(start..start + DIGEST_SIZE)?.try_into().ok()
+}
+
+/// payload of init messages: parameters, then the epoch identifying a diode-send instance (its
+/// start time, in ms since the UNIX epoch)
+pub fn serialize_init(parameters: &LidiParameters, epoch: u64) -> Vec<u8> {
+    let mut payload = parameters.serialize().to_vec();
+    payload.extend_from_slice(&u64::to_be_bytes(epoch));
+    payload
+}
+
+/// epoch from init payload, None if diode-send does not provide it
+pub fn deserialize_epoch(data: &[u8]) -> Option<u64> {
+    let epoch = data.get(PARAMETERS_SIZE..PARAMETERS_SIZE + 8)?;
+    Some(u64::from_be_bytes(epoch.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        block_digest, deserialize_epoch, nb_encoding_packets, object_transmission_information,
+        serialize_init, LidiParameters, LinkParameters, DIGEST_SIZE, MIN_MTU,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_block_digest() {
+        let mut payload = vec![0; 100];
+        payload[0..4].copy_from_slice(&u32::to_be_bytes(10));
+        payload[14..14 + DIGEST_SIZE].copy_from_slice(&[7; DIGEST_SIZE]);
+        assert_eq!(block_digest(&payload), Some([7; DIGEST_SIZE]));
+
+        // no room for digest after data
+        payload[0..4].copy_from_slice(&u32::to_be_bytes(80));
+        assert_eq!(block_digest(&payload), None);
+        assert_eq!(block_digest(&[0; 2]), None);
+    }
+
+    #[test]
+    fn test_epoch() {
+        let parameters = LidiParameters::new(60000, 6000, Duration::from_millis(500), 1500, 2);
+        let epoch = 1_760_000_000_123;
+        let payload = serialize_init(&parameters, epoch);
+        assert_eq!(deserialize_epoch(&payload), Some(epoch));
+
+        // parameters are read as before, epoch is missing from previous senders
+        assert_eq!(LidiParameters::deserialize(&payload).unwrap(), parameters);
+        assert_eq!(deserialize_epoch(&parameters.serialize()), None);
+        assert_eq!(deserialize_epoch(&payload[..23]), None);
+    }
+
+    #[test]
+    fn test_link_parameters() {
+        let link = LinkParameters::new(1500, 60000, 6000).unwrap();
+        assert_eq!(link.data_mtu, 1464);
+        assert_eq!(link.udp_payload_size, 1472);
+        assert_eq!(link.nb_encoding_packets, 40);
+        assert_eq!(link.nb_repair_packets, 4);
+        assert_eq!(link.encoding_block_size, 58560);
+        assert_eq!(link.repair_block_size, 5856);
+        assert_eq!(link.nb_packets(), 44);
+        assert_eq!(link.fec_overhead(), 10.0);
+
+        // same values as used by encoder and decoder
+        let oti = object_transmission_information(1500, 60000);
+        assert_eq!(oti.transfer_length(), link.encoding_block_size);
+        assert_eq!(nb_encoding_packets(&oti), link.nb_encoding_packets);
+
+        // no repair packet is allowed if explicitly requested
+        assert_eq!(
+            LinkParameters::new(1500, 60000, 0)
+                .unwrap()
+                .nb_repair_packets,
+            0
+        );
+        assert!(LinkParameters::new(MIN_MTU, 60000, 0).is_ok());
+    }
+
+    #[test]
+    fn test_link_parameters_errors() {
+        // headers do not fit
+        assert!(LinkParameters::new(MIN_MTU - 1, 60000, 6000).is_err());
+        assert!(LinkParameters::new(0, 60000, 6000).is_err());
+        // block smaller than a packet
+        assert!(LinkParameters::new(1500, 1000, 6000).is_err());
+        // repair block rounded down to no packet
+        assert!(LinkParameters::new(1500, 60000, 1000).is_err());
+        // too many source packets for RaptorQ
+        assert!(LinkParameters::new(MIN_MTU, 1_000_000, 0).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! State of diode-receive exposed on the control socket
+
+use crossbeam_channel::Receiver;
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::control::{self, Control, Sessions, Totals};
+use crate::receive::{packet::Packet, reorder::ReorderStatus, ReceiverBlock};
+
+pub struct ReceiverControl {
+    /// current TCP session to diode-receive-file
+    sessions: Sessions,
+    /// abort of current session requested
+    abort: AtomicBool,
+    /// last heartbeat received
+    last_heartbeat: Mutex<Option<Instant>>,
+    /// epoch of diode-send, from the init message (0 if unknown)
+    epoch: AtomicU64,
+    /// last known state of the reorder window
+    reorder: Mutex<Option<ReorderStatus>>,
+    for_reorder: Receiver<Packet>,
+    for_send: Receiver<ReceiverBlock>,
+}
+
+impl ReceiverControl {
+    pub(crate) fn new(for_reorder: Receiver<Packet>, for_send: Receiver<ReceiverBlock>) -> Self {
+        Self {
+            sessions: Sessions::default(),
+            abort: AtomicBool::new(false),
+            last_heartbeat: Mutex::new(None),
+            epoch: AtomicU64::new(0),
+            reorder: Mutex::new(None),
+            for_reorder,
+            for_send,
+        }
+    }
+
+    pub(crate) fn start_session(&self, id: u8) {
+        self.abort.store(false, Ordering::Relaxed);
+        self.sessions.start(id);
+    }
+
+    pub(crate) fn set_bytes(&self, bytes: usize) {
+        self.sessions.set_bytes(bytes);
+    }
+
+    pub(crate) fn end_session(&self) {
+        self.sessions.end();
+    }
+
+    /// return true (once) if current session must be aborted
+    pub(crate) fn take_abort(&self) -> bool {
+        self.abort.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn heartbeat(&self) {
+        if let Ok(mut last_heartbeat) = self.last_heartbeat.lock() {
+            *last_heartbeat = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn set_epoch(&self, epoch: u64) {
+        let previous = self.epoch.swap(epoch, Ordering::Relaxed);
+        if previous != epoch {
+            log::info!("diode-send epoch is {epoch}");
+        }
+    }
+
+    pub(crate) fn epoch(&self) -> Option<u64> {
+        match self.epoch.load(Ordering::Relaxed) {
+            0 => None,
+            epoch => Some(epoch),
+        }
+    }
+
+    pub(crate) fn set_reorder(&self, status: ReorderStatus) {
+        if let Ok(mut reorder) = self.reorder.lock() {
+            *reorder = Some(status);
+        }
+    }
+}
+
+impl Control for ReceiverControl {
+    fn status(&self) -> Value {
+        let last_heartbeat = self.last_heartbeat.lock().ok().and_then(|last| *last);
+        let reorder = self.reorder.lock().ok().and_then(|reorder| *reorder);
+
+        json!({
+            "session": self.sessions.current(),
+            "totals": self.sessions.totals(),
+            "reorder": reorder,
+            "queues": {
+                "reorder": self.for_reorder.len(),
+                "tcp_send": self.for_send.len(),
+            },
+            "last_heartbeat_ms": control::elapsed_ms(last_heartbeat),
+            "sender_epoch": self.epoch(),
+        })
+    }
+
+    fn abort(&self) -> Result<()> {
+        match self.sessions.current() {
+            None => Err(Error::new(ErrorKind::NotFound, "no active session")),
+            Some(_) => {
+                self.abort.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    fn set_rate(&self, _max_bandwidth: Option<f64>) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "rate limit can only be changed on diode-send",
+        ))
+    }
+
+    fn totals(&self) -> Totals {
+        self.sessions.totals()
+    }
+}
@@ -0,0 +1,82 @@
+//! Kernel statistics of UDP sockets, read from `/proc/net/udp` and `/proc/net/udp6`
+//!
+//! Sockets are identified by their inode, see [crate::udp::Udp::inode].
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result};
+
+const PROC_FILES: &[&str] = &["/proc/net/udp", "/proc/net/udp6"];
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SocketStats {
+    /// bytes waiting in the receive buffer
+    pub rx_queue: u64,
+    /// datagrams dropped by the kernel, mostly because the receive buffer was full
+    pub drops: u64,
+}
+
+/// statistics of all UDP sockets of the host, by inode
+pub fn read() -> Result<HashMap<u64, SocketStats>> {
+    let mut stats = HashMap::new();
+
+    for path in PROC_FILES {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => stats.extend(contents.lines().skip(1).filter_map(parse_line)),
+            // IPv6 disabled
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(stats)
+}
+
+// sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ref
+// pointer drops
+fn parse_line(line: &str) -> Option<(u64, SocketStats)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 13 {
+        return None;
+    }
+
+    let (_, rx_queue) = fields[4].split_once(':')?;
+    let rx_queue = u64::from_str_radix(rx_queue, 16).ok()?;
+    let inode = fields[9].parse().ok()?;
+    let drops = fields[12].parse().ok()?;
+
+    Some((inode, SocketStats { rx_queue, drops }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, SocketStats};
+
+    #[test]
+    fn test_parse_line() {
+        let line = " 1205: 0100007F:1388 00000000:0000 07 00000000:0003A980 00:00000000 00000000  1000        0 4267431 2 ffff8f6c4a2b8880 1742";
+        assert_eq!(
+            parse_line(line),
+            Some((
+                4267431,
+                SocketStats {
+                    rx_queue: 240_000,
+                    drops: 1742
+                }
+            ))
+        );
+
+        let line6 = "  127: 00000000000000000000000001000000:1389 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 5123 2 ffff8f6c4a2b9100 0";
+        assert_eq!(
+            parse_line(line6),
+            Some((
+                5123,
+                SocketStats {
+                    rx_queue: 0,
+                    drops: 0
+                }
+            ))
+        );
+
+        assert_eq!(parse_line("   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops"), None);
+    }
+}
@@ -29,8 +29,9 @@ use metrics::gauge;
 use metrics::{counter, histogram};
 use packet::Packet;
 
+use crate::audit::{AuditLog, EndReason};
 use crate::config::DiodeConfig;
-use crate::config::MAX_MTU;
+use crate::config::{DEFAULT_SHUTDOWN_TIMEOUT, MAX_MTU};
 use crate::protocol::LidiParameters;
 use crate::protocol::{Header, MessageType};
 use crate::receive::decoding::Decoding;
@@ -38,7 +39,9 @@ use crate::{protocol, receive::reorder::Reorder};
 use raptorq::{EncodingPacket, ObjectTransmissionInformation};
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{
     io::{Error, ErrorKind, Result},
     net::{self, SocketAddr, TcpStream},
@@ -47,20 +50,31 @@ use std::{
 
 use crate::receive::tcp::Tcp;
 
+mod control;
 pub mod decoding;
 mod heartbeat;
+mod kernel_stats;
 mod packet;
 mod reorder;
 mod tcp;
 
+use crate::systemd::{Beat, Liveness};
 use crate::udp::Udp;
+use control::ReceiverControl;
 use heartbeat::HeartBeat;
 
+/// maximum time to wait for a block before checking if current session must be aborted
+const ABORT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// interval to check if diode-receive must stop
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct ReceiverBlock {
     flags: MessageType,
     session_id: u8,
     block_id: u8,
     block: Option<Vec<u8>>,
+    /// made up by reorder for a session dropped before its last block
+    expired: bool,
 }
 
 /// An instance of this data structure is shared by workers to synchronize them and to access
@@ -76,6 +90,11 @@ pub struct ReceiverConfig {
     pub heartbeat_interval: Duration,
     pub session_expiration_timeout: Duration,
     pub core_affinity: Option<Vec<usize>>,
+    pub control_socket: Option<String>,
+    pub audit_log: Option<String>,
+    /// set to flush pending blocks and stop
+    pub shutdown: Arc<AtomicBool>,
+    pub shutdown_timeout: Duration,
 
     pub object_transmission_info: ObjectTransmissionInformation,
     pub to_buffer_size: usize,
@@ -150,6 +169,12 @@ impl TryFrom<DiodeConfig> for ReceiverConfig {
                                 .unwrap_or(config.heartbeat) as _,
                         ),
                         core_affinity: config_receiver.core_affinity,
+                        control_socket: config_receiver.control_socket,
+                        audit_log: config_receiver.audit_log,
+                        shutdown: Arc::new(AtomicBool::new(false)),
+                        shutdown_timeout: Duration::from_millis(
+                            config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT) as _,
+                        ),
                         // computed
                         object_transmission_info,
                         to_buffer_size,
@@ -171,8 +196,9 @@ impl TryFrom<DiodeConfig> for ReceiverConfig {
 }
 
 impl ReceiverConfig {
-    pub fn start(&self) -> Result<()> {
-        let mut threads = vec![];
+    pub fn start(self) -> Result<()> {
+        // threads of the data pipeline, stopped one after the other when shutting down
+        let mut pipeline = vec![];
 
         log::debug!("client socket buffer size is {} bytes", self.to_buffer_size);
 
@@ -212,8 +238,23 @@ impl ReceiverConfig {
             nb_threads as u8,
         );
 
+        // worker threads monitored by systemd watchdog
+        let liveness = Arc::new(Liveness::default());
+
+        let control = Arc::new(ReceiverControl::new(
+            self.for_reorder.clone(),
+            self.for_send.clone(),
+        ));
+
+        if let Some(path) = &self.control_socket {
+            crate::control::start(path, control.clone())?;
+        }
+
         let core_list = self.core_affinity.clone();
         let port_list_len = self.udp_port_list.len();
+        let decode_control = control.clone();
+        let decode_beat = liveness.register("reorder_decode");
+        let shutdown = self.shutdown.clone();
         let rx_decode = thread::Builder::new()
             .name("lidi_rx_reorder_decode".to_string())
             .spawn(move || {
@@ -238,11 +279,25 @@ impl ReceiverConfig {
                     session_expiration_timeout,
                     block_expiration_timeout,
                     parameters,
+                    decode_control,
+                    shutdown,
+                    decode_beat,
                 )
             })?;
-        threads.push(rx_decode);
+        pipeline.push(rx_decode);
+
+        let audit = match &self.audit_log {
+            Some(path) => Some(Arc::new(AuditLog::open(
+                path,
+                "receive",
+                &self.from_udp.to_string(),
+            )?)),
+            None => None,
+        };
 
         let core_list = self.core_affinity.clone();
+        let tcp_control = control.clone();
+        let tcp_beat = liveness.register("tcp");
         let rx_tcp = thread::Builder::new()
             .name("lidi_rx_tcp".to_string())
             .spawn(move || {
@@ -257,30 +312,53 @@ impl ReceiverConfig {
                     }
                 }
 
-                ReceiverConfig::tcp_send_loop(for_send, tcp_to, tcp_buffer_size);
+                ReceiverConfig::tcp_send_loop(
+                    for_send,
+                    tcp_to,
+                    tcp_buffer_size,
+                    tcp_control,
+                    audit,
+                    tcp_beat,
+                );
             })?;
-        threads.push(rx_tcp);
-
-        let for_reorder = self.for_reorder.clone();
-        let for_send = self.for_send.clone();
-        let metrics = thread::Builder::new()
-            .name("lidi_rx_metrics".to_string())
-            .spawn(move || ReceiverConfig::metrics_loop(for_reorder, for_send))?;
-        threads.push(metrics);
+        pipeline.push(rx_tcp);
 
         let from_udp = self.from_udp;
         let udp_mtu = self.from_udp_mtu;
         let block_size = self.encoding_block_size + u64::from(self.repair_block_size);
 
-        for i in 0..nb_threads {
+        let mut sockets = Vec::with_capacity(nb_threads);
+        let mut inodes = Vec::with_capacity(nb_threads);
+        for port in &self.udp_port_list {
+            let bind_udp = SocketAddr::new(from_udp, *port);
+            let udp = Udp::new(bind_udp, None, udp_mtu, block_size, "")?;
+
+            match (udp.inode(), udp.recv_buffer_size()) {
+                (Ok(inode), Ok(buffer_size)) => {
+                    gauge!("rx_udp_kernel_buffer_bytes", "port" => port.to_string())
+                        .set(buffer_size as f64);
+                    inodes.push((*port, inode));
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    log::warn!("udp: no kernel statistics for port {port}: {e}");
+                }
+            }
+            sockets.push(udp);
+        }
+
+        // metrics and udp threads run until the end of the process
+        let for_reorder = self.for_reorder.clone();
+        let for_send = self.for_send.clone();
+        thread::Builder::new()
+            .name("lidi_rx_metrics".to_string())
+            .spawn(move || ReceiverConfig::metrics_loop(for_reorder, for_send, inodes))?;
+
+        for (i, udp) in sockets.into_iter().enumerate() {
             let sender = self.to_reorder.clone();
             let port_list = self.udp_port_list.clone();
             let core_list = self.core_affinity.clone();
 
-            let bind_udp = SocketAddr::new(from_udp, port_list[i]);
-            let udp = Udp::new(bind_udp, None, udp_mtu, block_size, "")?;
-
-            let rx_udp = thread::Builder::new()
+            thread::Builder::new()
                 .name(format!("lidi_rx_udp_{i}"))
                 .spawn(move || {
                     if let Some(core_affinity) = core_list {
@@ -292,22 +370,45 @@ impl ReceiverConfig {
                         }
                     }
 
-                    ReceiverConfig::udp_read_loop(&sender, udp);
+                    ReceiverConfig::udp_read_loop(&sender, udp, port_list[i]);
                 })?;
-            threads.push(rx_udp);
         }
 
-        for thread in threads {
-            if let Err(e) = thread.join() {
-                log::warn!("Cannot join thread: {e:?}");
-            }
+        // from now on, only the reorder/decode thread keeps the tcp queue open: tcp thread stops
+        // once all decoded blocks are sent
+        drop(self.to_send);
+
+        // all sockets are bound
+        crate::systemd::start(control, liveness, self.shutdown.clone())?;
+
+        while !self.shutdown.load(Ordering::Relaxed) {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
         }
 
+        log::info!(
+            "waiting up to {} ms to flush pending blocks",
+            self.shutdown_timeout.as_millis()
+        );
+
+        if !crate::join_until(&mut pipeline, Instant::now() + self.shutdown_timeout) {
+            log::warn!("pending blocks not flushed in time: they are lost");
+        }
+
+        log::info!("diode-receive stopped");
+
         Ok(())
     }
 
-    fn tcp_connect(tcp_to: net::SocketAddr, tcp_buffer_size: usize) -> Tcp {
+    fn tcp_connect(
+        tcp_to: net::SocketAddr,
+        tcp_buffer_size: usize,
+        session_id: u8,
+        control: &ReceiverControl,
+        audit: &Option<Arc<AuditLog>>,
+        beat: &Beat,
+    ) -> Tcp {
         loop {
+            beat.beat();
             log::info!("tcp: connecting to {tcp_to}");
             // initialize tcp session properly
             // connect only when a new block has to be sent
@@ -318,35 +419,104 @@ impl ReceiverConfig {
                 );
 
                 // initialize tcp session properly
-                return Tcp::new(client, tcp_buffer_size);
+                return Tcp::new(
+                    client,
+                    tcp_buffer_size,
+                    session_id,
+                    control.epoch(),
+                    audit.clone(),
+                );
             } else {
                 std::thread::sleep(Duration::from_millis(100));
             }
         }
     }
 
-    fn metrics_loop(for_reorder: Receiver<Packet>, for_send: Receiver<ReceiverBlock>) {
+    // `inodes` identify udp sockets of each port in kernel statistics
+    fn metrics_loop(
+        for_reorder: Receiver<Packet>,
+        for_send: Receiver<ReceiverBlock>,
+        inodes: Vec<(u16, u64)>,
+    ) {
+        let sockets: Vec<_> = inodes
+            .into_iter()
+            .map(|(port, inode)| {
+                let port = port.to_string();
+                let drops = counter!("rx_udp_kernel_drops", "port" => port.clone());
+                let queue = gauge!("rx_udp_kernel_queue_bytes", "port" => port);
+                (inode, drops, queue)
+            })
+            .collect();
+        let mut kernel_stats_err = false;
+
         loop {
             std::thread::sleep(std::time::Duration::from_secs(1));
-            gauge!("rx_udp_send_queue_len").set(for_send.len() as f64);
-            gauge!("rx_udp_reorder_queue_len").set(for_reorder.len() as f64);
+            ReceiverConfig::queue_gauges(&for_reorder, &for_send);
+
+            if sockets.is_empty() {
+                continue;
+            }
+
+            match kernel_stats::read() {
+                Ok(stats) => {
+                    for (inode, drops, queue) in &sockets {
+                        if let Some(stats) = stats.get(inode) {
+                            drops.absolute(stats.drops);
+                            queue.set(stats.rx_queue as f64);
+                        }
+                    }
+                }
+                Err(e) => {
+                    // do not flood logs every second
+                    if !kernel_stats_err {
+                        log::warn!("udp: can't read kernel statistics: {e}");
+                        kernel_stats_err = true;
+                    }
+                }
+            }
         }
     }
 
+    // number of packets and blocks waiting between threads
+    fn queue_gauges(for_reorder: &Receiver<Packet>, for_send: &Receiver<ReceiverBlock>) {
+        gauge!("rx_udp_send_queue_len").set(for_send.len() as f64);
+        gauge!("rx_udp_reorder_queue_len").set(for_reorder.len() as f64);
+    }
+
     // entry point of send tcp thread
     // this loop runs over sessions (tcp connections)
     fn tcp_send_loop(
         for_send: Receiver<ReceiverBlock>,
         tcp_to: net::SocketAddr,
         tcp_buffer_size: usize,
+        control: Arc<ReceiverControl>,
+        audit: Option<Arc<AuditLog>>,
+        beat: Beat,
     ) {
-        let mut current_tcp = None;
+        let mut current_tcp: Option<Tcp> = None;
         // if there is a block to send at start
         loop {
-            let block = match for_send.recv() {
-                Err(e) => {
-                    log::warn!("Unable to read block: {e}");
-                    continue;
+            beat.beat();
+
+            // abort requested on control socket: reset connection, next blocks of this session
+            // will be dropped
+            if control.take_abort() {
+                if let Some(tcp) = current_tcp.take() {
+                    tcp.abort();
+                }
+                control.end_session();
+            }
+
+            let block = match for_send.recv_timeout(ABORT_CHECK_INTERVAL) {
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    // diode-receive is stopping and all blocks are sent
+                    if let Some(tcp) = current_tcp.take() {
+                        log::warn!("tcp: stopping before the end of current session");
+                        tcp.abort();
+                    }
+                    control.end_session();
+                    return;
                 }
                 Ok(block) => {
                     log::debug!(
@@ -359,9 +529,42 @@ impl ReceiverConfig {
                 }
             };
 
+            // session expired in reorder: close it, unless a newer session already replaced it
+            if block.expired {
+                match &current_tcp {
+                    Some(tcp) if tcp.session_id() == block.session_id => {
+                        log::warn!(
+                            "tcp: session {} expired before its last block",
+                            block.session_id
+                        );
+                        if let Some(tcp) = current_tcp.take() {
+                            tcp.close_incomplete(EndReason::Expired);
+                        }
+                        control.end_session();
+                    }
+                    _ => debug!(
+                        "tcp: drop expiration of session {}, not the current one",
+                        block.session_id
+                    ),
+                }
+                continue;
+            }
+
             // get tcp session to use
             let tcp = if block.flags.contains(MessageType::Start) {
-                current_tcp = Some(Self::tcp_connect(tcp_to, tcp_buffer_size));
+                // previous session never received its last block
+                if let Some(tcp) = current_tcp.take() {
+                    tcp.close_incomplete(EndReason::Expired);
+                }
+                current_tcp = Some(Self::tcp_connect(
+                    tcp_to,
+                    tcp_buffer_size,
+                    block.session_id,
+                    &control,
+                    &audit,
+                    &beat,
+                ));
+                control.start_session(block.session_id);
                 current_tcp.as_mut().unwrap()
             } else if let Some(tcp) = &mut current_tcp {
                 tcp
@@ -374,6 +577,18 @@ impl ReceiverConfig {
                 continue;
             };
 
+            tcp.check_block(block.block_id);
+
+            // session aborted on diode-send side
+            if block.flags.contains(MessageType::Abort) {
+                log::warn!("tcp: session {} aborted by diode-send", block.session_id);
+                if let Some(tcp) = current_tcp.take() {
+                    tcp.abort();
+                }
+                control.end_session();
+                continue;
+            }
+
             // send this block
             log::debug!(
                 "send block: session {} block {} flags {}",
@@ -383,15 +598,24 @@ impl ReceiverConfig {
             );
             let data = match block.block {
                 None => {
-                    // too bad, first block is not correct
+                    // too bad, block is not correct
                     log::warn!(
-                    "tcp: session {} lost first block {} flags {}: session is corrupted, skip this session and wait for the next",
-                    block.session_id,
-                    block.block_id,
-                    block.flags
-                );
+                        "tcp: session {} lost block {} flags {}: session is corrupted",
+                        block.session_id,
+                        block.block_id,
+                        block.flags
+                    );
                     // we drop this block
                     counter!("rx_skip_block").increment(1);
+                    tcp.lost_block();
+
+                    // last block lost: close session
+                    if block.flags.contains(MessageType::End) {
+                        if let Some(tcp) = current_tcp.take() {
+                            tcp.close_incomplete(EndReason::Expired);
+                        }
+                        control.end_session();
+                    }
                     continue;
                 }
 
@@ -401,18 +625,27 @@ impl ReceiverConfig {
             // everything ok, send this block
             if let Err(e) = ReceiverConfig::tcp_send(tcp, block.block_id, block.flags, &data) {
                 log::warn!("can't send block => reset tcp: {e}");
-                current_tcp = None;
+                if let Some(tcp) = current_tcp.take() {
+                    tcp.close_incomplete(EndReason::Error);
+                }
+                control.end_session();
                 continue;
             }
+            control.set_bytes(tcp.transmitted());
 
-            // if last block, close tcp session
+            // if last block, check digest and close tcp session
             if block.flags.contains(MessageType::End) {
-                if let Err(e) = tcp.flush() {
-                    log::warn!("tcp: cant flush final data: {e}");
-                }
+                let expected = if block.flags.contains(MessageType::Digest) {
+                    protocol::block_digest(&data)
+                } else {
+                    None
+                };
                 // last block : quit to reconnect
                 log::debug!("disconnect to force reconnect");
-                current_tcp = None;
+                if let Some(tcp) = current_tcp.take() {
+                    tcp.finish(expected);
+                }
+                control.end_session();
                 continue;
             }
         }
@@ -445,6 +678,7 @@ impl ReceiverConfig {
     // entry point of decode & send tcp thread
     // this loop runs over sessions (tcp connections)
     // we do not pop packets from rx if tcp session to diode-receive-file is not setup
+    #[allow(clippy::too_many_arguments)]
     fn reorder_decoding_loop(
         for_reorder: Receiver<Packet>,
         to_send: Sender<ReceiverBlock>,
@@ -453,6 +687,9 @@ impl ReceiverConfig {
         session_expiration_timeout: Duration,
         block_expiration_timeout: Duration, // config.block_expiration_timeout
         parameters: LidiParameters,
+        control: Arc<ReceiverControl>,
+        shutdown: Arc<AtomicBool>,
+        beat: Beat,
     ) {
         let nb_normal_packets = protocol::nb_encoding_packets(&object_transmission_info);
         let nb_repair_packets =
@@ -474,7 +711,35 @@ impl ReceiverConfig {
         // if we received init - if not, we will initialize reorder with first block received
         let mut reorder_initialized = false;
 
+        // when stopping, number of packets still to process before flushing reorder
+        let mut stopping: Option<usize> = None;
+
         loop {
+            beat.beat();
+
+            // sessions dropped by reorder before their last block: tcp thread must close them
+            for (session_id, block_id) in reorder.take_expired() {
+                let block = ReceiverBlock {
+                    flags: MessageType::End,
+                    session_id,
+                    block_id,
+                    block: None,
+                    expired: true,
+                };
+                if let Err(e) = to_send.send(block) {
+                    log::warn!("can't send expired session to tcp: {e}");
+                }
+            }
+
+            if stopping.is_none() && shutdown.load(Ordering::Relaxed) {
+                stopping = Some(for_reorder.len());
+            }
+
+            if stopping == Some(0) && !test_pop_first {
+                Self::flush(&mut reorder, &decoding, &to_send);
+                return;
+            }
+
             let (flags, session_id, block_id, encoded_packets) = if test_pop_first {
                 // try to get as many finised queues as we can
                 if let Some(ret) = reorder.pop_first() {
@@ -489,6 +754,10 @@ impl ReceiverConfig {
 
                 match for_reorder.recv_timeout(reorder.block_expiration_timeout()) {
                     Ok(packet) => {
+                        if let Some(remaining) = stopping.as_mut() {
+                            *remaining = remaining.saturating_sub(1);
+                        }
+
                         let header = packet.header();
                         let payload = packet.payload();
                         // if first packet of a new sender instance: flush everything
@@ -499,6 +768,10 @@ impl ReceiverConfig {
 
                             /* check init parameters */
 
+                            if let Some(epoch) = protocol::deserialize_epoch(payload) {
+                                control.set_epoch(epoch);
+                            }
+
                             match LidiParameters::deserialize(payload) {
                                 Err(e) => {
                                     log::warn!("Unable to deserialize init message parameters from diode-send: {e}");
@@ -517,16 +790,19 @@ impl ReceiverConfig {
                         } else if header.message_type().contains(MessageType::Heartbeat) {
                             log::debug!("Heartbeat message received from diode-send");
                             heartbeat.update();
+                            control.heartbeat();
+
+                            // heartbeats may prevent recv timeout: check if a block has expired
+                            test_pop_first = true;
+                            continue;
                         }
 
                         if payload.is_empty() {
+                            test_pop_first = true;
                             continue;
                         }
 
                         // this is a data packet
-                        counter!("rx_udp_pkts").increment(1);
-                        counter!("rx_udp_bytes").increment(payload.len() as _);
-
                         if !reorder_initialized {
                             reorder.init(header);
                             reorder_initialized = true;
@@ -543,6 +819,8 @@ impl ReceiverConfig {
                             }
                             Some(packets) => {
                                 counter!("rx_pop_ok_packets").increment(1);
+                                // following blocks may already be complete (encoded out of order)
+                                test_pop_first = true;
                                 packets
                             }
                         }
@@ -562,6 +840,8 @@ impl ReceiverConfig {
                 }
             };
 
+            control.set_reorder(reorder.status());
+
             let block = Self::decode(&decoding, flags, block_id, session_id, encoded_packets);
             if let Err(e) = to_send.try_send(block) {
                 counter!("rx_send_block_err").increment(1);
@@ -577,6 +857,19 @@ impl ReceiverConfig {
         }
     }
 
+    // diode-receive is stopping: decode all stored blocks, complete or not, and send them to tcp
+    fn flush(reorder: &mut Reorder, decoding: &Decoding, to_send: &Sender<ReceiverBlock>) {
+        let blocks = reorder.flush();
+        log::info!("reorder: flushing {} pending blocks", blocks.len());
+
+        for (flags, session_id, block_id, encoded_packets) in blocks {
+            let block = Self::decode(decoding, flags, block_id, session_id, encoded_packets);
+            if let Err(e) = to_send.send(block) {
+                log::warn!("can't send block to tcp: {e}");
+            }
+        }
+    }
+
     // try to decode a block from a list of packets.
     // return true if we should continue (session still running), false if we should stop processing because of an error
     fn decode(
@@ -632,41 +925,312 @@ impl ReceiverConfig {
             session_id,
             block_id,
             block,
+            expired: false,
         }
     }
 
     // loop of in rx threads
-    fn udp_read_loop(output: &Sender<Packet>, mut udp: Udp) {
+    fn udp_read_loop(output: &Sender<Packet>, mut udp: Udp, port: u16) {
+        let counters = UdpCounters::new(port);
+
         loop {
             // how to not init this without ub & unsafe ? use shared memory ?
             let mut buf: [u8; MAX_MTU] = [0; MAX_MTU];
             match udp.recv(&mut buf) {
-                Ok(len) => {
-                    if let Ok(header) = Header::deserialize(&buf) {
-                        let pkt = Packet::new(buf, len, header);
-                        if let Err(e) = output.try_send(pkt) {
-                            counter!("rx_udp_send_reorder_err").increment(1);
-                            match e {
-                                crossbeam_channel::TrySendError::Disconnected(_) => {
-                                    log::warn!(
-                                        "udp: Can't send packet to reorder: queue disconnected"
-                                    )
-                                }
-                                crossbeam_channel::TrySendError::Full(_) => {
-                                    log::debug!("udp: Can't send packet to reorder: queue full")
-                                }
-                            }
-                        }
-                    } else {
-                        log::warn!("udp: Can't deserialize header");
-                        counter!("rx_udp_deserialize_header_err").increment(1);
-                    }
-                }
+                Ok(len) => ReceiverConfig::udp_forward(output, buf, len, &counters),
                 Err(e) => {
                     log::debug!("udp: udp : can't read socket: {e}");
-                    counter!("rx_udp_recv_pkts_err").increment(1);
+                    counters.recv_pkts_err.increment(1);
+                }
+            }
+        }
+    }
+
+    // push a packet read from udp socket to reorder/decode queue
+    fn udp_forward(
+        output: &Sender<Packet>,
+        buf: [u8; MAX_MTU],
+        len: usize,
+        counters: &UdpCounters,
+    ) {
+        let Ok(header) = Header::deserialize(&buf) else {
+            log::warn!("udp: Can't deserialize header");
+            counters.deserialize_header_err.increment(1);
+            return;
+        };
+        let pkt = Packet::new(buf, len, header);
+
+        // count data packets only, not init and heartbeat messages
+        let data_len = (!pkt.payload().is_empty()
+            && !header
+                .message_type()
+                .intersects(MessageType::Init | MessageType::Heartbeat))
+        .then(|| pkt.payload().len());
+
+        match output.try_send(pkt) {
+            Ok(()) => {
+                if let Some(data_len) = data_len {
+                    counters.pkts.increment(1);
+                    counters.bytes.increment(data_len as u64);
                 }
             }
+            Err(e) => {
+                counters.send_reorder_err.increment(1);
+                match e {
+                    crossbeam_channel::TrySendError::Disconnected(_) => {
+                        log::warn!("udp: Can't send packet to reorder: queue disconnected")
+                    }
+                    crossbeam_channel::TrySendError::Full(_) => {
+                        log::debug!("udp: Can't send packet to reorder: queue full")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Counters of a listening udp port
+struct UdpCounters {
+    pkts: metrics::Counter,
+    bytes: metrics::Counter,
+    send_reorder_err: metrics::Counter,
+    deserialize_header_err: metrics::Counter,
+    recv_pkts_err: metrics::Counter,
+}
+
+impl UdpCounters {
+    fn new(port: u16) -> Self {
+        let port = port.to_string();
+        Self {
+            pkts: counter!("rx_udp_pkts", "port" => port.clone()),
+            bytes: counter!("rx_udp_bytes", "port" => port.clone()),
+            send_reorder_err: counter!("rx_udp_send_reorder_err", "port" => port.clone()),
+            deserialize_header_err: counter!("rx_udp_deserialize_header_err", "port" => port.clone()),
+            recv_pkts_err: counter!("rx_udp_recv_pkts_err", "port" => port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::{thread, time};
+
+    use crate::config::MAX_MTU;
+    use crate::protocol::{self, Header, LidiParameters, MessageType};
+    use crate::send::encoding::Encoding;
+    use crate::systemd::Liveness;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::{Packet, ReceiverBlock, ReceiverConfig, ReceiverControl, UdpCounters};
+
+    const MTU: u16 = 1500;
+    const BLOCK_SIZE: u64 = 10_000;
+    // long enough never to be reached: blocks must not wait for expiration
+    const EXPIRATION: Duration = Duration::from_secs(60);
+
+    // udp packets of a block, as sent by diode-send
+    fn packets(
+        encoding: &Encoding,
+        flags: MessageType,
+        session: u8,
+        block: u8,
+        data: Vec<u8>,
+    ) -> Vec<Packet> {
+        let mut header = Header::new(flags, session, block);
+        encoding
+            .encode(data, block)
+            .into_iter()
+            .map(|packet| {
+                let payload = packet.serialize();
+                let mut buf = [0; MAX_MTU];
+                buf[0..4].copy_from_slice(&header.serialized());
+                buf[4..4 + payload.len()].copy_from_slice(&payload);
+                let packet = Packet::new(buf, 4 + payload.len(), header);
+                header.incr_seq();
+                packet
+            })
+            .collect()
+    }
+
+    // run the reorder/decode loop on `packets`, returns the first `count` decoded blocks
+    fn reorder_decode(packets: Vec<Packet>, count: usize) -> Vec<ReceiverBlock> {
+        let oti = protocol::object_transmission_information(MTU, BLOCK_SIZE);
+        let (to_reorder, for_reorder) = crossbeam_channel::unbounded();
+        let (to_send, for_send) = crossbeam_channel::unbounded();
+        let control = Arc::new(ReceiverControl::new(for_reorder.clone(), for_send.clone()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let beat = Liveness::default().register("reorder_decode");
+        let parameters = LidiParameters::new(BLOCK_SIZE, 0, EXPIRATION, MTU, 1);
+
+        let stop = shutdown.clone();
+        let reorder_decode = thread::spawn(move || {
+            ReceiverConfig::reorder_decoding_loop(
+                for_reorder,
+                to_send,
+                oti,
+                0,
+                EXPIRATION,
+                EXPIRATION,
+                parameters,
+                control,
+                stop,
+                beat,
+            )
+        });
+
+        for packet in packets {
+            to_reorder.send(packet).unwrap();
+        }
+
+        let blocks = (0..count)
+            .map_while(|_| for_send.recv_timeout(time::Duration::from_secs(5)).ok())
+            .collect();
+
+        shutdown.store(true, Ordering::Relaxed);
+        drop(to_reorder);
+        reorder_decode.join().unwrap();
+
+        blocks
+    }
+
+    #[test]
+    fn test_blocks_encoded_out_of_order() {
+        let oti = protocol::object_transmission_information(MTU, BLOCK_SIZE);
+        let encoding = Encoding::new(oti, 0);
+        let data = |i: u8| vec![i; oti.transfer_length() as usize];
+
+        // encoders finish blocks 2 and 1 before block 0
+        let mut udp = packets(&encoding, MessageType::End, 0, 2, data(2));
+        udp.extend(packets(&encoding, MessageType::Data, 0, 1, data(1)));
+        udp.extend(packets(
+            &encoding,
+            MessageType::Start | MessageType::Data,
+            0,
+            0,
+            data(0),
+        ));
+
+        // following blocks are sent as soon as block 0 is decoded, not after expiration
+        let blocks = reorder_decode(udp, 3);
+        let received: Vec<_> = blocks
+            .iter()
+            .map(|block| (block.block_id, block.block.clone()))
+            .collect();
+        assert_eq!(
+            received,
+            vec![(0, Some(data(0))), (1, Some(data(1))), (2, Some(data(2)))]
+        );
+    }
+
+    // block as decoded, with its length prefix
+    fn block(flags: MessageType, session: u8, block: u8, data: &[u8]) -> ReceiverBlock {
+        let mut payload = (data.len() as u32).to_be_bytes().to_vec();
+        payload.extend(data);
+        ReceiverBlock {
+            flags,
+            session_id: session,
+            block_id: block,
+            block: Some(payload),
+            expired: false,
+        }
+    }
+
+    fn expired(session: u8, block: u8) -> ReceiverBlock {
+        ReceiverBlock {
+            flags: MessageType::End,
+            session_id: session,
+            block_id: block,
+            block: None,
+            expired: true,
+        }
+    }
+
+    #[test]
+    fn test_expired_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_to = listener.local_addr().unwrap();
+        let (to_send, for_send) = crossbeam_channel::unbounded();
+        let control = Arc::new(ReceiverControl::new(
+            crossbeam_channel::unbounded().1,
+            for_send.clone(),
+        ));
+        let beat = Liveness::default().register("tcp");
+
+        let blocks = [
+            block(MessageType::Start | MessageType::Data, 0, 0, b"a"),
+            // session 1 starts before reorder drops session 0
+            block(MessageType::Start | MessageType::Data, 1, 0, b"b"),
+            expired(0, 1),
+            block(MessageType::End, 1, 1, b"c"),
+            // current session dropped by reorder
+            block(MessageType::Start | MessageType::Data, 2, 0, b"d"),
+            expired(2, 1),
+            block(MessageType::End, 2, 1, b"e"),
+        ];
+        for block in blocks {
+            to_send.send(block).unwrap();
+        }
+        drop(to_send);
+
+        ReceiverConfig::tcp_send_loop(for_send, tcp_to, 4096, control, None, beat);
+
+        let sessions: Vec<_> = (0..3)
+            .map(|_| {
+                let mut data = vec![];
+                listener.accept().unwrap().0.read_to_end(&mut data).unwrap();
+                data
+            })
+            .collect();
+        assert_eq!(sessions, vec![b"a".to_vec(), b"bc".to_vec(), b"d".to_vec()]);
+    }
+
+    // udp datagram carrying `len` bytes of payload
+    fn datagram(flags: MessageType, len: usize) -> [u8; MAX_MTU] {
+        let mut buf = [0; MAX_MTU];
+        buf[0..4].copy_from_slice(&Header::new(flags, 0, 0).serialized());
+        buf[4..4 + len].fill(1);
+        buf
+    }
+
+    #[test]
+    fn test_metrics() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            let (to_reorder, for_reorder) = crossbeam_channel::bounded(2);
+            let (_to_send, for_send) = crossbeam_channel::unbounded();
+            let counters = [UdpCounters::new(5000), UdpCounters::new(5001)];
+
+            let data = datagram(MessageType::Data, 100);
+            ReceiverConfig::udp_forward(&to_reorder, data, 104, &counters[0]);
+            // heartbeats are forwarded, but not counted
+            let heartbeat = datagram(MessageType::Heartbeat, 8);
+            ReceiverConfig::udp_forward(&to_reorder, heartbeat, 12, &counters[1]);
+            // queue is full: packet is not counted as received
+            ReceiverConfig::udp_forward(&to_reorder, data, 104, &counters[1]);
+
+            ReceiverConfig::queue_gauges(&for_reorder, &for_send);
+        });
+
+        let metrics = handle.render();
+        for metric in [
+            "rx_udp_pkts{port=\"5000\"} 1",
+            "rx_udp_bytes{port=\"5000\"} 100",
+            "rx_udp_send_reorder_err{port=\"5000\"} 0",
+            "rx_udp_pkts{port=\"5001\"} 0",
+            "rx_udp_bytes{port=\"5001\"} 0",
+            "rx_udp_send_reorder_err{port=\"5001\"} 1",
+            "rx_udp_reorder_queue_len 2",
+            "rx_udp_send_queue_len 0",
+        ] {
+            assert!(metrics.lines().any(|line| line == metric), "{metric}");
         }
     }
 }
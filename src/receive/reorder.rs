@@ -6,6 +6,7 @@ use std::time::{Duration, Instant};
 use log::{debug, trace, warn};
 use metrics::counter;
 use raptorq::EncodingPacket;
+use serde::Serialize;
 
 use crate::protocol::{Header, MessageType, FIRST_BLOCK_ID, FIRST_SESSION_ID};
 
@@ -291,11 +292,28 @@ impl Session {
     }
 }
 
+/// Snapshot of the reorder window, reported on the control socket
+#[derive(Clone, Copy, Serialize)]
+pub struct ReorderStatus {
+    /// session currently reassembled
+    pub session: u8,
+    /// next block to decode
+    pub current_block: u8,
+    /// latest block received (including wrap around count)
+    pub latest_block: usize,
+    /// number of blocks with packets waiting to be decoded
+    pub pending_blocks: usize,
+    /// number of packets waiting to be decoded
+    pub pending_packets: usize,
+}
+
 pub struct Reorder {
     sessions: Vec<Session>,
     // how much time should we wait before allowing force decoding (in milliseconds)
     block_expiration_timeout: Duration,
     current_session: u8,
+    /// sessions expired before their last block, with their first missing block
+    expired: Vec<(u8, u8)>,
 }
 
 impl Reorder {
@@ -321,6 +339,7 @@ impl Reorder {
             current_session: FIRST_SESSION_ID,
             block_expiration_timeout,
             sessions,
+            expired: vec![],
         }
     }
 
@@ -372,8 +391,11 @@ impl Reorder {
         match reason {
             FlushCondition::Nothing => None,
             FlushCondition::SessionExpired => {
-                let session = self.session_mut(self.current_session);
+                let session_id = self.current_session;
+                let session = self.session_mut(session_id);
+                let missing_block = session.current_block;
                 session.clear();
+                self.expired.push((session_id, missing_block));
                 self.incr_session();
                 None
             }
@@ -452,6 +474,50 @@ impl Reorder {
     pub fn block_expiration_timeout(&self) -> Duration {
         self.block_expiration_timeout
     }
+
+    /// diode-receive is stopping: return all stored blocks, complete or not, session after session
+    pub fn flush(&mut self) -> Vec<(MessageType, u8, u8, Vec<EncodingPacket>)> {
+        let mut blocks = vec![];
+
+        for _ in 0..=u8::MAX {
+            if !self.sessions.iter().any(|session| session.active) {
+                break;
+            }
+
+            let session = self.session_mut(self.current_session);
+            while session.queues.iter().any(Block::used) {
+                if session.queues[session.current_block as usize].used() {
+                    blocks.extend(session.pop_first());
+                } else {
+                    // skip lost blocks
+                    session.incr_block();
+                }
+            }
+
+            session.clear();
+            self.incr_session();
+        }
+
+        blocks
+    }
+
+    /// sessions expired since last call, with their first missing block
+    pub fn take_expired(&mut self) -> Vec<(u8, u8)> {
+        std::mem::take(&mut self.expired)
+    }
+
+    pub fn status(&self) -> ReorderStatus {
+        let session = self.session(self.current_session);
+        let pending = session.queues.iter().filter(|block| block.used());
+
+        ReorderStatus {
+            session: self.current_session,
+            current_block: session.current_block,
+            latest_block: session.latest_block,
+            pending_blocks: pending.clone().count(),
+            pending_packets: pending.map(|block| block.len()).sum(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -531,6 +597,24 @@ mod tests {
         assert!(ret.is_none());
     }
 
+    #[test]
+    fn test_session_expired() {
+        let mut reorder = Reorder::new(1, 0, ONE_HUNDRED_MS, ONE_HUNDRED_MS);
+
+        let (header, packet) = build_packet(MessageType::Start, 0, 0);
+        assert!(reorder.push(&header, packet).is_some());
+
+        // block 1 is lost
+        let (header, packet) = build_packet(MessageType::Data, 0, 2);
+        assert!(reorder.push(&header, packet).is_none());
+        assert!(reorder.take_expired().is_empty());
+
+        std::thread::sleep(2 * ONE_HUNDRED_MS);
+        assert!(reorder.pop_first().is_none());
+        assert_eq!(reorder.take_expired(), vec![(0, 1)]);
+        assert!(reorder.take_expired().is_empty());
+    }
+
     #[test]
     fn test_two_packets_succeed() {
         let mut reorder = Reorder::new(2, 0, ONE_HUNDRED_MS, FIVE_HUNDRED_MS);
@@ -962,4 +1046,30 @@ mod tests {
     // XXX TODO test multiple session (max active queue)
     // XXX TODO 10 sessions en parallèle
     // XXX TODO diode send / init
+
+    #[test]
+    fn test_flush() {
+        let mut reorder = Reorder::new(2, 0, ONE_HUNDRED_MS, FIVE_HUNDRED_MS);
+
+        // incomplete blocks, block 1 of session 0 is lost
+        for (flags, session, block) in [
+            (MessageType::Start | MessageType::Data, 0, 0),
+            (MessageType::Data, 0, 2),
+            (MessageType::Start | MessageType::End, 1, 0),
+        ] {
+            let (header, packet) = build_packet(flags, session, block);
+            assert!(reorder.push(&header, packet).is_none());
+        }
+
+        let blocks: Vec<(u8, u8)> = reorder
+            .flush()
+            .into_iter()
+            .map(|(_, session, block, _)| (session, block))
+            .collect();
+        assert_eq!(blocks, vec![(0, 0), (0, 2), (1, 0)]);
+
+        // nothing left
+        assert!(reorder.flush().is_empty());
+        assert!(reorder.pop_first().is_none());
+    }
 }
@@ -1,25 +1,55 @@
 //! Worker that writes decoded and reordered messages to client
 
-use nix::sys::socket::sockopt::SndBuf;
+use nix::sys::socket::sockopt::{Linger, SndBuf};
 use nix::sys::socket::{getsockopt, setsockopt};
 
-use metrics::counter;
+use metrics::{counter, histogram};
 
-use crate::{protocol::PAYLOAD_OVERHEAD, receive};
+use crate::audit::{to_hex, AuditLog, EndReason, SessionRecord};
+use crate::protocol::{DIGEST_SIZE, PAYLOAD_OVERHEAD};
+use crate::receive;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std::{
     io::{self, BufWriter, Write},
     net,
+    sync::Arc,
+    time::Instant,
 };
 
 pub struct Tcp {
     transmitted: usize,
+    /// start of the session
+    started: Instant,
+    /// number of blocks sent to client
+    blocks: u64,
+    /// number of blocks of the session which could not be decoded or never arrived
+    lost_blocks: u64,
+    /// id of the next expected block
+    next_block: Option<u8>,
+    session_id: u8,
+    /// epoch of diode-send, if known
+    epoch: Option<u64>,
+    /// start of the session, for audit log
+    start_time: DateTime<Utc>,
+    audit: Option<Arc<AuditLog>>,
+    /// digest of data sent so far
+    digest: Sha256,
+    /// final digest, once the session is finished
+    sha256: Option<[u8; DIGEST_SIZE]>,
     // bufwriter on top of socket
     bufwriter: BufWriter<net::TcpStream>,
 }
 
 impl Tcp {
     // buffer_size: receiver.to_buffer_size
-    pub fn new(mut client: net::TcpStream, buffer_size: usize) -> Self {
+    pub fn new(
+        mut client: net::TcpStream,
+        buffer_size: usize,
+        session_id: u8,
+        epoch: Option<u64>,
+        audit: Option<Arc<AuditLog>>,
+    ) -> Self {
         log::debug!("tcp : starting transfer");
 
         if let Err(_e) = Tcp::configure(&mut client, buffer_size) {}
@@ -27,6 +57,16 @@ impl Tcp {
         let bufwriter = io::BufWriter::with_capacity(buffer_size, client);
         Self {
             transmitted: 0,
+            started: Instant::now(),
+            blocks: 0,
+            lost_blocks: 0,
+            next_block: None,
+            session_id,
+            epoch,
+            start_time: Utc::now(),
+            audit,
+            digest: Sha256::new(),
+            sha256: None,
             bufwriter,
         }
     }
@@ -54,13 +94,137 @@ impl Tcp {
         Ok(())
     }
 
-    pub fn flush(&mut self) -> Result<(), receive::Error> {
+    /// close the connection after the last block, `expected` is the digest sent by diode-send
+    ///
+    /// If data does not match the digest, the connection is reset so client knows the transfer
+    /// is corrupted.
+    pub fn finish(mut self, expected: Option<[u8; DIGEST_SIZE]>) {
+        let sha256: [u8; DIGEST_SIZE] = self.digest.finalize_reset().into();
+        self.sha256 = Some(sha256);
+
+        if expected.is_some_and(|expected| expected != sha256) {
+            log::error!(
+                "client : session {} digest mismatch, {} bytes transmitted, {} blocks lost: resetting connection",
+                self.session_id,
+                self.transmitted,
+                self.lost_blocks
+            );
+            counter!("rx_session_digest_err").increment(1);
+            let status = if self.lost_blocks == 0 {
+                "corrupted"
+            } else {
+                "lossy"
+            };
+            self.record(status, EndReason::DigestMismatch);
+            self.reset();
+            return;
+        }
+
         log::info!(
             "client : finished transfer, {} bytes transmitted",
             self.transmitted
         );
         counter!("rx_sessions").increment(1);
-        self.bufwriter.flush()
+        if self.lost_blocks == 0 {
+            self.record("complete", EndReason::End);
+        } else {
+            self.record("lossy", EndReason::End);
+        }
+
+        if let Err(e) = self.bufwriter.flush() {
+            log::warn!("tcp: cant flush final data: {e}");
+        }
+    }
+
+    /// close the connection of a session which ended without its last block (`reason` is
+    /// expired) or with a socket error
+    pub fn close_incomplete(mut self, reason: EndReason) {
+        log::warn!(
+            "client : incomplete transfer, {} bytes transmitted, {} blocks lost",
+            self.transmitted,
+            self.lost_blocks
+        );
+        self.record("lossy", reason);
+
+        if let Err(e) = self.bufwriter.flush() {
+            log::warn!("tcp: cant flush data of incomplete session: {e}");
+        }
+    }
+
+    /// count blocks missing before `block_id`
+    pub fn check_block(&mut self, block_id: u8) {
+        if let Some(next_block) = self.next_block {
+            let missing = block_id.wrapping_sub(next_block);
+            if missing > 0 {
+                log::warn!("tcp: blocks {next_block} to {block_id} (excluded) were lost");
+                self.lost_blocks += u64::from(missing);
+            }
+        }
+        self.next_block = Some(block_id.wrapping_add(1));
+    }
+
+    /// count a block which could not be decoded
+    pub fn lost_block(&mut self) {
+        self.lost_blocks += 1;
+    }
+
+    // session metrics and audit, `status` is complete, lossy or aborted
+    fn record(&self, status: &'static str, reason: EndReason) {
+        if self.lost_blocks > 0 {
+            counter!("rx_sessions_with_losses").increment(1);
+            counter!("rx_session_lost_blocks").increment(self.lost_blocks);
+        }
+
+        histogram!("rx_session_duration_seconds", "status" => status)
+            .record(self.started.elapsed().as_secs_f64());
+        histogram!("rx_session_bytes", "status" => status).record(self.transmitted as f64);
+        histogram!("rx_session_blocks", "status" => status).record(self.blocks as f64);
+
+        if let Some(audit) = &self.audit {
+            audit.write(&SessionRecord {
+                epoch: self.epoch,
+                session: self.session_id,
+                start: self.start_time,
+                end: Utc::now(),
+                bytes: self.transmitted as u64,
+                blocks: self.blocks,
+                lost_blocks: self.lost_blocks,
+                end_reason: reason,
+                tcp_peer: self.bufwriter.get_ref().peer_addr().ok(),
+                sha256: self.sha256.as_ref().map(|sha256| to_hex(sha256)),
+            });
+        }
+    }
+
+    pub fn session_id(&self) -> u8 {
+        self.session_id
+    }
+
+    pub fn transmitted(&self) -> usize {
+        self.transmitted
+    }
+
+    /// close the connection with a reset, so client knows the transfer is incomplete
+    pub fn abort(self) {
+        log::warn!(
+            "client : aborted transfer, {} bytes transmitted",
+            self.transmitted
+        );
+        counter!("rx_sessions_aborted").increment(1);
+        self.record("aborted", EndReason::Abort);
+        self.reset();
+    }
+
+    // drop buffered data and reset connection when socket is closed
+    fn reset(self) {
+        let (client, _) = self.bufwriter.into_parts();
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        if let Err(e) = setsockopt(&client, Linger, &linger) {
+            log::warn!("tcp: can't reset connection: {e}");
+        }
     }
 
     pub fn send(&mut self, payload: &[u8]) -> Result<(), receive::Error> {
@@ -74,6 +238,48 @@ impl Tcp {
         log::debug!("tcp: sending {} bytes", real_payload.len());
 
         self.transmitted += real_payload.len();
+        self.blocks += 1;
+        self.digest.update(real_payload);
         self.bufwriter.write_all(real_payload)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::net;
+
+    use sha2::{Digest, Sha256};
+
+    use super::Tcp;
+    use crate::protocol::DIGEST_SIZE;
+
+    // data received by the receiver application when the session ends with `expected` digest
+    fn finish(data: &[u8], expected: Option<[u8; DIGEST_SIZE]>) -> io::Result<Vec<u8>> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut tcp = Tcp::new(client, 4096, 0, None, None);
+
+        let mut payload = (data.len() as u32).to_be_bytes().to_vec();
+        payload.extend(data);
+        tcp.send(&payload).unwrap();
+        tcp.finish(expected);
+
+        let mut received = vec![];
+        listener.accept()?.0.read_to_end(&mut received)?;
+        Ok(received)
+    }
+
+    #[test]
+    fn test_finish() {
+        let data = b"hello";
+        let sha256: [u8; DIGEST_SIZE] = Sha256::digest(data).into();
+
+        assert_eq!(finish(data, Some(sha256)).unwrap(), data);
+        assert_eq!(finish(data, None).unwrap(), data);
+
+        // digest mismatch: connection is reset
+        let error = finish(data, Some([0; DIGEST_SIZE])).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,129 @@
+//! State of diode-send exposed on the control socket
+
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::net;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crossbeam_channel::Receiver;
+
+use crate::config::Bandwidth;
+use crate::control::{self, Control, Sessions, Totals};
+use crate::protocol::Header;
+use crate::send::{throttle::Throttle, SenderConfig};
+
+pub struct SenderControl {
+    /// current TCP session
+    sessions: Sessions,
+    /// current TCP client, used to interrupt a blocking read when aborting
+    client: Mutex<Option<net::TcpStream>>,
+    /// abort of current session requested
+    abort: AtomicBool,
+    /// last heartbeat sent
+    last_heartbeat: Mutex<Option<Instant>>,
+    max_bandwidth: Arc<Mutex<Option<Bandwidth>>>,
+    throttle: Arc<Throttle>,
+    for_encoding: Receiver<(Header, Vec<u8>)>,
+    for_send: Vec<Receiver<(Header, Vec<Vec<u8>>)>>,
+}
+
+impl SenderControl {
+    pub(crate) fn new(
+        max_bandwidth: Arc<Mutex<Option<Bandwidth>>>,
+        throttle: Arc<Throttle>,
+        for_encoding: Receiver<(Header, Vec<u8>)>,
+        for_send: Vec<Receiver<(Header, Vec<Vec<u8>>)>>,
+    ) -> Self {
+        Self {
+            sessions: Sessions::default(),
+            client: Mutex::new(None),
+            abort: AtomicBool::new(false),
+            last_heartbeat: Mutex::new(None),
+            max_bandwidth,
+            throttle,
+            for_encoding,
+            for_send,
+        }
+    }
+
+    pub(crate) fn start_session(&self, id: u8, client: Option<net::TcpStream>) {
+        self.abort.store(false, Ordering::Relaxed);
+        self.sessions.start(id);
+        if let Ok(mut current) = self.client.lock() {
+            *current = client;
+        }
+    }
+
+    pub(crate) fn set_bytes(&self, bytes: usize) {
+        self.sessions.set_bytes(bytes);
+    }
+
+    pub(crate) fn end_session(&self) {
+        self.sessions.end();
+        if let Ok(mut current) = self.client.lock() {
+            *current = None;
+        }
+    }
+
+    /// return true (once) if current session must be aborted
+    pub(crate) fn take_abort(&self) -> bool {
+        self.abort.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn heartbeat(&self) {
+        if let Ok(mut last_heartbeat) = self.last_heartbeat.lock() {
+            *last_heartbeat = Some(Instant::now());
+        }
+    }
+}
+
+impl Control for SenderControl {
+    fn status(&self) -> Value {
+        let last_heartbeat = self.last_heartbeat.lock().ok().and_then(|last| *last);
+
+        json!({
+            "session": self.sessions.current(),
+            "totals": self.sessions.totals(),
+            "max_bandwidth": self.throttle.rate().map(|rate| rate / 1_000_000.0),
+            "queues": {
+                "encoding": self.for_encoding.len(),
+                "udp_send": self.for_send.iter().map(|queue| queue.len()).collect::<Vec<_>>(),
+            },
+            "last_heartbeat_ms": control::elapsed_ms(last_heartbeat),
+        })
+    }
+
+    fn abort(&self) -> Result<()> {
+        let client = self
+            .client
+            .lock()
+            .map_err(|e| Error::other(format!("can't get current session: {e}")))?;
+
+        match client.as_ref() {
+            None => Err(Error::new(ErrorKind::NotFound, "no active session")),
+            Some(client) => {
+                self.abort.store(true, Ordering::Relaxed);
+                // wake up tcp thread if it is waiting for data
+                client.shutdown(net::Shutdown::Read)
+            }
+        }
+    }
+
+    fn set_rate(&self, max_bandwidth: Option<f64>) -> Result<()> {
+        {
+            let mut current = self
+                .max_bandwidth
+                .lock()
+                .map_err(|e| Error::other(format!("can't update max_bandwidth: {e}")))?;
+            *current = max_bandwidth.map(Bandwidth::Fixed);
+        }
+
+        SenderConfig::update_bandwidth(&self.max_bandwidth, &self.throttle);
+        Ok(())
+    }
+    fn totals(&self) -> Totals {
+        self.sessions.totals()
+    }
+}
@@ -8,38 +8,29 @@
 //! Here follows a simplified representation of the workers pipeline:
 //!
 //! ```text
-//!             ----------              -------------------
-//! tcp rcv   --| blocks |->  encoder --| encoded packets |-> udp sender
-//!             ----------              -------------------
-//! ```
-//!
-//! Target :
-//!
-//! ```text
-//!                                     /-- >  encoder + udp sender (udp sock)
-//!                        ----------   |
-//! (tcp sock) tcp recv  --| blocks |---+-- >  encoder + udp sender (udp sock)
-//!                        ----------   |
-//!                                     +-- >  encoder + udp sender (udp sock)
-//!                                     .
-//!                                     .
-//!                                     .
-//!                                     \-- >  encoder + udp sender (udp sock)
+//!                                     /-- > encoder --\                   /-- > udp sender (udp sock)
+//!                        ----------   |               |   -----------    |
+//! (tcp sock) tcp recv  --| blocks |---+-- > encoder --+---| packets |----+-- > udp sender (udp sock)
+//!                        ----------   |               |   -----------    |
+//!                                     \-- > encoder --/                   \-- > udp sender (udp sock)
 //!
-//!                                         +  heatbeat (udp sock)
+//!                                                                            +  heatbeat (udp sock)
 //! ```
 //!
 //! tcp recv:
-//! * rate limit
 //! * split in block to encode
 //! * allocate a block id per block
-//! * dispatch (round robin) on multiple encoders
+//! * push blocks in a queue shared by all encoders
 //!
-//! each encoder + udp sender thread
+//! each encoder thread (`encoding_threads`)
 //! * encode in predefined packet size
 //! * add repair packets
+//! * dispatch packets of the block to a udp sender, according to the block id
+//! * there must be a reasonnable number of encoding threads (at most `MAX_ENCODING_THREADS`, 20), because of block_id encoded on 8 bits
+//!
+//! each udp sender thread (one per `udp_port`)
+//! * rate limit (one rate limiter shared by all threads)
 //! * send all packet on udp
-//! * there must be a reasonnable number of encoding threads (max ~20), because of block_id encoded on 8 bits
 //!
 //! heartbeat
 //! * send periodically on dedicated socket
@@ -52,27 +43,39 @@
 //!   + udp sender depends on MTU
 //!     * with 1500 MTU, it is a bit slow but can go up to 20 Gb/s : socket_send bench
 //!     * with 9000 MTU, it is quick and can go up to 90 Gb/s : socket_send_big_mtu_bench
-//!   + encoding is a bit slow, less than 10 Gb/s, so there should be multiple (at least 2) `nb_encoding_threads` workers running in parallel.
+//!   + encoding is a bit slow, less than 10 Gb/s, so there should be multiple (at least 2) `encoding_threads` workers running in parallel.
 //!
 
-use crate::config::DiodeConfig;
+use crate::audit::AuditLog;
+use crate::config::{
+    Bandwidth, DiodeConfig, LinkOverhead, TimeOfDay, DEFAULT_SHUTDOWN_TIMEOUT, MAX_ENCODING_THREADS,
+};
 use crate::protocol::{Header, LidiParameters, MessageType, FIRST_BLOCK_ID, FIRST_SESSION_ID};
 use crate::{protocol, send::encoding::Encoding};
 use std::io::{Error, ErrorKind, Result};
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{net, thread, time};
 
+pub mod control;
 pub mod encoding;
 pub mod tcp;
 mod throttle;
 
+use crate::control::Control;
+use crate::systemd::{Beat, Liveness, BEAT_INTERVAL};
 use crate::udp::Udp;
-use crossbeam_channel::{Receiver, Sender};
-use metrics::counter;
+use control::SenderControl;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use metrics::{counter, gauge};
 use throttle::Throttle;
 
+/// interval to check if diode-send is stopping, when waiting for a new TCP client
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// An instance of this data structure is shared by workers to synchronize them and to access
 /// communication channels
 ///
@@ -92,9 +95,22 @@ pub struct SenderConfig {
     pub object_transmission_info: raptorq::ObjectTransmissionInformation,
     pub from_buffer_size: u32,
     pub to_max_messages: u16,
-    pub to_encoding: Vec<Sender<(Header, Vec<u8>)>>,
-    pub for_encoding: Vec<Receiver<(Header, Vec<u8>)>>,
-    pub max_bandwidth: Option<f64>,
+    pub to_encoding: Sender<(Header, Vec<u8>)>,
+    pub for_encoding: Receiver<(Header, Vec<u8>)>,
+    pub to_send: Vec<Sender<(Header, Vec<Vec<u8>>)>>,
+    pub for_send: Vec<Receiver<(Header, Vec<Vec<u8>>)>>,
+    /// rate limit, can be changed at runtime
+    pub max_bandwidth: Arc<Mutex<Option<Bandwidth>>>,
+    pub encoding_threads: usize,
+    pub link_overhead: LinkOverhead,
+    pub control_socket: Option<String>,
+    pub audit_log: Option<String>,
+    /// send the digest of each session with its last block
+    pub session_digest: bool,
+    /// set to stop accepting clients, finish current session and drain queues
+    pub shutdown: Arc<AtomicBool>,
+    pub shutdown_timeout: Duration,
+    throttle: Arc<Throttle>,
 }
 
 impl TryFrom<DiodeConfig> for SenderConfig {
@@ -109,21 +125,31 @@ impl TryFrom<DiodeConfig> for SenderConfig {
             + protocol::nb_repair_packets(&object_transmission_info, config.repair_block_size)
                 as u16;
 
-        let mut to_encoding = vec![];
-        let mut for_encoding = vec![];
+        let encoding_threads = config
+            .sender
+            .as_ref()
+            .and_then(|sender| sender.encoding_threads)
+            .unwrap_or(config.udp_port.len().min(MAX_ENCODING_THREADS));
 
-        // create a bounded channel for each thread (round robin dispatcher).
-        // channels can grow up if tcp thread is too fast compared to senders.
+        // create a bounded channel shared by all encoding threads.
+        // channel can grow up if tcp thread is too fast compared to encoders.
         // this happens when no rate limit (max throughput) is configured, so
-        // tcp thread reads as fast as possible, and it is quicker than tx threads.
+        // tcp thread reads as fast as possible, and it is quicker than encoding threads.
+        // the fifo capacity value must be as small as possible, to prevent too many
+        // disordering, throttling issues and consuming a lot of useless memory,
+        // but big enough to prevent starvation on encoding threads
+        // tcp thread will block on this when the fifo is full
+        let (to_encoding, for_encoding) =
+            crossbeam_channel::bounded::<(Header, Vec<u8>)>(3 * encoding_threads);
+
+        let mut to_send = vec![];
+        let mut for_send = vec![];
+
+        // create a bounded channel for each udp sender thread (dispatched by block id)
         (0..config.udp_port.len()).for_each(|_| {
-            // the fifo capacity value must be as small as possible, to prevent too many
-            // disordering, throttling issues and consuming a lot of useless memory,
-            // but big enough to prevent starvation on udp tx thread
-            // tcp thread will block on this when the fifo is full
-            let (tx, rx) = crossbeam_channel::bounded::<(Header, Vec<u8>)>(3);
-            to_encoding.push(tx);
-            for_encoding.push(rx);
+            let (tx, rx) = crossbeam_channel::bounded::<(Header, Vec<Vec<u8>>)>(3);
+            to_send.push(tx);
+            for_send.push(rx);
         });
 
         match config.sender {
@@ -164,7 +190,24 @@ impl TryFrom<DiodeConfig> for SenderConfig {
                     to_max_messages,
                     to_encoding,
                     for_encoding,
-                    max_bandwidth: config_sender.max_bandwidth,
+                    to_send,
+                    for_send,
+                    max_bandwidth: Arc::new(Mutex::new(config_sender.max_bandwidth)),
+                    encoding_threads,
+                    link_overhead: config_sender.link_overhead.unwrap_or_default(),
+                    control_socket: config_sender.control_socket,
+                    audit_log: config_sender.audit_log,
+                    session_digest: config_sender.session_digest.unwrap_or(false),
+                    shutdown: Arc::new(AtomicBool::new(false)),
+                    shutdown_timeout: Duration::from_millis(
+                        config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT) as _,
+                    ),
+                    throttle: Arc::new(Throttle::new(
+                        None,
+                        config_sender.max_burst.unwrap_or(
+                            config.encoding_block_size + config.repair_block_size as u64,
+                        ),
+                    )),
                 })
             }
         }
@@ -172,23 +215,29 @@ impl TryFrom<DiodeConfig> for SenderConfig {
 }
 
 impl SenderConfig {
-    fn start_encoder_sender(
+    fn encoding_loop(
         for_encoding: Receiver<(Header, Vec<u8>)>,
         encoding: Encoding,
-        mut sender: Udp,
-        mut throttle: Option<Throttle>,
+        to_send: Vec<Sender<(Header, Vec<Vec<u8>>)>>,
+        thread_id: usize,
+        beat: Beat,
     ) {
+        let nb_senders = to_send.len();
+        let encoding_blocks = counter!("tx_encoding_blocks", "thread" => thread_id.to_string());
+
         loop {
-            let packets;
-            let (header, payload) = match for_encoding.recv() {
+            beat.beat();
+
+            let (header, payload) = match for_encoding.recv_timeout(BEAT_INTERVAL) {
                 Ok(ret) => {
-                    counter!("tx_encoding_blocks").increment(1);
+                    encoding_blocks.increment(1);
                     ret
                 }
-                Err(e) => {
-                    log::debug!("Error receiving data: {e}");
-                    counter!("tx_encoding_blocks_err").increment(1);
-                    continue;
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(_) => {
+                    // tcp thread is stopped and all blocks are encoded
+                    log::debug!("encoding: no more blocks to encode");
+                    return;
                 }
             };
 
@@ -201,58 +250,149 @@ impl SenderConfig {
                 log::debug!("end of encoding block for client")
             }
 
-            if !payload.is_empty() {
-                packets = encoding.encode(payload, header.block());
+            if payload.is_empty() {
+                continue;
+            }
 
-                let mut header = header;
+            // todo : try to remove this serialize and get only data
+            let packets = encoding
+                .encode(payload, header.block())
+                .into_iter()
+                .map(|packet| packet.serialize())
+                .collect();
+
+            // always send packets of the same block id to the same udp sender thread
+            let sender_id = header.block() as usize % nb_senders;
+            if let Err(e) = to_send[sender_id].send((header, packets)) {
+                log::warn!("Sender encoding: {e}");
+            }
+        }
+    }
 
-                for packet in packets {
-                    header.incr_seq();
-                    // todo : try to remove this serialize and get only data
+    fn udp_send_loop(
+        for_send: Receiver<(Header, Vec<Vec<u8>>)>,
+        mut sender: Udp,
+        throttle: Arc<Throttle>,
+        link_overhead: LinkOverhead,
+        port: u16,
+        beat: Beat,
+    ) {
+        let port = port.to_string();
+        let pkts = counter!("tx_udp_pkts", "port" => port.clone());
+        let bytes = counter!("tx_udp_bytes", "port" => port.clone());
+        let wire_bytes = counter!("tx_udp_wire_bytes", "port" => port.clone());
+        let pkts_err = counter!("tx_udp_pkts_err", "port" => port.clone());
+        let bytes_err = counter!("tx_udp_bytes_err", "port" => port.clone());
+        let wire_bytes_err = counter!("tx_udp_wire_bytes_err", "port" => port);
 
-                    let packet = packet.serialize();
-                    let payload_len = packet.len();
+        loop {
+            beat.beat();
+
+            let (mut header, packets) = match for_send.recv_timeout(BEAT_INTERVAL) {
+                Ok(ret) => ret,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(_) => {
+                    // encoding threads are stopped and all packets are sent
+                    log::debug!("udp: no more packets to send");
+                    return;
+                }
+            };
 
-                    // sleep to respect rate limit
-                    if let Some(ref mut throttle) = throttle {
-                        // to try to match real packet size, add network header size:
-                        // eth 14, ip 20, udp 8 = 42
-                        // maybe we should be able to change this in configuration ?
-                        let packet_len = payload_len + 42;
-                        throttle.limit(packet_len);
-                    }
+            for packet in packets {
+                header.incr_seq();
 
-                    match sender.send(header, packet) {
-                        Ok(_) => {
-                            counter!("tx_udp_pkts").increment(1);
-                            counter!("tx_udp_bytes").increment(payload_len as u64);
-                        }
-                        Err(_e) => {
-                            counter!("tx_udp_pkts_err").increment(1);
-                            counter!("tx_udp_bytes_err").increment(payload_len as u64);
-                        }
+                let payload_len = packet.len();
+
+                // sleep to respect rate limit
+                // to try to match real packet size, add lidi header and network overhead
+                let wire_len = link_overhead.wire_size(payload_len + Header::serialize_overhead());
+                throttle.limit(wire_len);
+
+                match sender.send(header, packet) {
+                    Ok(_) => {
+                        pkts.increment(1);
+                        bytes.increment(payload_len as u64);
+                        wire_bytes.increment(wire_len as u64);
+                    }
+                    Err(_e) => {
+                        pkts_err.increment(1);
+                        bytes_err.increment(payload_len as u64);
+                        wire_bytes_err.increment(wire_len as u64);
                     }
                 }
             }
         }
     }
 
+    fn metrics_loop(
+        for_encoding: Receiver<(Header, Vec<u8>)>,
+        for_send: Vec<Receiver<(Header, Vec<Vec<u8>>)>>,
+        ports: Vec<u16>,
+    ) {
+        let encoding_queue_len = gauge!("tx_encoding_queue_len");
+        let udp_queue_len: Vec<_> = ports
+            .iter()
+            .map(|port| gauge!("tx_udp_queue_len", "port" => port.to_string()))
+            .collect();
+
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            encoding_queue_len.set(for_encoding.len() as f64);
+            for (gauge, for_send) in udp_queue_len.iter().zip(&for_send) {
+                gauge.set(for_send.len() as f64);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn tcp_listener_loop(
         listener: net::TcpListener,
         from_buffer_size: u32,
-        to_encoding: Vec<Sender<(Header, Vec<u8>)>>,
+        to_encoding: Sender<(Header, Vec<u8>)>,
+        control: Arc<SenderControl>,
+        shutdown: Arc<AtomicBool>,
+        epoch: u64,
+        audit: Option<Arc<AuditLog>>,
+        session_digest: bool,
     ) {
         let mut session_id = FIRST_SESSION_ID;
-        let nb_threads = to_encoding.len() as u8;
 
-        for client in listener.incoming() {
-            match client {
+        // listener is non blocking, to check regularly if we must stop accepting clients
+        if let Err(e) = listener.set_nonblocking(true) {
+            log::error!("failed to configure TCP listener: {e}");
+            return;
+        }
+
+        loop {
+            if shutdown.load(Ordering::Relaxed) {
+                log::info!("stop accepting TCP clients");
+                return;
+            }
+
+            match listener.accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
                 Err(e) => {
                     log::error!("failed to accept TCP client: {e}");
                     return;
                 }
-                Ok(client) => {
-                    let mut tcp = tcp::Tcp::new(client, from_buffer_size, session_id);
+                Ok((client, _)) => {
+                    if let Err(e) = client.set_nonblocking(false) {
+                        log::warn!("client: error: {e}");
+                    }
+
+                    control.start_session(session_id, client.try_clone().ok());
+
+                    let mut tcp = tcp::Tcp::new(
+                        client,
+                        from_buffer_size,
+                        session_id,
+                        epoch,
+                        audit.clone(),
+                        session_digest,
+                    );
 
                     if let Err(e) = tcp.configure() {
                         log::warn!("client: error: {e}");
@@ -260,10 +400,18 @@ impl SenderConfig {
 
                     log::debug!("tcp connected");
 
-                    let mut to_encoding_id = 0;
-
                     loop {
-                        match tcp.read() {
+                        let message = tcp.read();
+
+                        // abort requested on control socket: drop what was read
+                        let message = if control.take_abort() {
+                            let pending = message.ok().flatten().map(|(header, _)| header);
+                            Ok(Some(tcp.abort(pending)))
+                        } else {
+                            message
+                        };
+
+                        match message {
                             Ok(message) => {
                                 if let Some((message, payload)) = message {
                                     log::debug!(
@@ -275,21 +423,13 @@ impl SenderConfig {
 
                                     counter!("tx_tcp_blocks").increment(1);
                                     counter!("tx_tcp_bytes").increment(payload.len() as u64);
+                                    control.set_bytes(tcp.transmitted());
 
                                     let message_type = message.message_type();
-                                    if let Err(e) = to_encoding[to_encoding_id as usize]
-                                        .send((message, payload))
-                                    {
+                                    if let Err(e) = to_encoding.send((message, payload)) {
                                         log::warn!("Sender tcp read: {e}");
                                     }
 
-                                    // send next message to next thread
-                                    to_encoding_id = if to_encoding_id == nb_threads - 1 {
-                                        0
-                                    } else {
-                                        to_encoding_id + 1
-                                    };
-
                                     if message_type.contains(MessageType::End) {
                                         break;
                                     }
@@ -297,10 +437,13 @@ impl SenderConfig {
                             }
                             Err(e) => {
                                 log::warn!("Error tcp read: {e}");
+                                tcp.error();
                                 break;
                             }
                         }
                     }
+
+                    control.end_session();
                 }
             }
 
@@ -312,8 +455,10 @@ impl SenderConfig {
         }
     }
 
-    pub fn start(&self) -> Result<()> {
-        let mut threads = vec![];
+    pub fn start(self) -> Result<()> {
+        // threads of the data pipeline, stopped one after the other when shutting down
+        let mut encoding_threads = vec![];
+        let mut udp_threads = vec![];
 
         log::debug!(
             "client socket buffer size is {} bytes",
@@ -328,7 +473,7 @@ impl SenderConfig {
         );
 
         let nb_threads = self.udp_port_list.len();
-        let for_encoding = &self.for_encoding;
+        let for_send = &self.for_send;
 
         let to_udp = self.to_udp;
         let to_udp_mtu = self.to_udp_mtu;
@@ -339,16 +484,75 @@ impl SenderConfig {
         let object_transmission_info = self.object_transmission_info;
         let heartbeat_interval = self.hearbeat_interval;
 
-        // we have to multiply by 1 million because bandwidth is in Mbit/s in configuration,
-        // when throttle module uses bit/s
-        // we divide max bandwitdh by the number of thread sending data in parallel, each thread
-        // having is own throttling instance
-        let max_bandwidth = self
-            .max_bandwidth
-            .map(|max| max * 1_000_000.0 / nb_threads as f64);
+        // identifies this instance in audit logs of both sides, sent in the init message
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        log::info!("diode-send epoch is {epoch}");
+
+        let audit = match &self.audit_log {
+            Some(path) => Some(Arc::new(AuditLog::open(
+                path,
+                "send",
+                &self.to_udp.to_string(),
+            )?)),
+            None => None,
+        };
+
+        // apply current rate limit before sending anything, then follow schedule and updates
+        SenderConfig::update_bandwidth(&self.max_bandwidth, &self.throttle);
+
+        // worker threads monitored by systemd watchdog
+        let liveness = Arc::new(Liveness::default());
+
+        let control = Arc::new(SenderControl::new(
+            self.max_bandwidth.clone(),
+            self.throttle.clone(),
+            self.for_encoding.clone(),
+            self.for_send.clone(),
+        ));
+
+        if let Some(path) = &self.control_socket {
+            crate::control::start(path, control.clone())?;
+        }
+
+        // bandwidth, metrics, heartbeat and control threads run until the end of the process
+        let max_bandwidth = self.max_bandwidth.clone();
+        let throttle = self.throttle.clone();
+        thread::Builder::new()
+            .name("lidi_tx_bandwidth".into())
+            .spawn(move || SenderConfig::bandwidth_loop(max_bandwidth, throttle))?;
+
+        let encoding_queue = self.for_encoding.clone();
+        let udp_queues = self.for_send.clone();
+        let ports = self.udp_port_list.clone();
+        thread::Builder::new()
+            .name("lidi_tx_metrics".into())
+            .spawn(move || SenderConfig::metrics_loop(encoding_queue, udp_queues, ports))?;
+
+        log::info!("starting {} encoding threads", self.encoding_threads);
+
+        for i in 0..self.encoding_threads {
+            let for_encoding = self.for_encoding.clone();
+            let to_send = self.to_send.clone();
+            let beat = liveness.register(&format!("encoding_{i}"));
+
+            let encoding_thread = thread::Builder::new()
+                .name(format!("lidi_tx_encoding_{i}"))
+                .spawn(move || {
+                    let encoding = Encoding::new(object_transmission_info, repair_block_size);
+
+                    // loop on blocks to encode
+                    SenderConfig::encoding_loop(for_encoding, encoding, to_send, i, beat);
+                })?;
+            encoding_threads.push(encoding_thread);
+        }
 
         for i in 0..nb_threads {
-            let for_encoding = for_encoding[i].clone();
+            let for_send = for_send[i].clone();
+            let throttle = self.throttle.clone();
+            let link_overhead = self.link_overhead;
+            let beat = liveness.register(&format!("udp_{i}"));
             let port_list = self.udp_port_list.clone();
 
             let to_udp = SocketAddr::new(to_udp, port_list[i]);
@@ -370,8 +574,6 @@ impl SenderConfig {
                         bind_udp
                     );
 
-                    let encoding = Encoding::new(object_transmission_info, repair_block_size);
-
                     // first, send one "init" packet
                     if i == 0 {
                         let header =
@@ -383,17 +585,23 @@ impl SenderConfig {
                             to_udp_mtu,
                             nb_threads as u8,
                         );
-                        if let Err(err) = sender.send(header, Vec::from(payload.serialize())) {
+                        let payload = protocol::serialize_init(&payload, epoch);
+                        if let Err(err) = sender.send(header, payload) {
                             log::warn!("Unable to send init message: {err}");
                         }
                     }
 
-                    let throttle = max_bandwidth.map(Throttle::new);
-
                     // loop on packets to send
-                    SenderConfig::start_encoder_sender(for_encoding, encoding, sender, throttle);
+                    SenderConfig::udp_send_loop(
+                        for_send,
+                        sender,
+                        throttle,
+                        link_overhead,
+                        to_udp.port(),
+                        beat,
+                    );
                 })?;
-            threads.push(tx_thread);
+            udp_threads.push(tx_thread);
         }
 
         log::info!(
@@ -409,12 +617,12 @@ impl SenderConfig {
             encoding_block_size + repair_block_size as u64,
             "heartbeat",
         )?;
-        let hb_thread = thread::Builder::new()
+        let hb_control = control.clone();
+        thread::Builder::new()
             .name("lidi_tx_heartbeat".into())
             .spawn(move || {
-                SenderConfig::heartbeat_start(sender, heartbeat_interval);
+                SenderConfig::heartbeat_start(sender, heartbeat_interval, hb_control);
             })?;
-        threads.push(hb_thread);
 
         log::info!("accepting TCP clients at {}", self.from_tcp);
 
@@ -430,32 +638,107 @@ impl SenderConfig {
 
         let from_buffer_size = self.from_buffer_size;
         let to_encoding = self.to_encoding.clone();
+        let shutdown = self.shutdown.clone();
+        let tcp_control = control.clone();
+        let session_digest = self.session_digest;
 
         let tcp_thread = thread::Builder::new()
             .name("lidi_tx_tcp".into())
             .spawn(move || {
-                SenderConfig::tcp_listener_loop(tcp_listener, from_buffer_size, to_encoding)
+                SenderConfig::tcp_listener_loop(
+                    tcp_listener,
+                    from_buffer_size,
+                    to_encoding,
+                    tcp_control,
+                    shutdown,
+                    epoch,
+                    audit,
+                    session_digest,
+                )
             })?;
 
-        threads.push(tcp_thread);
+        // from now on, only workers keep channels open: when a worker stops, the next one in
+        // the pipeline stops too, once its queue is empty
+        drop(self.to_encoding);
+        drop(self.to_send);
+
+        // all sockets are bound
+        crate::systemd::start(control.clone(), liveness, self.shutdown.clone())?;
 
-        for thread in threads {
-            if let Err(e) = thread.join() {
-                log::warn!("Cannot join thread: {e:?}");
+        while !self.shutdown.load(Ordering::Relaxed) && !tcp_thread.is_finished() {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+
+        if !self.shutdown.load(Ordering::Relaxed) {
+            let _ = tcp_thread.join();
+            return Err(Error::other("TCP listener stopped"));
+        }
+
+        log::info!(
+            "waiting up to {} ms for current session to complete",
+            self.shutdown_timeout.as_millis()
+        );
+
+        let mut pipeline = vec![tcp_thread];
+        if !crate::join_until(&mut pipeline, Instant::now() + self.shutdown_timeout) {
+            log::warn!("current session not completed in time: aborting it");
+            if let Err(e) = control.abort() {
+                log::warn!("unable to abort current session: {e}");
             }
         }
 
+        // encoding and udp threads stop when all blocks are sent
+        pipeline.extend(encoding_threads);
+        pipeline.extend(udp_threads);
+        if !crate::join_until(&mut pipeline, Instant::now() + self.shutdown_timeout) {
+            log::warn!("queues not flushed in time: pending data is lost");
+        }
+
+        log::info!("diode-send stopped");
+
         Ok(())
     }
 
-    fn heartbeat_start(mut sender: Udp, interval: Duration) {
+    // every second, check if rate limit must be changed (schedule or runtime update)
+    fn bandwidth_loop(max_bandwidth: Arc<Mutex<Option<Bandwidth>>>, throttle: Arc<Throttle>) {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+            SenderConfig::update_bandwidth(&max_bandwidth, &throttle);
+        }
+    }
+
+    fn update_bandwidth(max_bandwidth: &Mutex<Option<Bandwidth>>, throttle: &Throttle) {
+        // we have to multiply by 1 million because bandwidth is in Mbit/s in configuration,
+        // when throttle module uses bit/s
+        let rate = match max_bandwidth.lock() {
+            Ok(max_bandwidth) => max_bandwidth
+                .as_ref()
+                .and_then(|bandwidth| bandwidth.at(TimeOfDay::now()))
+                .map(|max| max * 1_000_000.0),
+            Err(e) => {
+                log::warn!("Unable to read bandwidth configuration: {e}");
+                return;
+            }
+        };
+
+        if rate != throttle.rate() {
+            match rate {
+                Some(rate) => log::info!("rate limit set to {} Mbit/s", rate / 1_000_000.0),
+                None => log::info!("rate limit disabled"),
+            }
+            throttle.set_rate(rate);
+        }
+    }
+
+    fn heartbeat_start(mut sender: Udp, interval: Duration, control: Arc<SenderControl>) {
         let header = Header::new(MessageType::Heartbeat, 0, 0);
 
         loop {
             std::thread::sleep(interval);
             log::trace!("Sending heartbeat");
-            if let Err(err) = sender.send(header, vec![]) {
-                log::warn!("Unable to send heartbeat message: {err}");
+            match sender.send(header, vec![]) {
+                Ok(_) => control.heartbeat(),
+                Err(err) => log::warn!("Unable to send heartbeat message: {err}"),
             }
         }
     }
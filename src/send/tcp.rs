@@ -1,12 +1,17 @@
 //! Worker that reads data from a client socket and split it into [crate::protocol] messages
 
-use metrics::counter;
+use metrics::{counter, histogram};
 use nix::sys::socket::sockopt::{RcvBuf, SndBuf};
 use nix::sys::socket::{getsockopt, setsockopt};
 
-use crate::protocol::{Header, MessageType, FIRST_BLOCK_ID, PAYLOAD_OVERHEAD};
+use crate::audit::{to_hex, AuditLog, EndReason, SessionRecord};
+use crate::protocol::{Header, MessageType, DIGEST_SIZE, FIRST_BLOCK_ID, PAYLOAD_OVERHEAD};
 use crate::{protocol, send};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std::io::Read;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{io, net};
 
 pub struct Tcp {
@@ -24,10 +29,32 @@ pub struct Tcp {
     session_id: u8,
     /// current block counter
     block_id: u8,
+    /// start of the session
+    started: Instant,
+    /// number of blocks read
+    blocks: u64,
+    /// start of the session, for audit log
+    start_time: DateTime<Utc>,
+    /// epoch of this diode-send instance
+    epoch: u64,
+    audit: Option<Arc<AuditLog>>,
+    /// send the final digest with the last block, previous receivers reject it
+    send_digest: bool,
+    /// digest of data read so far
+    digest: Sha256,
+    /// final digest, once the last block is read
+    sha256: Option<[u8; DIGEST_SIZE]>,
 }
 
 impl Tcp {
-    pub fn new(client: net::TcpStream, buffer_size: u32, session_id: u8) -> Self {
+    pub fn new(
+        client: net::TcpStream,
+        buffer_size: u32,
+        session_id: u8,
+        epoch: u64,
+        audit: Option<Arc<AuditLog>>,
+        send_digest: bool,
+    ) -> Self {
         Self {
             buffer: vec![0; buffer_size as _],
             // we always start at PAYLOAD_OVERHEAD to keep some room to store read length
@@ -37,6 +64,14 @@ impl Tcp {
             message_type: MessageType::Start | MessageType::Data,
             session_id,
             block_id: FIRST_BLOCK_ID,
+            started: Instant::now(),
+            blocks: 0,
+            start_time: Utc::now(),
+            epoch,
+            audit,
+            send_digest,
+            digest: Sha256::new(),
+            sha256: None,
         }
     }
 
@@ -68,10 +103,10 @@ impl Tcp {
     }
 
     fn new_header(&mut self, end: bool) -> Header {
-        let flags = if end {
-            self.message_type | MessageType::End
-        } else {
-            self.message_type
+        let flags = match (end, self.send_digest) {
+            (true, true) => self.message_type | MessageType::End | MessageType::Digest,
+            (true, false) => self.message_type | MessageType::End,
+            (false, _) => self.message_type,
         };
         let message = protocol::Header::new(flags, self.session_id, self.block_id);
 
@@ -87,6 +122,66 @@ impl Tcp {
         message
     }
 
+    /// build the last message of an aborted session: diode-receive will drop this session
+    ///
+    /// `pending` is the header of a message read but not sent yet: its block id is reused, so
+    /// there is no missing block on diode-receive side.
+    pub fn abort(&mut self, pending: Option<Header>) -> (Header, Vec<u8>) {
+        let header = pending.unwrap_or_else(|| self.new_header(true));
+        let header = protocol::Header::new(
+            header.message_type().difference(MessageType::Digest)
+                | MessageType::End
+                | MessageType::Abort,
+            header.session(),
+            header.block(),
+        );
+
+        // drop pending data
+        self.cursor = PAYLOAD_OVERHEAD;
+        self.buffer[0..PAYLOAD_OVERHEAD].copy_from_slice(&u32::to_be_bytes(0));
+
+        log::warn!("aborted transfer, {} bytes transmitted", self.transmitted);
+        counter!("tx_sessions_aborted").increment(1);
+        self.record("aborted", EndReason::Abort);
+
+        (header, self.buffer.to_vec())
+    }
+
+    pub fn transmitted(&self) -> usize {
+        self.transmitted
+    }
+
+    /// the session ended with a socket error
+    pub fn error(&self) {
+        self.audit(EndReason::Error);
+    }
+
+    // session metrics, `status` is complete or aborted
+    fn record(&self, status: &'static str, reason: EndReason) {
+        histogram!("tx_session_duration_seconds", "status" => status)
+            .record(self.started.elapsed().as_secs_f64());
+        histogram!("tx_session_bytes", "status" => status).record(self.transmitted as f64);
+        histogram!("tx_session_blocks", "status" => status).record(self.blocks as f64);
+        self.audit(reason);
+    }
+
+    fn audit(&self, end_reason: EndReason) {
+        if let Some(audit) = &self.audit {
+            audit.write(&SessionRecord {
+                epoch: Some(self.epoch),
+                session: self.session_id,
+                start: self.start_time,
+                end: Utc::now(),
+                bytes: self.transmitted as u64,
+                blocks: self.blocks,
+                lost_blocks: 0,
+                end_reason,
+                tcp_peer: self.client.peer_addr().ok(),
+                sha256: self.sha256.as_ref().map(|sha256| to_hex(sha256)),
+            });
+        }
+    }
+
     pub fn read(&mut self) -> Result<Option<(Header, Vec<u8>)>, send::Error> {
         log::trace!("tcp read...");
 
@@ -111,7 +206,10 @@ impl Tcp {
                 // handling incomplete last packet
                 log::trace!("tcp : send last buffer");
 
-                header = self.new_header(true);
+                // last block must have room for the digest, otherwise its data is sent first and
+                // next read (end of stream again) sends an empty last block
+                let end = !self.send_digest || self.cursor + DIGEST_SIZE <= self.buffer.len();
+                header = self.new_header(end);
 
                 log::trace!("tcp : buffer not full");
             }
@@ -139,15 +237,128 @@ impl Tcp {
         let read_size = self.cursor - PAYLOAD_OVERHEAD;
         self.buffer[0..PAYLOAD_OVERHEAD as _].copy_from_slice(&u32::to_be_bytes(read_size as _));
 
+        self.digest
+            .update(&self.buffer[PAYLOAD_OVERHEAD..self.cursor]);
+        // digest is computed for the audit log even if it is not sent
+        if header.message_type().contains(MessageType::End) {
+            let sha256: [u8; DIGEST_SIZE] = self.digest.finalize_reset().into();
+            if header.message_type().contains(MessageType::Digest) {
+                self.buffer[self.cursor..self.cursor + DIGEST_SIZE].copy_from_slice(&sha256);
+            }
+            self.sha256 = Some(sha256);
+        }
+
         log::trace!("tcp reset cursor");
-        self.transmitted += self.cursor;
+        self.transmitted += read_size;
+        self.blocks += 1;
         self.cursor = PAYLOAD_OVERHEAD;
 
         if header.message_type().contains(MessageType::End) {
             log::info!("finished transfer, {} bytes transmitted", self.transmitted);
             counter!("tx_sessions").increment(1);
+            self.record("complete", EndReason::End);
         }
 
         Ok(Some((header, self.buffer.to_vec())))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net;
+
+    use sha2::{Digest, Sha256};
+
+    use super::Tcp;
+    use crate::protocol::{self, MessageType, DIGEST_SIZE, PAYLOAD_OVERHEAD};
+
+    const BUFFER_SIZE: usize = 64;
+
+    // blocks read by diode-send from a client sending `data`
+    fn read_blocks(data: &[u8], send_digest: bool) -> Vec<(MessageType, Vec<u8>)> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(data).unwrap();
+        drop(client);
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut tcp = Tcp::new(stream, BUFFER_SIZE as u32, 0, 0, None, send_digest);
+
+        let mut blocks = vec![];
+        loop {
+            if let Some((header, payload)) = tcp.read().unwrap() {
+                let end = header.message_type().contains(MessageType::End);
+                blocks.push((header.message_type(), payload));
+                if end {
+                    return blocks;
+                }
+            }
+        }
+    }
+
+    fn data(payload: &[u8]) -> &[u8] {
+        let len = u32::from_be_bytes(payload[0..PAYLOAD_OVERHEAD].try_into().unwrap());
+        &payload[PAYLOAD_OVERHEAD..PAYLOAD_OVERHEAD + len as usize]
+    }
+
+    #[test]
+    fn test_digest() {
+        // digest right after data in the last block
+        let sent = [1; 10];
+        let blocks = read_blocks(&sent, true);
+        assert_eq!(blocks.len(), 1);
+        let (flags, payload) = &blocks[0];
+        assert!(flags.contains(MessageType::End | MessageType::Digest));
+        assert_eq!(data(payload), sent);
+        let sha256: [u8; DIGEST_SIZE] = Sha256::digest(sent).into();
+        assert_eq!(protocol::block_digest(payload), Some(sha256));
+
+        // no room for the digest: data first, then an empty last block with the digest
+        let sent = [2; BUFFER_SIZE - PAYLOAD_OVERHEAD - DIGEST_SIZE + 1];
+        let blocks = read_blocks(&sent, true);
+        assert_eq!(blocks.len(), 2);
+        assert!(!blocks[0].0.contains(MessageType::End));
+        assert!(!blocks[0].0.contains(MessageType::Digest));
+        assert_eq!(data(&blocks[0].1), sent);
+        assert!(blocks[1].0.contains(MessageType::End | MessageType::Digest));
+        assert!(data(&blocks[1].1).is_empty());
+        let sha256: [u8; DIGEST_SIZE] = Sha256::digest(sent).into();
+        assert_eq!(protocol::block_digest(&blocks[1].1), Some(sha256));
+
+        // digest of all blocks
+        let sent: Vec<u8> = (0..200).collect();
+        let blocks = read_blocks(&sent, true);
+        let received: Vec<u8> = blocks
+            .iter()
+            .flat_map(|(_, payload)| data(payload).to_vec())
+            .collect();
+        assert_eq!(received, sent);
+        let (flags, payload) = blocks.last().unwrap();
+        assert!(flags.contains(MessageType::End | MessageType::Digest));
+        let sha256: [u8; DIGEST_SIZE] = Sha256::digest(&sent).into();
+        assert_eq!(protocol::block_digest(payload), Some(sha256));
+    }
+
+    #[test]
+    fn test_no_digest() {
+        // previous receivers reject headers with unknown flags
+        let known = MessageType::all().difference(MessageType::Digest);
+
+        // by default, the last block is the same as before: no digest, no empty last block
+        let sent = [2; BUFFER_SIZE - PAYLOAD_OVERHEAD - DIGEST_SIZE + 1];
+        let blocks = read_blocks(&sent, false);
+        assert_eq!(blocks.len(), 1);
+        assert!(blocks[0].0 == MessageType::Start | MessageType::Data | MessageType::End);
+        assert_eq!(data(&blocks[0].1), sent);
+        assert!(blocks.iter().all(|(flags, _)| known.contains(*flags)));
+
+        let sent: Vec<u8> = (0..200).collect();
+        let blocks = read_blocks(&sent, false);
+        assert!(blocks.iter().all(|(flags, _)| known.contains(*flags)));
+
+        // digest only when enabled
+        let blocks = read_blocks(&sent, true);
+        assert!(!known.contains(blocks.last().unwrap().0));
+    }
+}
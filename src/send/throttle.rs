@@ -1,63 +1,225 @@
+//! Rate limiter shared by all udp sender threads
+//!
+//! It is implemented as a lock-free token bucket, using the virtual scheduling algorithm (GCRA):
+//! a single atomic value stores the theoretical arrival time (TAT) of the next packet, i.e. the
+//! time at which all packets already allowed would have been sent at the configured rate.
+//! A packet can be sent as soon as the current time reaches TAT minus the burst tolerance.
+//!
+//! Since every sender thread reserves its sending time in the same timeline, the configured rate
+//! is enforced globally, whatever the load of each thread.
+
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+/// below this delay, busy wait instead of sleeping to achieve sub-millisecond pacing
+const SPIN_THRESHOLD: Duration = Duration::from_micros(200);
+
+/// longest wait for a packet, in nanoseconds (one day): tiny rates must not overflow timestamps
+const MAX_WAIT: u64 = 24 * 3600 * 1_000_000_000;
+
 pub struct Throttle {
+    /// reference for all timestamps (in nanoseconds)
     instant: Instant,
-    previous_elapsed: f64,
-    refresh_rate: f64,
-    current_tokens: f64,
-    max_tokens: f64,
+    /// theoretical arrival time of the next packet, in nanoseconds since `instant`
+    tat: AtomicU64,
+    /// rate in bit/s (f64 bits), infinite if there is no limit
+    rate: AtomicU64,
+    /// maximum amount of data which can be sent in a burst, in bits
+    burst: u64,
 }
 
 impl Throttle {
-    /// rate is in bit/s
-    pub fn new(rate: f64) -> Self {
-        log::debug!("Throttling at {rate} bits/s");
-        let instant = Instant::now();
-        let previous_elapsed = instant.elapsed().as_secs_f64();
-        Self {
-            instant,
-            previous_elapsed,
-            refresh_rate: rate,
-            max_tokens: rate,
-            // starts at 0 to try to limit bursts
-            current_tokens: 0.0,
+    /// rate is in bit/s (None if unlimited), burst in bytes
+    pub fn new(rate: Option<f64>, burst: u64) -> Self {
+        let throttle = Self {
+            instant: Instant::now(),
+            tat: AtomicU64::new(0),
+            rate: AtomicU64::new(f64::INFINITY.to_bits()),
+            burst: burst * 8,
+        };
+        throttle.set_rate(rate);
+        throttle
+    }
+
+    /// change the rate (in bit/s, None if unlimited)
+    pub fn set_rate(&self, rate: Option<f64>) {
+        match rate {
+            Some(rate) => log::debug!(
+                "Throttling at {rate} bits/s with bursts of {} bytes",
+                self.burst / 8
+            ),
+            None => log::debug!("Throttling disabled"),
         }
+        let rate = rate.unwrap_or(f64::INFINITY);
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+        // starts without any credit to try to limit bursts
+        self.tat.store(
+            self.now()
+                .saturating_add(Self::bits_duration(rate, self.burst)),
+            Ordering::Relaxed,
+        );
     }
 
-    fn refresh(&mut self) {
-        // first compute time since last call
-        let elapsed = self.instant.elapsed().as_secs_f64();
-        let mut diff = elapsed - self.previous_elapsed;
-        // workaround for issue #26, it looks like bandwidth can be exceeded when diff is big.
-        // so remove this diff in that case to be sure bandwidth is enforced.
-        if diff > 1.0 {
-            diff = 0.0;
+    /// current rate in bit/s, None if unlimited
+    pub fn rate(&self) -> Option<f64> {
+        let rate = f64::from_bits(self.rate.load(Ordering::Relaxed));
+        rate.is_finite().then_some(rate)
+    }
+
+    // time needed to send `bits` at `rate`, in nanoseconds
+    fn bits_duration(rate: f64, bits: u64) -> u64 {
+        ((bits as f64 * 1_000_000_000.0 / rate) as u64).min(MAX_WAIT)
+    }
+
+    fn now(&self) -> u64 {
+        self.instant.elapsed().as_nanos() as u64
+    }
+
+    /// give the amount of bytes to send, sleep until they are allowed to be sent
+    pub fn limit(&self, bytes: usize) {
+        if let Some(departure) = self.reserve(bytes) {
+            self.wait_until(departure);
         }
-        self.previous_elapsed = elapsed;
+    }
+
+    /// reserve sending time of `bytes`, gives when they can be sent (None if unlimited)
+    fn reserve(&self, bytes: usize) -> Option<u64> {
+        let rate = self.rate()?;
+
+        let cost = Self::bits_duration(rate, bytes as u64 * 8);
+        let burst = Self::bits_duration(rate, self.burst);
+
+        let mut tat = self.tat.load(Ordering::Relaxed);
+        let departure = loop {
+            let now = self.now();
+            // do not accumulate more than `burst` credit while idle
+            let departure = now.max(tat.saturating_sub(burst));
+            let next_tat = departure.max(tat).saturating_add(cost);
 
-        // add tokens in the bucket
-        self.current_tokens += self.refresh_rate * diff;
+            match self
+                .tat
+                .compare_exchange_weak(tat, next_tat, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break departure,
+                Err(current) => tat = current,
+            }
+        };
 
-        // max the bucket
-        if self.current_tokens > self.max_tokens {
-            self.current_tokens = self.max_tokens;
+        Some(departure)
+    }
+
+    fn wait_until(&self, departure: u64) {
+        loop {
+            let now = self.now();
+            if departure <= now {
+                return;
+            }
+
+            let delay = Duration::from_nanos(departure - now);
+            if delay > SPIN_THRESHOLD {
+                // wake up a bit earlier to absorb scheduler latency
+                sleep(delay - SPIN_THRESHOLD / 2);
+            } else {
+                std::hint::spin_loop();
+            }
         }
     }
+}
 
-    /// give the amount of read bytes
-    pub fn limit(&mut self, bytes: usize) {
-        self.refresh();
+#[cfg(test)]
+mod tests {
+    use super::Throttle;
+    use std::time::{Duration, Instant};
 
-        let bits = bytes * 8;
-        // check if we have enough tokens
-        while self.current_tokens < bits as f64 {
-            // sleep
-            sleep(Duration::from_millis(10));
-            self.refresh();
+    const PACKET: usize = 1000;
+
+    // rate achieved sending `count` packets, in bit/s, measured from the first packet sent
+    fn achieved_rate(throttle: &Throttle, count: usize) -> f64 {
+        throttle.limit(PACKET);
+        let start = Instant::now();
+        for _ in 1..count {
+            throttle.limit(PACKET);
+        }
+        ((count - 1) * PACKET * 8) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    fn assert_close(achieved: f64, rate: f64) {
+        let error = (achieved - rate).abs() / rate;
+        assert!(
+            error < 0.01,
+            "achieved {achieved} bit/s instead of {rate} bit/s"
+        );
+    }
+
+    // rate changes are tested here too: timing tests spinning in parallel would disturb each other
+    #[test]
+    fn test_rate() {
+        // 100 µs per packet, bursts absorb scheduling delays up to 50 ms (25 ms at 160 Mbit/s)
+        let throttle = Throttle::new(Some(80_000_000.0), 500 * PACKET as u64);
+        assert_eq!(throttle.rate(), Some(80_000_000.0));
+        assert_close(achieved_rate(&throttle, 2001), 80_000_000.0);
+
+        // packets are paced below the millisecond, not in steps of several ms
+        throttle.set_rate(Some(80_000_000.0));
+        throttle.limit(PACKET);
+        let start = Instant::now();
+        for _ in 0..10 {
+            throttle.limit(PACKET);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_micros(900), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(3), "{elapsed:?}");
+
+        throttle.set_rate(Some(160_000_000.0));
+        assert_eq!(throttle.rate(), Some(160_000_000.0));
+        assert_close(achieved_rate(&throttle, 4001), 160_000_000.0);
+
+        throttle.set_rate(Some(40_000_000.0));
+        assert_close(achieved_rate(&throttle, 1001), 40_000_000.0);
+
+        // unlimited
+        throttle.set_rate(None);
+        assert_eq!(throttle.rate(), None);
+        let start = Instant::now();
+        for _ in 0..10_000 {
+            throttle.limit(PACKET);
         }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_burst() {
+        // 10 ms per packet, bursts of 10 packets
+        let throttle = Throttle::new(Some(800_000.0), 10 * PACKET as u64);
+
+        // no credit at start
+        throttle.limit(PACKET);
+        let start = Instant::now();
+        throttle.limit(PACKET);
+        assert!(start.elapsed() >= Duration::from_millis(9));
+
+        // credit accumulated while idle is limited to the burst, 10 packets after the first one
+        std::thread::sleep(Duration::from_millis(300));
+        let start = Instant::now();
+        for _ in 0..11 {
+            throttle.limit(PACKET);
+        }
+        assert!(start.elapsed() < Duration::from_millis(5));
+        throttle.limit(PACKET);
+        assert!(start.elapsed() >= Duration::from_millis(9));
+    }
+
+    #[test]
+    fn test_tiny_rate() {
+        let throttle = Throttle::new(Some(1e-300), 10 * PACKET as u64);
+        assert_eq!(throttle.rate(), Some(1e-300));
 
-        // remove current packet length
-        self.current_tokens -= bits as f64;
+        // waits are clamped, timestamps do not overflow and keep pacing packets
+        let first = throttle.reserve(PACKET).unwrap();
+        let second = throttle.reserve(PACKET).unwrap();
+        let third = throttle.reserve(PACKET).unwrap();
+        assert!(first < second && second < third);
+        assert_eq!(third - second, super::MAX_WAIT);
     }
 }
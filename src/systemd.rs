@@ -0,0 +1,284 @@
+//! Notifications to systemd (see sd_notify(3)), used when diode-send or diode-receive is run as
+//! a `Type=notify` service
+//!
+//! Notifications are datagrams sent to the unix socket given by systemd in `NOTIFY_SOCKET`, made
+//! of `VARIABLE=value` lines:
+//! - `READY=1` once sockets are bound,
+//! - `STATUS=...` every few seconds, with number of sessions and throughput,
+//! - `WATCHDOG=1` when `WatchdogSec` is set, only if all monitored worker threads are alive,
+//! - `STOPPING=1` when SIGTERM or SIGINT is received.
+//!
+//! Worker threads prove they are alive by calling [Beat::beat] regularly, even when idle.
+
+use std::env;
+use std::io::Result;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::control::Control;
+
+/// maximum interval between two beats of an idle worker thread
+pub const BEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// interval between two status updates
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Socket to send notifications to systemd
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// notifier to the socket set by systemd, None if not run by systemd
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        match Notifier::new(&path) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                log::warn!("systemd: invalid NOTIFY_SOCKET {path}: {e}");
+                None
+            }
+        }
+    }
+
+    /// notifier to socket `path`, starting with '@' for an abstract socket
+    pub fn new(path: &str) -> Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// send a notification, made of one or more `VARIABLE=value` lines
+    pub fn notify(&self, state: &str) -> Result<()> {
+        log::trace!("systemd: notify {state:?}");
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+/// watchdog interval requested by systemd, if it is meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Liveness of monitored worker threads
+pub struct Liveness {
+    start: Instant,
+    workers: Mutex<Vec<(String, Arc<AtomicU64>)>>,
+}
+
+/// Handle used by a worker thread to tell it is alive
+#[derive(Clone)]
+pub struct Beat {
+    start: Instant,
+    last: Arc<AtomicU64>,
+}
+
+impl Beat {
+    pub fn beat(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            workers: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Liveness {
+    /// monitor a new worker thread
+    pub fn register(&self, name: &str) -> Beat {
+        let beat = Beat {
+            start: self.start,
+            last: Arc::new(AtomicU64::new(self.start.elapsed().as_millis() as u64)),
+        };
+
+        if let Ok(mut workers) = self.workers.lock() {
+            workers.push((name.to_string(), beat.last.clone()));
+        }
+
+        beat
+    }
+
+    /// names of worker threads which did not beat for more than `timeout`
+    pub fn stalled(&self, timeout: Duration) -> Vec<String> {
+        let now = self.start.elapsed().as_millis() as u64;
+        let timeout = timeout.as_millis() as u64;
+
+        match self.workers.lock() {
+            Ok(workers) => workers
+                .iter()
+                .filter(|(_, last)| now.saturating_sub(last.load(Ordering::Relaxed)) > timeout)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            Err(_) => vec!["all".to_string()],
+        }
+    }
+}
+
+/// send `READY=1` and start a thread sending status and watchdog notifications, if run by systemd
+pub fn start<C: Control>(
+    control: Arc<C>,
+    liveness: Arc<Liveness>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<()> {
+    let Some(notifier) = Notifier::from_env() else {
+        return Ok(());
+    };
+
+    let watchdog = watchdog_interval();
+
+    let totals = control.totals();
+    notifier.notify(&format!("READY=1\nSTATUS={}", status(totals.sessions, 0.0)))?;
+    log::info!("systemd: ready notification sent");
+
+    if let Some(watchdog) = watchdog {
+        log::info!(
+            "systemd: watchdog enabled, timeout is {} ms",
+            watchdog.as_millis()
+        );
+    }
+
+    thread::Builder::new()
+        .name("lidi_systemd".into())
+        .spawn(move || notify_loop(notifier, control, liveness, shutdown, watchdog))?;
+
+    Ok(())
+}
+
+fn status(sessions: u64, throughput: f64) -> String {
+    format!("{sessions} sessions, {throughput:.1} Mbit/s")
+}
+
+fn notify_loop<C: Control>(
+    notifier: Notifier,
+    control: Arc<C>,
+    liveness: Arc<Liveness>,
+    shutdown: Arc<AtomicBool>,
+    watchdog: Option<Duration>,
+) {
+    // systemd recommends to ping at half the watchdog timeout
+    let interval = watchdog.map_or(STATUS_INTERVAL, |watchdog| {
+        (watchdog / 2).min(STATUS_INTERVAL)
+    });
+
+    let mut last_status = Instant::now();
+    let mut last_bytes = control.totals().bytes;
+    let mut stalled_logged = false;
+
+    loop {
+        thread::sleep(interval);
+
+        // workers are stopping: `STOPPING=1` is sent by the signal handler
+        if shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut state = vec![];
+
+        if let Some(watchdog) = watchdog {
+            let stalled = liveness.stalled(watchdog);
+            if stalled.is_empty() {
+                state.push("WATCHDOG=1".to_string());
+                stalled_logged = false;
+            } else if !stalled_logged {
+                log::error!("systemd: worker threads not responding: {stalled:?}");
+                stalled_logged = true;
+            }
+        }
+
+        if STATUS_INTERVAL <= last_status.elapsed() {
+            let totals = control.totals();
+            let elapsed = last_status.elapsed().as_secs_f64();
+            let throughput =
+                totals.bytes.saturating_sub(last_bytes) as f64 * 8.0 / elapsed / 1_000_000.0;
+            state.push(format!("STATUS={}", status(totals.sessions, throughput)));
+
+            last_status = Instant::now();
+            last_bytes = totals.bytes;
+        }
+
+        if !state.is_empty() {
+            if let Err(e) = notifier.notify(&state.join("\n")) {
+                log::warn!("systemd: unable to send notification: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Liveness, Notifier};
+    use crate::test::TempDir;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+    use std::time::Duration;
+
+    #[test]
+    fn test_notify() {
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(path.to_str().unwrap()).unwrap();
+        notifier.notify("READY=1\nSTATUS=0 sessions").unwrap();
+
+        let mut buffer = [0u8; 64];
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1\nSTATUS=0 sessions");
+    }
+
+    #[test]
+    fn test_notify_abstract() {
+        let name = format!("lidi_notify_{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+
+        let notifier = Notifier::new(&format!("@{name}")).unwrap();
+        notifier.notify("WATCHDOG=1").unwrap();
+
+        let mut buffer = [0u8; 64];
+        let len = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_liveness() {
+        let liveness = Liveness::default();
+        let alive = liveness.register("alive");
+        let _stalled = liveness.register("stalled");
+
+        std::thread::sleep(Duration::from_millis(100));
+        alive.beat();
+
+        assert_eq!(liveness.stalled(Duration::from_millis(50)), vec!["stalled"]);
+        assert!(liveness.stalled(Duration::from_secs(10)).is_empty());
+    }
+}
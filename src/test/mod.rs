@@ -2,9 +2,41 @@
 
 use rand::{Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fs, ops, path, process};
 
 use crate::protocol::{Header, MessageType};
 
+/// Empty temporary directory, removed when dropped, even if the test panics
+pub struct TempDir(path::PathBuf);
+
+impl TempDir {
+    /// `name` identifies the test, the directory is unique in the process
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("lidi_{name}_{}_{count}", process::id()));
+        // left by a previous process with the same pid
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).expect("cannot create temporary directory");
+        Self(path)
+    }
+}
+
+impl ops::Deref for TempDir {
+    type Target = path::Path;
+
+    fn deref(&self) -> &path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
 pub fn build_random_data(data_len: usize) -> Vec<u8> {
     // set a seed for random algorithm generation
     let mut rng = XorShiftRng::from_seed([
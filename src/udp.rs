@@ -4,6 +4,8 @@ use nix::sys::socket::sockopt::{RcvBuf, SndBuf};
 use nix::sys::socket::{getsockopt, setsockopt};
 use std::io::Error;
 use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
 
 use crate::protocol::Header;
 
@@ -121,4 +123,15 @@ impl Udp {
     pub fn mtu(&self) -> u16 {
         self.mtu
     }
+
+    /// inode of the socket, as listed in `/proc/net/udp`
+    pub fn inode(&self) -> std::io::Result<u64> {
+        std::fs::metadata(format!("/proc/self/fd/{}", self.socket.as_raw_fd()))
+            .map(|metadata| metadata.ino())
+    }
+
+    /// size of the kernel receive buffer, in bytes
+    pub fn recv_buffer_size(&self) -> std::io::Result<usize> {
+        Ok(getsockopt(&self.socket, RcvBuf)?)
+    }
 }